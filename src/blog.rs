@@ -1,5 +1,4 @@
 use chrono::{DateTime, Utc};
-use reqwest::blocking::Client;
 use rusqlite::{Connection, params};
 use serde::{Deserialize, Serialize};
 use std::time::Duration;
@@ -7,8 +6,7 @@ use std::sync::Arc;
 use warp::{Filter, Rejection, Reply, http::StatusCode};
 use tokio::sync::Mutex;
 
-// Model configuration
-const OLLAMA_MODEL: &str = "riven/smolvlm";
+use crate::generator::{Generator, GeneratorError};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BlogPost {
@@ -20,6 +18,16 @@ pub struct BlogPost {
     pub image_url: String,
 }
 
+// Use ~/.config/egghead/blog.sqlite unless BLOG_DB_PATH says otherwise
+pub fn default_db_path() -> String {
+    std::env::var("BLOG_DB_PATH").unwrap_or_else(|_| {
+        let config_dir = crate::config::config_dir();
+        // Create the directory if it doesn't exist
+        std::fs::create_dir_all(&config_dir).ok();
+        format!("{}/blog.sqlite", config_dir)
+    })
+}
+
 #[derive(Debug)]
 struct DatabaseError;
 
@@ -43,125 +51,51 @@ pub fn init_database(db_path: &str) -> Result<Connection, rusqlite::Error> {
     Ok(conn)
 }
 
-pub fn fetch_guardian_headlines() -> Result<Vec<String>, Box<dyn std::error::Error>> {
-    let client = Client::builder()
-        .timeout(Duration::from_secs(30))
-        .build()?;
-
-    let response = client
-        .get("https://www.theguardian.com/world/rss")
-        .send()?
-        .bytes()?;
+const GUARDIAN_WORLD_FEED: &str = "https://www.theguardian.com/world/rss";
 
-    let channel = rss::Channel::read_from(&response[..])?;
+pub async fn fetch_guardian_headlines(client: &reqwest::Client) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+    let headlines = crate::news::fetch_headlines(client, GUARDIAN_WORLD_FEED, 3).await?;
 
-    let titles: Vec<String> = channel
-        .items()
-        .iter()
-        .take(3)
-        .filter_map(|item| item.title().map(|t| t.to_string()))
-        .collect();
-
-    Ok(titles)
+    Ok(headlines.into_iter().map(|headline| headline.title).collect())
 }
 
-pub fn generate_location() -> Result<String, Box<dyn std::error::Error>> {
-    let client = Client::builder()
-        .timeout(Duration::from_secs(60))
-        .build()?;
-
+pub async fn generate_location(generator: &Generator) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
     let prompt = "Pick an interesting city somewhere in the world:";
 
-    let request_data = serde_json::json!({
-        "model": OLLAMA_MODEL,
-        "prompt": prompt,
-        "stream": false,
-        "temperature": 0.9,
-        "num_predict": 32,
-    });
-
-    let response = client
-        .post("http://localhost:11434/api/generate")
-        .header("Content-Type", "application/json")
-        .json(&request_data)
-        .send()?;
-
-    let response_json: serde_json::Value = response.json()?;
-    let location = response_json["response"]
-        .as_str()
-        .unwrap_or("Sydney, Australia")
-        .trim()
-        .to_string();
-
-    Ok(location)
-}
+    let location = generator.complete(prompt, 0.9, 32, Duration::from_secs(60)).await;
 
-pub fn generate_activity(location: &str) -> Result<String, Box<dyn std::error::Error>> {
-    let client = Client::builder()
-        .timeout(Duration::from_secs(120))
-        .build()?;
+    or_fallback(location, "Sydney, Australia")
+}
 
+pub async fn generate_activity(generator: &Generator, location: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
     let prompt = format!(
         "You're in {}. What are your impressions?",
         location
     );
 
-    let request_data = serde_json::json!({
-        "model": OLLAMA_MODEL,
-        "prompt": prompt,
-        "stream": false,
-        "temperature": 0.9,
-        "num_predict": 64,
-    });
-
-    let response = client
-        .post("http://localhost:11434/api/generate")
-        .header("Content-Type", "application/json")
-        .json(&request_data)
-        .send()?;
-
-    let response_json: serde_json::Value = response.json()?;
-    let activity = response_json["response"]
-        .as_str()
-        .unwrap_or("Enjoying the sun!")
-        .trim()
-        .to_string();
-
-    Ok(activity)
-}
+    let activity = generator.complete(&prompt, 0.9, 64, Duration::from_secs(120)).await;
 
-pub fn generate_blog_content(location: &str, activity: &str, context: &str) -> Result<String, Box<dyn std::error::Error>> {
-    let client = Client::builder()
-        .timeout(Duration::from_secs(180))
-        .build()?;
+    or_fallback(activity, "Enjoying the sun!")
+}
 
+pub async fn generate_blog_content(generator: &Generator, context: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
     let prompt = format!(
         "You're a tech enthusiast blogger named Egghead. Write an intimate blog post about your current life, and include musings on headlines you consider important:\n{}\n\nDo not use any markdown formatting or emojis.",
         context
     );
 
-    let request_data = serde_json::json!({
-        "model": OLLAMA_MODEL,
-        "prompt": prompt,
-        "stream": false,
-        "temperature": 1.35,
-        "num_predict": 256,
-    });
-
-    let response = client
-        .post("http://localhost:11434/api/generate")
-        .header("Content-Type", "application/json")
-        .json(&request_data)
-        .send()?;
-
-    let response_json: serde_json::Value = response.json()?;
-    let content = response_json["response"]
-        .as_str()
-        .unwrap_or("Thinking about how technology connects us all and shapes our future. Always learning, always curious about what's next!")
-        .trim()
-        .to_string();
-
-    Ok(content)
+    let content = generator.complete(&prompt, 1.35, 256, Duration::from_secs(180)).await;
+
+    or_fallback(content, "Thinking about how technology connects us all and shapes our future. Always learning, always curious about what's next!")
+}
+
+// An empty answer gets a canned stand-in; any other failure is passed up
+fn or_fallback(result: Result<String, GeneratorError>, fallback: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    match result {
+        Ok(text) if !text.trim().is_empty() => Ok(text.trim().to_string()),
+        Ok(_) | Err(GeneratorError::EmptyChoices) => Ok(fallback.to_string()),
+        Err(e) => Err(Box::new(e)),
+    }
 }
 
 pub fn get_picsum_image(seed: &str) -> String {
@@ -188,19 +122,19 @@ pub fn save_blog_post(conn: &Connection, post: &BlogPost) -> Result<i64, rusqlit
     Ok(conn.last_insert_rowid())
 }
 
-pub fn generate_blog_post() -> Result<BlogPost, Box<dyn std::error::Error>> {
+pub async fn generate_blog_post(generator: &Generator) -> Result<BlogPost, Box<dyn std::error::Error + Send + Sync>> {
     // Fetch Guardian headlines for blog content only
-    let headlines = fetch_guardian_headlines()?;
+    let headlines = fetch_guardian_headlines(generator.client()).await?;
     let context = headlines.join("\n");
 
     // Generate random location (no context)
-    let location = generate_location()?;
+    let location = generate_location(generator).await?;
 
     // Generate activity based on location only (no news context)
-    let activity = generate_activity(&location)?;
+    let activity = generate_activity(generator, &location).await?;
 
     // Generate blog content based on news context
-    let content = generate_blog_content(&location, &activity, &context)?;
+    let content = generate_blog_content(generator, &context).await?;
 
     // Get image from Picsum (no API key needed)
     let image_url = get_picsum_image(&location);
@@ -0,0 +1,51 @@
+use std::collections::BTreeMap;
+
+// Values keyed by a Discord message id, keeping only the newest `N`. Message ids grow over
+// time, so the first entries are the oldest and go first.
+pub struct BoundedMap<V, const N: usize> {
+    entries: BTreeMap<u64, V>,
+}
+
+impl<V, const N: usize> Default for BoundedMap<V, N> {
+    fn default() -> Self {
+        BoundedMap { entries: BTreeMap::new() }
+    }
+}
+
+impl<V, const N: usize> BoundedMap<V, N> {
+    pub fn insert(&mut self, id: u64, value: V) {
+        self.entries.insert(id, value);
+
+        while self.entries.len() > N {
+            self.entries.pop_first();
+        }
+    }
+
+    pub fn get(&self, id: u64) -> Option<&V> {
+        self.entries.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: u64) -> Option<&mut V> {
+        self.entries.get_mut(&id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_oldest_ids() {
+        let mut map: BoundedMap<&str, 2> = BoundedMap::default();
+        map.insert(20, "b");
+        map.insert(10, "a");
+        map.insert(30, "c");
+
+        assert_eq!(map.get(10), None);
+        assert_eq!(map.get(20), Some(&"b"));
+        assert_eq!(map.get(30), Some(&"c"));
+
+        *map.get_mut(20).unwrap() = "changed";
+        assert_eq!(map.get(20), Some(&"changed"));
+    }
+}
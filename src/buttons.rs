@@ -0,0 +1,305 @@
+use std::sync::Arc;
+
+use serde_json::json;
+use serenity::builder::CreateComponents;
+use serenity::model::application::component::ButtonStyle;
+use serenity::model::application::interaction::message_component::MessageComponentInteraction;
+use serenity::model::application::interaction::InteractionResponseType;
+use serenity::model::channel::Message;
+use serenity::prelude::*;
+use tokio::sync::Notify;
+
+use crate::bounded::BoundedMap;
+use crate::{conversation, generator, ratelimit};
+use crate::Generations;
+
+// Replies the buttons still work on. Older ones are forgotten and say so when pressed.
+const MAX_TRACKED: usize = 500;
+// How much hotter each "Try hotter" press runs
+const HOTTER_STEP: f64 = 0.3;
+const MAX_TEMPERATURE: f64 = 2.0;
+const CONTINUE_PROMPT: &str = "Continue exactly where you left off. Don't repeat anything you already wrote.";
+
+// The buttons under a streamed reply. Their custom ids carry the action and the id of the
+// reply's first message, which is how presses find their way back to the generation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Regenerate,
+    // Regenerate at a higher temperature
+    Hotter,
+    // Pick up where a reply cut off at `max_tokens`
+    Continue,
+    // Cancel a generation that is still streaming
+    Stop,
+}
+
+impl Action {
+    fn name(&self) -> &'static str {
+        match self {
+            Action::Regenerate => "regenerate",
+            Action::Hotter => "hotter",
+            Action::Continue => "continue",
+            Action::Stop => "stop",
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            Action::Regenerate => "Regenerate",
+            Action::Hotter => "Try hotter",
+            Action::Continue => "Continue",
+            Action::Stop => "Stop",
+        }
+    }
+
+    fn style(&self) -> ButtonStyle {
+        match self {
+            Action::Continue => ButtonStyle::Primary,
+            Action::Stop => ButtonStyle::Danger,
+            _ => ButtonStyle::Secondary,
+        }
+    }
+
+    fn custom_id(&self, key: u64) -> String {
+        format!("egghead:{}:{}", self.name(), key)
+    }
+
+    fn parse(custom_id: &str) -> Option<(Action, u64)> {
+        let mut parts = custom_id.split(':');
+        if parts.next()? != "egghead" {
+            return None;
+        }
+
+        let action = match parts.next()? {
+            "regenerate" => Action::Regenerate,
+            "hotter" => Action::Hotter,
+            "continue" => Action::Continue,
+            "stop" => Action::Stop,
+            _ => return None,
+        };
+        let key = parts.next()?.parse().ok()?;
+        Some((action, key))
+    }
+}
+
+// One row of `actions` for the reply `key`. No actions leaves `components` empty, which
+// removes the buttons from a message being edited.
+pub fn add_buttons<'a>(components: &'a mut CreateComponents, key: u64, actions: &[Action]) -> &'a mut CreateComponents {
+    if actions.is_empty() {
+        return components;
+    }
+
+    components.create_action_row(|row| {
+        for action in actions {
+            row.create_button(|button| {
+                button
+                    .custom_id(action.custom_id(key))
+                    .label(action.label())
+                    .style(action.style())
+            });
+        }
+        row
+    })
+}
+
+// Everything needed to answer a message again
+#[derive(Debug, Clone)]
+pub struct Request {
+    // Without any `--model` flag, which is kept in `forced_model`
+    pub prompt: String,
+    // The model picked with `--model`, which reruns stick with
+    pub forced_model: Option<String>,
+    // Base64-encoded images attached to the prompt
+    pub images: Vec<String>,
+    pub history: Vec<serde_json::Value>,
+    // Added to the persona's system prompt (see `answer`)
+    pub instructions: Option<String>,
+    // Overrides the persona's temperature
+    pub temperature: Option<f64>,
+    // The message being answered, for the conversation store. It's saved with the answer, so
+    // a request that never gets one leaves nothing behind.
+    pub turn: conversation::Turn,
+}
+
+impl Request {
+    fn hotter(&self, temperature: f64) -> Request {
+        Request {
+            temperature: Some((temperature + HOTTER_STEP).min(MAX_TEMPERATURE)),
+            ..self.clone()
+        }
+    }
+
+    // The same conversation with the cut-off answer as the last turn, asking for the rest
+    fn continuation(&self, answer: &str) -> Request {
+        let mut history = self.history.clone();
+        history.push(generator::user_message(&self.prompt, &self.images));
+        history.push(json!({ "role": "assistant", "content": answer }));
+
+        Request {
+            prompt: CONTINUE_PROMPT.to_string(),
+            images: Vec::new(),
+            history,
+            ..self.clone()
+        }
+    }
+}
+
+// A streamed reply the buttons act on
+#[derive(Debug, Clone)]
+pub struct Generation {
+    // Only they get to press the buttons
+    pub requester: u64,
+    // The message being answered; reruns reply to it as well
+    pub message: Message,
+    pub request: Request,
+    // The temperature it actually ran at
+    pub temperature: f64,
+    // Stops the generation while it's running; None once it's done
+    pub cancel: Option<Arc<Notify>>,
+    // The model's answer, once it's done
+    pub text: String,
+}
+
+// Generations by the id of their reply's first message
+pub type GenerationMap = BoundedMap<Generation, MAX_TRACKED>;
+
+pub async fn handle(ctx: &Context, component: &MessageComponentInteraction) {
+    let (action, key) = match Action::parse(&component.data.custom_id) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+    println!("Button '{}' pressed by '{}'", action.name(), component.user.tag());
+
+    let generations = {
+        let data_read = ctx.data.read().await;
+        data_read.get::<Generations>().expect("Expected Generations in TypeMap.").clone()
+    };
+    let generation = match generations.read().await.get(key).cloned() {
+        Some(generation) if generation.requester == component.user.id.0 => generation,
+        Some(generation) => {
+            refuse(ctx, component, &format!("Only <@{}> can use these buttons.", generation.requester)).await;
+            return;
+        }
+        None => {
+            refuse(ctx, component, "I've forgotten about that answer. Ask me again?").await;
+            return;
+        }
+    };
+
+    // Everything but Stop is another generation
+    if action != Action::Stop {
+        let roles = component.member.as_ref().map(|member| member.roles.clone()).unwrap_or_default();
+        if let Err(wait) = ratelimit::check(ctx, ratelimit::Kind::Chat, component.user.id, component.channel_id, component.guild_id, &roles).await {
+            refuse(ctx, component, &ratelimit::slow_down(wait)).await;
+            return;
+        }
+    }
+
+    // Acknowledge without touching the message; whatever happens next shows up on its own
+    if let Err(why) = component.defer(&ctx.http).await {
+        println!("Error acknowledging button press: {:?}", why);
+        return;
+    }
+
+    match action {
+        Action::Stop => {
+            if let Some(cancel) = &generation.cancel {
+                cancel.notify_one();
+            }
+        }
+        Action::Regenerate => crate::answer_request(ctx, &generation.message, generation.request).await,
+        Action::Hotter => {
+            let request = generation.request.hotter(generation.temperature);
+            crate::answer_request(ctx, &generation.message, request).await
+        }
+        Action::Continue => {
+            let request = generation.request.continuation(&generation.text);
+            crate::answer_request(ctx, &generation.message, request).await
+        }
+    }
+}
+
+// Tells only the person who pressed the button why nothing happened
+async fn refuse(ctx: &Context, component: &MessageComponentInteraction, reason: &str) {
+    let result = component
+        .create_interaction_response(&ctx.http, |r| {
+            r.kind(InteractionResponseType::ChannelMessageWithSource)
+                .interaction_response_data(|d| d.content(reason).ephemeral(true))
+        })
+        .await;
+
+    if let Err(why) = result {
+        println!("Error refusing button press: {:?}", why);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> Request {
+        Request {
+            prompt: "write a poem".to_string(),
+            forced_model: Some("llama3".to_string()),
+            images: vec!["aW1hZ2U=".to_string()],
+            history: vec![json!({ "role": "user", "content": "hi" })],
+            instructions: Some("Rhyme.".to_string()),
+            temperature: None,
+            turn: conversation::Turn {
+                message_id: 2,
+                parent_id: Some(1),
+                channel_id: 10,
+                user_id: 100,
+                role: "user",
+                content: "write a poem".to_string(),
+                images: vec!["aW1hZ2U=".to_string()],
+            },
+        }
+    }
+
+    #[test]
+    fn custom_ids_round_trip() {
+        for action in [Action::Regenerate, Action::Hotter, Action::Continue, Action::Stop] {
+            assert_eq!(Action::parse(&action.custom_id(1234)), Some((action, 1234)));
+        }
+    }
+
+    #[test]
+    fn ignores_other_custom_ids() {
+        assert_eq!(Action::parse("other:regenerate:1"), None);
+        assert_eq!(Action::parse("egghead:explode:1"), None);
+        assert_eq!(Action::parse("egghead:stop:notanumber"), None);
+        assert_eq!(Action::parse("egghead:stop"), None);
+        assert_eq!(Action::parse(""), None);
+    }
+
+    #[test]
+    fn hotter_raises_the_temperature_up_to_the_cap() {
+        assert_eq!(request().hotter(0.8).temperature, Some(0.8 + HOTTER_STEP));
+        assert_eq!(request().hotter(1.9).temperature, Some(MAX_TEMPERATURE));
+        assert_eq!(request().hotter(MAX_TEMPERATURE).temperature, Some(MAX_TEMPERATURE));
+
+        let hotter = request().hotter(0.8);
+        assert_eq!(hotter.prompt, "write a poem");
+        assert_eq!(hotter.forced_model.as_deref(), Some("llama3"));
+    }
+
+    #[test]
+    fn continuation_asks_for_the_rest() {
+        let continuation = request().continuation("Roses are red,");
+
+        assert_eq!(continuation.prompt, CONTINUE_PROMPT);
+        assert!(continuation.images.is_empty());
+        assert_eq!(
+            continuation.history,
+            vec![
+                json!({ "role": "user", "content": "hi" }),
+                generator::user_message("write a poem", &["aW1hZ2U=".to_string()]),
+                json!({ "role": "assistant", "content": "Roses are red," }),
+            ]
+        );
+        // Keeps going on the same model, with the same instructions
+        assert_eq!(continuation.forced_model.as_deref(), Some("llama3"));
+        assert_eq!(continuation.instructions.as_deref(), Some("Rhyme."));
+    }
+}
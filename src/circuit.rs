@@ -0,0 +1,227 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::ResilienceConfig;
+
+// Stops us hammering a backend that is down. After `failure_threshold` consecutive
+// failures the circuit opens and requests are refused outright for `cooldown`. Once that
+// passes a single trial request is let through (half-open): success closes the circuit,
+// failure opens it for another cooldown.
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    state: Mutex<State>,
+}
+
+struct State {
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    // When the half-open trial request went out. A trial that never reports back (its
+    // future was dropped) is treated as abandoned after another cooldown.
+    trial_started: Option<Instant>,
+    last_error: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum CircuitState {
+    Closed,
+    Open { retry_in: Duration },
+    HalfOpen,
+}
+
+#[derive(Debug, Clone)]
+pub struct CircuitStatus {
+    pub state: CircuitState,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: &ResilienceConfig) -> CircuitBreaker {
+        CircuitBreaker {
+            failure_threshold: config.failure_threshold.max(1),
+            cooldown: Duration::from_secs(config.cooldown_secs),
+            state: Mutex::new(State {
+                consecutive_failures: 0,
+                opened_at: None,
+                trial_started: None,
+                last_error: None,
+            }),
+        }
+    }
+
+    // Ok if a request may go out now, otherwise how long until the circuit half-opens
+    pub fn allow(&self) -> Result<(), Duration> {
+        let mut state = self.state.lock().unwrap();
+
+        let opened_at = match state.opened_at {
+            Some(opened_at) => opened_at,
+            None => return Ok(()),
+        };
+
+        let elapsed = opened_at.elapsed();
+        if elapsed < self.cooldown {
+            return Err(self.cooldown - elapsed);
+        }
+
+        // Half-open: only one request gets to find out whether the backend is back
+        if let Some(started) = state.trial_started {
+            if started.elapsed() < self.cooldown {
+                return Err(Duration::from_secs(1));
+            }
+        }
+        state.trial_started = Some(Instant::now());
+        Ok(())
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.opened_at.is_some() {
+            println!("Circuit closed, backend is answering again");
+        }
+        state.consecutive_failures = 0;
+        state.opened_at = None;
+        state.trial_started = None;
+    }
+
+    pub fn record_failure(&self, error: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.consecutive_failures += 1;
+        state.last_error = Some(error.to_string());
+
+        let trial_failed = state.trial_started.take().is_some();
+
+        if trial_failed || state.consecutive_failures >= self.failure_threshold {
+            if state.opened_at.is_none() || trial_failed {
+                eprintln!(
+                    "Circuit opened after {} consecutive failure(s), cooling down for {}s",
+                    state.consecutive_failures,
+                    self.cooldown.as_secs()
+                );
+            }
+            state.opened_at = Some(Instant::now());
+        }
+    }
+
+    pub fn status(&self) -> CircuitStatus {
+        let state = self.state.lock().unwrap();
+
+        let circuit_state = match state.opened_at {
+            None => CircuitState::Closed,
+            Some(opened_at) => {
+                let elapsed = opened_at.elapsed();
+                if elapsed < self.cooldown {
+                    CircuitState::Open { retry_in: self.cooldown - elapsed }
+                } else {
+                    CircuitState::HalfOpen
+                }
+            }
+        };
+
+        CircuitStatus {
+            state: circuit_state,
+            consecutive_failures: state.consecutive_failures,
+            last_error: state.last_error.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new(&ResilienceConfig {
+            failure_threshold: 2,
+            cooldown_secs: 30,
+            ..ResilienceConfig::default()
+        })
+    }
+
+    // Moves the circuit's clock forward by pretending everything happened `by` earlier
+    fn wait(breaker: &CircuitBreaker, by: Duration) {
+        let mut state = breaker.state.lock().unwrap();
+        state.opened_at = state.opened_at.map(|at| at - by);
+        state.trial_started = state.trial_started.map(|at| at - by);
+    }
+
+    #[test]
+    fn opens_after_threshold() {
+        let breaker = breaker();
+        breaker.record_failure("first");
+        assert!(breaker.allow().is_ok());
+        assert!(matches!(breaker.status().state, CircuitState::Closed));
+
+        breaker.record_failure("second");
+        let retry_in = breaker.allow().unwrap_err();
+        assert!(retry_in > Duration::from_secs(29));
+        assert!(matches!(breaker.status().state, CircuitState::Open { .. }));
+
+        let status = breaker.status();
+        assert_eq!(status.consecutive_failures, 2);
+        assert_eq!(status.last_error.as_deref(), Some("second"));
+    }
+
+    #[test]
+    fn success_resets_the_count() {
+        let breaker = breaker();
+        breaker.record_failure("first");
+        breaker.record_success();
+        breaker.record_failure("again");
+        assert!(breaker.allow().is_ok());
+        assert_eq!(breaker.status().consecutive_failures, 1);
+    }
+
+    #[test]
+    fn half_opens_for_one_trial() {
+        let breaker = breaker();
+        breaker.record_failure("first");
+        breaker.record_failure("second");
+        wait(&breaker, Duration::from_secs(30));
+
+        assert!(matches!(breaker.status().state, CircuitState::HalfOpen));
+        assert!(breaker.allow().is_ok());
+        // The trial is still out, so nobody else gets through
+        assert!(breaker.allow().is_err());
+    }
+
+    #[test]
+    fn successful_trial_closes() {
+        let breaker = breaker();
+        breaker.record_failure("first");
+        breaker.record_failure("second");
+        wait(&breaker, Duration::from_secs(30));
+        breaker.allow().unwrap();
+
+        breaker.record_success();
+        assert!(matches!(breaker.status().state, CircuitState::Closed));
+        assert!(breaker.allow().is_ok());
+        assert!(breaker.allow().is_ok());
+    }
+
+    #[test]
+    fn failed_trial_reopens() {
+        let breaker = breaker();
+        breaker.record_failure("first");
+        breaker.record_failure("second");
+        wait(&breaker, Duration::from_secs(30));
+        breaker.allow().unwrap();
+
+        breaker.record_failure("still down");
+        assert!(matches!(breaker.status().state, CircuitState::Open { .. }));
+        assert!(breaker.allow().is_err());
+    }
+
+    #[test]
+    fn abandoned_trial_is_replaced() {
+        let breaker = breaker();
+        breaker.record_failure("first");
+        breaker.record_failure("second");
+        wait(&breaker, Duration::from_secs(30));
+        breaker.allow().unwrap();
+
+        // The trial never reported back
+        wait(&breaker, Duration::from_secs(30));
+        assert!(breaker.allow().is_ok());
+    }
+}
@@ -0,0 +1,384 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+
+use crate::overflow::OverflowPolicy;
+
+// Runtime configuration. Everything has a default so egghead still starts with no config file,
+// talking to a local Ollama the same way it always has.
+//
+// The file is read from `EGGHEAD_CONFIG`, falling back to ~/.config/egghead/config.json.
+// A handful of environment variables override the file so a box can be repointed without
+// editing it (see `apply_env_overrides`).
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub backend: BackendConfig,
+    // Fallback persona when neither the channel nor the guild has set one (see `e.persona`)
+    pub persona: PersonaConfig,
+    pub routing: RoutingConfig,
+    pub context: ContextConfig,
+    pub resilience: ResilienceConfig,
+    pub queue: QueueConfig,
+    pub tools: ToolsConfig,
+    pub news: NewsConfig,
+    pub overflow: OverflowConfig,
+    pub rate_limits: RateLimitConfig,
+    pub permissions: PermissionsConfig,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum BackendKind {
+    // Any server speaking the OpenAI chat completions API (Ollama's /v1 endpoint included)
+    #[serde(rename = "openai", alias = "openai-compatible")]
+    OpenAi,
+    // Ollama's native /api/chat endpoint
+    #[serde(rename = "ollama")]
+    Ollama,
+    // The llama.cpp `server` binary
+    #[serde(rename = "llamacpp", alias = "llama.cpp")]
+    LlamaCpp,
+}
+
+impl BackendKind {
+    pub fn parse(value: &str) -> Option<BackendKind> {
+        match value.trim().to_lowercase().as_str() {
+            "openai" | "openai-compatible" => Some(BackendKind::OpenAi),
+            "ollama" => Some(BackendKind::Ollama),
+            "llamacpp" | "llama.cpp" => Some(BackendKind::LlamaCpp),
+            _ => None,
+        }
+    }
+
+    pub fn default_base_url(&self) -> &'static str {
+        match self {
+            BackendKind::OpenAi | BackendKind::Ollama => "http://localhost:11434",
+            BackendKind::LlamaCpp => "http://localhost:8080",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BackendConfig {
+    pub kind: BackendKind,
+    // Defaults to the usual port for `kind` when unset
+    pub base_url: Option<String>,
+    pub model: String,
+    pub api_key: Option<String>,
+}
+
+impl Default for BackendConfig {
+    fn default() -> Self {
+        BackendConfig {
+            kind: BackendKind::OpenAi,
+            base_url: None,
+            model: "riven/smolvlm".to_string(),
+            api_key: None,
+        }
+    }
+}
+
+impl BackendConfig {
+    pub fn base_url(&self) -> String {
+        self.base_url
+            .clone()
+            .unwrap_or_else(|| self.kind.default_base_url().to_string())
+            .trim_end_matches('/')
+            .to_string()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PersonaConfig {
+    pub system_prompt: String,
+    pub temperature: f64,
+}
+
+impl Default for PersonaConfig {
+    fn default() -> Self {
+        PersonaConfig {
+            system_prompt: "You are Egghead, the world's smartest computer.".to_string(),
+            temperature: 0.85,
+        }
+    }
+}
+
+// Which model answers a request. Unset models fall back to `backend.model`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RoutingConfig {
+    // Used whenever the request carries images
+    pub vision_model: Option<String>,
+    // Used for text-only requests
+    pub text_model: Option<String>,
+    // Keyword overrides for text-only requests, checked in order
+    pub rules: Vec<RoutingRule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RoutingRule {
+    pub keyword: String,
+    pub model: String,
+}
+
+// Token budgeting for prompts (see tokens.rs)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ContextConfig {
+    // Context window, in tokens, for models not listed in `sizes`
+    pub default_size: usize,
+    // Per-model context windows, keyed by model name
+    pub sizes: HashMap<String, usize>,
+    // Reply length requested from the model; always reserved out of the window
+    pub max_tokens: u32,
+    // Estimated cost of one attached image
+    pub image_tokens: usize,
+    // How far up a reply chain is followed (and how much of a thread is read), before budgeting
+    pub max_history_messages: usize,
+    // Most images sent with one request. The new message's own always go; earlier turns'
+    // images fill the rest, newest first.
+    pub max_images: usize,
+}
+
+impl Default for ContextConfig {
+    fn default() -> Self {
+        ContextConfig {
+            default_size: 4096,
+            sizes: HashMap::new(),
+            max_tokens: 1024,
+            image_tokens: 768,
+            max_history_messages: 30,
+            max_images: 4,
+        }
+    }
+}
+
+// Retries and circuit breaking around the chat backend (see circuit.rs)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ResilienceConfig {
+    // Extra attempts after a connection error or 5xx
+    pub max_retries: u32,
+    // Delay before the first retry; doubles each attempt up to `max_backoff_ms`
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    // Consecutive failed requests before the circuit opens
+    pub failure_threshold: u32,
+    // How long an open circuit refuses requests before trying the backend again
+    pub cooldown_secs: u64,
+}
+
+impl Default for ResilienceConfig {
+    fn default() -> Self {
+        ResilienceConfig {
+            max_retries: 3,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 8000,
+            failure_threshold: 5,
+            cooldown_secs: 30,
+        }
+    }
+}
+
+// Generation scheduling (see scheduler.rs)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct QueueConfig {
+    // Generations (chat and dream) allowed to run at the same time
+    pub max_concurrent: usize,
+    // Jobs one user may have waiting before new ones are turned away
+    pub max_queued_per_user: usize,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            max_concurrent: 1,
+            max_queued_per_user: 3,
+        }
+    }
+}
+
+// Function calling in the mention handler (see tools.rs)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ToolsConfig {
+    // Off by default: not every model understands the `tools` field, and some answer
+    // worse for having it
+    pub enabled: bool,
+    // Rounds of tool calls allowed per reply before the model has to answer with what it has
+    pub max_iterations: usize,
+}
+
+impl Default for ToolsConfig {
+    fn default() -> Self {
+        ToolsConfig {
+            enabled: false,
+            max_iterations: 4,
+        }
+    }
+}
+
+// Feeds behind the news commands (see news.rs). Setting `feeds` replaces the defaults
+// entirely, so list every feed you want to keep.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct NewsConfig {
+    // Feed name, which is also its command (`right` for `e.right`, or `e.news <name>`)
+    pub feeds: HashMap<String, NewsFeed>,
+    // Newest items read from each URL when picking a headline
+    pub headlines_per_feed: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NewsFeed {
+    // The outlet the model imitates, e.g. "Fox News"
+    pub name: String,
+    // RSS or Atom feeds; headlines from all of them go in one pool
+    pub urls: Vec<String>,
+}
+
+impl Default for NewsConfig {
+    fn default() -> Self {
+        let feed = |name: &str, url: &str| NewsFeed { name: name.to_string(), urls: vec![url.to_string()] };
+
+        NewsConfig {
+            feeds: HashMap::from([
+                ("right".to_string(), feed("Fox News", "https://moxie.foxnews.com/google-publisher/latest.xml")),
+                ("left".to_string(), feed("PBS NewsHour", "https://www.pbs.org/newshour/feeds/rss/headlines")),
+                ("green".to_string(), feed("The Guardian (Environment)", "https://www.theguardian.com/environment/rss")),
+            ]),
+            headlines_per_feed: 20,
+        }
+    }
+}
+
+// Answers too long for one Discord message (see overflow.rs)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct OverflowConfig {
+    // Used wherever a guild hasn't picked its own with `e.overflow set`
+    pub default_policy: OverflowPolicy,
+}
+
+impl Default for OverflowConfig {
+    fn default() -> Self {
+        OverflowConfig {
+            default_policy: OverflowPolicy::Messages,
+        }
+    }
+}
+
+// How often people can ask for generations (see ratelimit.rs)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    // Chat answers: mentions and the prefix commands that ask the model something
+    pub chat: RateBudgets,
+    // `e.dream`, which ties the image backend up far longer than a chat answer
+    pub dream: RateBudgets,
+    // Roles, by id or name, whose members are never limited
+    pub exempt_roles: Vec<String>,
+}
+
+// One budget per scope; a request has to fit in all of them. Scopes left out aren't limited.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RateBudgets {
+    pub user: BucketConfig,
+    pub channel: BucketConfig,
+    pub guild: BucketConfig,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct BucketConfig {
+    // Requests allowed back to back before having to wait; 0 turns the limit off
+    pub burst: u32,
+    // How fast spent requests come back
+    pub per_minute: f64,
+}
+
+impl BucketConfig {
+    pub fn is_limited(&self) -> bool {
+        self.burst > 0 && self.per_minute > 0.0
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        let bucket = |burst, per_minute| BucketConfig { burst, per_minute };
+
+        RateLimitConfig {
+            enabled: true,
+            chat: RateBudgets {
+                user: bucket(5, 3.0),
+                channel: bucket(15, 10.0),
+                guild: bucket(30, 20.0),
+            },
+            dream: RateBudgets {
+                user: bucket(2, 0.5),
+                channel: bucket(4, 1.0),
+                guild: bucket(6, 2.0),
+            },
+            exempt_roles: Vec::new(),
+        }
+    }
+}
+
+// Who counts as an owner (see permissions.rs)
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct PermissionsConfig {
+    // User ids on top of the application's owner (or its team), which are always owners
+    pub owners: Vec<u64>,
+}
+
+pub fn config_dir() -> String {
+    let home = env::var("HOME").unwrap_or_else(|_| ".".to_string());
+    format!("{}/.config/egghead", home)
+}
+
+pub fn load() -> Config {
+    let path = env::var("EGGHEAD_CONFIG").unwrap_or_else(|_| format!("{}/config.json", config_dir()));
+
+    let mut config = match std::fs::read_to_string(&path) {
+        Ok(raw) => match serde_json::from_str::<Config>(&raw) {
+            Ok(config) => {
+                println!("Loaded config from {}", path);
+                config
+            }
+            Err(e) => {
+                eprintln!("Failed to parse config at {}: {}. Using defaults.", path, e);
+                Config::default()
+            }
+        },
+        Err(_) => {
+            println!("No config at {}, using defaults", path);
+            Config::default()
+        }
+    };
+
+    apply_env_overrides(&mut config);
+    config
+}
+
+fn apply_env_overrides(config: &mut Config) {
+    if let Ok(kind) = env::var("EGGHEAD_BACKEND") {
+        match BackendKind::parse(&kind) {
+            Some(kind) => config.backend.kind = kind,
+            None => eprintln!("Unknown EGGHEAD_BACKEND '{}', keeping {:?}", kind, config.backend.kind),
+        }
+    }
+    if let Ok(url) = env::var("EGGHEAD_BASE_URL") {
+        config.backend.base_url = Some(url);
+    }
+    if let Ok(model) = env::var("EGGHEAD_MODEL") {
+        config.backend.model = model;
+    }
+    if let Ok(key) = env::var("EGGHEAD_API_KEY") {
+        config.backend.api_key = Some(key);
+    }
+}
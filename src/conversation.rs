@@ -0,0 +1,246 @@
+use chrono::Utc;
+use rusqlite::{Connection, params};
+use serde_json::json;
+
+use crate::generator;
+
+// What stands in for an image egghead posted itself. Chat APIs only take images from the
+// user, so those go in a user turn of their own right after the answer.
+const OWN_IMAGE_NOTE: &str = "(The image you posted above.)";
+
+// Every turn egghead takes part in, keyed by its Discord message id and pointing at the
+// message it answers. Following `parent_id` up from any message rebuilds the conversation
+// that led to it without asking Discord, however long ago it was.
+pub fn create_table(conn: &Connection) -> Result<(), rusqlite::Error> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS conversation_turns (
+            message_id INTEGER PRIMARY KEY,
+            parent_id INTEGER,
+            channel_id INTEGER NOT NULL,
+            user_id INTEGER,
+            role TEXT NOT NULL,
+            content TEXT NOT NULL,
+            created_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+
+    // Stores from before turns were kept per user don't have the column; their older turns
+    // stay unattributed
+    let has_user_id = conn
+        .prepare("SELECT 1 FROM pragma_table_info('conversation_turns') WHERE name = 'user_id'")?
+        .exists([])?;
+    if !has_user_id {
+        conn.execute("ALTER TABLE conversation_turns ADD COLUMN user_id INTEGER", [])?;
+    }
+
+    // Base64-encoded images attached to a turn, in order
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS conversation_images (
+            message_id INTEGER NOT NULL,
+            position INTEGER NOT NULL,
+            data TEXT NOT NULL,
+            PRIMARY KEY (message_id, position)
+        )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct Turn {
+    pub message_id: u64,
+    // The message this one replies to, if any
+    pub parent_id: Option<u64>,
+    pub channel_id: u64,
+    // Whose turn it is: who wrote the message, or who egghead was answering
+    pub user_id: u64,
+    // "user" or "assistant"
+    pub role: &'static str,
+    pub content: String,
+    // Base64-encoded images: the user's attachments, or what egghead dreamed up
+    pub images: Vec<String>,
+}
+
+impl Turn {
+    // The turn as OpenAI-style messages; images from egghead get a user turn of their own
+    pub fn messages(&self) -> Vec<serde_json::Value> {
+        if self.role == "user" {
+            if self.content.is_empty() && self.images.is_empty() {
+                return Vec::new();
+            }
+            return vec![generator::user_message(&self.content, &self.images)];
+        }
+
+        let mut messages = Vec::new();
+        if !self.content.is_empty() {
+            messages.push(json!({
+                "role": "assistant",
+                "content": self.content
+            }));
+        }
+        if !self.images.is_empty() {
+            messages.push(generator::user_message(OWN_IMAGE_NOTE, &self.images));
+        }
+        messages
+    }
+}
+
+pub fn record(conn: &Connection, turn: &Turn) -> Result<(), rusqlite::Error> {
+    conn.execute(
+        "INSERT OR REPLACE INTO conversation_turns (message_id, parent_id, channel_id, user_id, role, content, created_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            turn.message_id as i64,
+            turn.parent_id.map(|id| id as i64),
+            turn.channel_id as i64,
+            turn.user_id as i64,
+            turn.role,
+            turn.content,
+            Utc::now().timestamp(),
+        ],
+    )?;
+
+    conn.execute(
+        "DELETE FROM conversation_images WHERE message_id = ?1",
+        params![turn.message_id as i64],
+    )?;
+    for (position, image) in turn.images.iter().enumerate() {
+        conn.execute(
+            "INSERT INTO conversation_images (message_id, position, data) VALUES (?1, ?2, ?3)",
+            params![turn.message_id as i64, position as i64, image],
+        )?;
+    }
+
+    Ok(())
+}
+
+// The turns leading up to and including `message_id`, oldest first, at most `max_turns` of
+// them. Empty if the message isn't in the store.
+pub fn chain(conn: &Connection, message_id: u64, max_turns: usize) -> Result<Vec<Turn>, rusqlite::Error> {
+    let mut stmt = conn.prepare(
+        "WITH RECURSIVE chain(message_id, parent_id, channel_id, user_id, role, content, depth) AS (
+            SELECT message_id, parent_id, channel_id, user_id, role, content, 0
+            FROM conversation_turns WHERE message_id = ?1
+            UNION ALL
+            SELECT t.message_id, t.parent_id, t.channel_id, t.user_id, t.role, t.content, chain.depth + 1
+            FROM conversation_turns t JOIN chain ON t.message_id = chain.parent_id
+            WHERE chain.depth + 1 < ?2
+        )
+        SELECT message_id, parent_id, channel_id, user_id, role, content FROM chain ORDER BY depth DESC",
+    )?;
+
+    let mut turns = stmt
+        .query_map(params![message_id as i64, max_turns as i64], |row| {
+            let role: String = row.get(4)?;
+            Ok(Turn {
+                message_id: row.get::<_, i64>(0)? as u64,
+                parent_id: row.get::<_, Option<i64>>(1)?.map(|id| id as u64),
+                channel_id: row.get::<_, i64>(2)? as u64,
+                user_id: row.get::<_, Option<i64>>(3)?.unwrap_or(0) as u64,
+                role: if role == "assistant" { "assistant" } else { "user" },
+                content: row.get(5)?,
+                images: Vec::new(),
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut images = conn.prepare("SELECT data FROM conversation_images WHERE message_id = ?1 ORDER BY position")?;
+    for turn in &mut turns {
+        turn.images = images
+            .query_map(params![turn.message_id as i64], |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+    }
+
+    Ok(turns)
+}
+
+// Deletes every turn that's the user's, egghead's answers to them included, with their images.
+// Returns how many turns went.
+pub fn forget_user(conn: &Connection, user_id: u64) -> Result<usize, rusqlite::Error> {
+    conn.execute(
+        "DELETE FROM conversation_images WHERE message_id IN (
+            SELECT message_id FROM conversation_turns WHERE user_id = ?1
+        )",
+        params![user_id as i64],
+    )?;
+    conn.execute("DELETE FROM conversation_turns WHERE user_id = ?1", params![user_id as i64])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn turn(message_id: u64, parent_id: Option<u64>, user_id: u64, role: &'static str, images: Vec<String>) -> Turn {
+        Turn {
+            message_id,
+            parent_id,
+            channel_id: 1,
+            user_id,
+            role,
+            content: format!("turn {}", message_id),
+            images,
+        }
+    }
+
+    #[test]
+    fn follows_parents_oldest_first() {
+        let conn = Connection::open_in_memory().unwrap();
+        create_table(&conn).unwrap();
+        record(&conn, &turn(10, Some(5), 7, "user", vec!["image".to_string()])).unwrap();
+        record(&conn, &turn(11, Some(10), 7, "assistant", Vec::new())).unwrap();
+        record(&conn, &turn(12, Some(11), 7, "user", Vec::new())).unwrap();
+
+        let ids: Vec<u64> = chain(&conn, 12, 10).unwrap().iter().map(|turn| turn.message_id).collect();
+        assert_eq!(ids, [10, 11, 12]);
+
+        let newest = chain(&conn, 12, 2).unwrap();
+        assert_eq!(newest.iter().map(|turn| turn.message_id).collect::<Vec<_>>(), [11, 12]);
+        // Where the chain left the store
+        assert_eq!(chain(&conn, 12, 10).unwrap()[0].parent_id, Some(5));
+        assert_eq!(chain(&conn, 10, 10).unwrap()[0].images, ["image"]);
+        assert!(chain(&conn, 99, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn forgets_one_users_turns() {
+        let conn = Connection::open_in_memory().unwrap();
+        create_table(&conn).unwrap();
+        record(&conn, &turn(10, None, 7, "user", vec!["image".to_string()])).unwrap();
+        record(&conn, &turn(11, Some(10), 7, "assistant", Vec::new())).unwrap();
+        record(&conn, &turn(20, None, 8, "user", Vec::new())).unwrap();
+
+        assert_eq!(forget_user(&conn, 7).unwrap(), 2);
+        assert!(chain(&conn, 11, 10).unwrap().is_empty());
+        assert_eq!(chain(&conn, 20, 10).unwrap().len(), 1);
+
+        let images: i64 = conn.query_row("SELECT COUNT(*) FROM conversation_images", [], |row| row.get(0)).unwrap();
+        assert_eq!(images, 0);
+    }
+
+    #[test]
+    fn adds_user_id_to_old_stores() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute(
+            "CREATE TABLE conversation_turns (
+                message_id INTEGER PRIMARY KEY,
+                parent_id INTEGER,
+                channel_id INTEGER NOT NULL,
+                role TEXT NOT NULL,
+                content TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            )",
+            [],
+        )
+        .unwrap();
+        conn.execute("INSERT INTO conversation_turns VALUES (1, NULL, 1, 'user', 'old', 0)", []).unwrap();
+
+        create_table(&conn).unwrap();
+        // Twice is fine too
+        create_table(&conn).unwrap();
+        assert_eq!(chain(&conn, 1, 10).unwrap()[0].content, "old");
+        record(&conn, &turn(2, Some(1), 7, "user", Vec::new())).unwrap();
+        assert_eq!(forget_user(&conn, 7).unwrap(), 1);
+    }
+}
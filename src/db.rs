@@ -0,0 +1,48 @@
+use rusqlite::Connection;
+use std::env;
+use std::sync::Arc;
+
+use crate::config;
+use crate::conversation;
+use crate::memory;
+use crate::overflow;
+use crate::permissions;
+use crate::persona;
+
+// Bot state (personas and friends) lives in its own SQLite file, separate from the blog.
+// Use ~/.config/egghead/egghead.sqlite unless EGGHEAD_DB_PATH says otherwise.
+pub fn default_path() -> String {
+    env::var("EGGHEAD_DB_PATH").unwrap_or_else(|_| {
+        let config_dir = config::config_dir();
+        // Create the directory if it doesn't exist
+        std::fs::create_dir_all(&config_dir).ok();
+        format!("{}/egghead.sqlite", config_dir)
+    })
+}
+
+pub fn init_database(db_path: &str) -> Result<Connection, rusqlite::Error> {
+    let conn = Connection::open(db_path)?;
+
+    persona::create_table(&conn)?;
+    overflow::create_table(&conn)?;
+    conversation::create_table(&conn)?;
+    memory::create_table(&conn)?;
+    permissions::create_table(&conn)?;
+
+    Ok(conn)
+}
+
+// Runs `f` against a fresh connection on the blocking pool so SQLite never stalls the
+// gateway tasks.
+pub async fn with_connection<T, F>(db_path: Arc<String>, f: F) -> Result<T, rusqlite::Error>
+where
+    T: Send + 'static,
+    F: FnOnce(&Connection) -> Result<T, rusqlite::Error> + Send + 'static,
+{
+    tokio::task::spawn_blocking(move || {
+        let conn = Connection::open(db_path.as_str())?;
+        f(&conn)
+    })
+    .await
+    .unwrap_or_else(|e| Err(rusqlite::Error::ToSqlConversionFailure(Box::new(e))))
+}
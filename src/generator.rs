@@ -1,100 +1,1242 @@
-use reqwest::blocking::Client;
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use reqwest::{Client, Response};
+use std::fmt;
 use std::time::Duration;
-use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-pub fn get_chat_response(temp: &str, init: &str, prompt: &str, images: Option<Vec<String>>, conversation_history: Option<Vec<serde_json::Value>>) -> Result<String, reqwest::Error> {
-    let client = Client::builder()
-        .timeout(Duration::from_secs(360))
-        .build()?;
+use crate::circuit::CircuitBreaker;
+use crate::config::{BackendConfig, BackendKind, Config, ResilienceConfig};
+use crate::routing::Router;
+use crate::tokens::{self, ContextBudget};
 
-    // Build messages array with system message
-    let mut messages = vec![
+// Everything a backend needs to produce one reply. `messages` are always in the OpenAI shape
+// (content may be a string or an array of text/image_url parts); backends that speak
+// something else translate on the way out.
+pub struct ChatRequest {
+    // Overrides the backend's configured model when set
+    pub model: Option<String>,
+    pub messages: Vec<serde_json::Value>,
+    pub temperature: f64,
+    pub max_tokens: u32,
+    pub timeout: Duration,
+    // OpenAI-style function definitions the model may call; empty disables tool calling
+    pub tools: Vec<serde_json::Value>,
+}
+
+// A function call requested by the model
+#[derive(Debug, Clone)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    // JSON-encoded arguments object
+    pub arguments: String,
+}
+
+impl ToolCall {
+    // The `tool` message that hands `result` back to the model
+    pub fn result_message(&self, result: &str) -> serde_json::Value {
         json!({
-            "role": "system",
-            "content": init
+            "role": "tool",
+            "tool_call_id": self.id,
+            "name": self.name,
+            "content": result,
         })
-    ];
+    }
+}
 
-    // Add conversation history if provided
-    if let Some(history) = conversation_history {
-        messages.extend(history);
+// What the model said: text, tool calls, or both
+#[derive(Debug, Clone, Default)]
+pub struct Completion {
+    pub content: String,
+    pub tool_calls: Vec<ToolCall>,
+    // The model ran into `max_tokens` rather than finishing its answer
+    pub truncated: bool,
+}
+
+impl Completion {
+    // The assistant turn to replay before the tool results on the next request
+    pub fn assistant_message(&self) -> serde_json::Value {
+        let tool_calls: Vec<serde_json::Value> = self.tool_calls
+            .iter()
+            .map(|call| json!({
+                "id": call.id,
+                "type": "function",
+                "function": { "name": call.name, "arguments": call.arguments },
+            }))
+            .collect();
+
+        json!({
+            "role": "assistant",
+            "content": self.content,
+            "tool_calls": tool_calls,
+        })
     }
+}
 
-    // Build user message content
-    let user_message = if let Some(img_list) = images {
-        if !img_list.is_empty() {
-            // OpenAI vision format: content is an array of text and image_url objects
-            let mut content_parts = vec![
-                json!({
-                    "type": "text",
-                    "text": prompt
-                })
-            ];
+#[derive(Debug)]
+pub enum GeneratorError {
+    // The backend took longer than the request's timeout
+    Timeout,
+    // Couldn't reach the backend at all
+    Connection(String),
+    // Non-2xx response without a recognisable error payload
+    Http { status: u16, body: String },
+    // The body (or a stream chunk) wasn't the JSON we expected
+    MalformedJson(String),
+    // A well-formed response with no text or tool calls in it
+    EmptyChoices,
+    // The backend answered with an explicit error message
+    Api(String),
+    // The system prompt and new message alone don't fit the model's context window
+    ContextOverflow { needed: usize, available: usize },
+    // Too many recent failures; not even trying until the cooldown passes
+    CircuitOpen { retry_in: Duration },
+}
 
-            for img_base64 in img_list {
-                content_parts.push(json!({
-                    "type": "image_url",
-                    "image_url": {
-                        "url": format!("data:image/jpeg;base64,{}", img_base64)
-                    }
-                }));
+impl fmt::Display for GeneratorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GeneratorError::Timeout => write!(f, "request to the model timed out"),
+            GeneratorError::Connection(e) => write!(f, "could not reach the model: {}", e),
+            GeneratorError::Http { status, body } => write!(f, "model returned HTTP {}: {}", status, body),
+            GeneratorError::MalformedJson(e) => write!(f, "malformed JSON from the model: {}", e),
+            GeneratorError::EmptyChoices => write!(f, "model returned no content"),
+            GeneratorError::Api(e) => write!(f, "model API error: {}", e),
+            GeneratorError::ContextOverflow { needed, available } => {
+                write!(f, "prompt needs ~{} tokens but only {} are available", needed, available)
             }
+            GeneratorError::CircuitOpen { retry_in } => {
+                write!(f, "circuit open after repeated failures, retrying in {}s", retry_in.as_secs())
+            }
+        }
+    }
+}
 
-            println!("Sending request with {} image(s)", content_parts.len() - 1);
+impl std::error::Error for GeneratorError {}
 
-            json!({
-                "role": "user",
-                "content": content_parts
-            })
+impl GeneratorError {
+    // Worth another attempt: the backend is unreachable or fell over (Ollama answers 5xx while
+    // it's loading a model)
+    fn is_retryable(&self) -> bool {
+        match self {
+            GeneratorError::Connection(_) => true,
+            GeneratorError::Http { status, .. } => *status >= 500,
+            _ => false,
+        }
+    }
+
+    // Counts against the circuit breaker. Timeouts aren't retried (another six minute wait
+    // helps nobody) but they do mean the backend isn't healthy.
+    fn is_backend_failure(&self) -> bool {
+        self.is_retryable() || matches!(self, GeneratorError::Timeout)
+    }
+}
+
+enum Outcome<T> {
+    Finished(Result<T, GeneratorError>),
+    RetryAfter(Duration),
+}
+
+impl From<reqwest::Error> for GeneratorError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            GeneratorError::Timeout
+        } else if e.is_decode() {
+            GeneratorError::MalformedJson(e.to_string())
+        } else if let Some(status) = e.status() {
+            GeneratorError::Http { status: status.as_u16(), body: e.to_string() }
         } else {
-            json!({
-                "role": "user",
-                "content": prompt
-            })
+            GeneratorError::Connection(e.to_string())
         }
-    } else {
-        json!({
-            "role": "user",
-            "content": prompt
-        })
-    };
+    }
+}
 
-    messages.push(user_message);
+// One user turn plus everything around it needed to answer it
+#[derive(Debug, Clone)]
+pub struct ChatPrompt {
+    // Normally filled in from `Router::route`; None uses the backend's configured model
+    pub model: Option<String>,
+    pub temperature: f64,
+    pub system_prompt: String,
+    pub prompt: String,
+    // Base64-encoded images attached to this turn
+    pub images: Vec<String>,
+    // Earlier turns, oldest first, as OpenAI-style messages
+    pub history: Vec<serde_json::Value>,
+    // Functions on offer to the model (see tools.rs)
+    pub tools: Vec<serde_json::Value>,
+    // Assistant tool calls and their results so far for this turn, replayed after the prompt
+    pub tool_turns: Vec<serde_json::Value>,
+}
+
+pub type TokenSink<'a> = &'a mut (dyn FnMut(&str) + Send);
 
-    let request_data = json!({
-        "model": "riven/smolvlm",
-        "max_tokens": 1024,
-        "messages": messages,
-        "temperature": temp.parse::<f64>().unwrap(),
-        "stream": false,
+// Backends share the Generator's pooled client. Dropping a returned future drops the
+// in-flight HTTP request with it, which is how callers cancel a generation.
+#[async_trait]
+pub trait ChatBackend: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn model(&self) -> &str;
+    async fn chat(&self, client: &Client, request: &ChatRequest) -> Result<Completion, GeneratorError>;
+    // Same as `chat`, but hands each piece of the reply text to `on_token` as it arrives.
+    // Returns the whole completion once the stream ends.
+    async fn chat_stream(&self, client: &Client, request: &ChatRequest, on_token: TokenSink<'_>) -> Result<Completion, GeneratorError>;
+}
+
+pub struct OpenAiBackend {
+    base_url: String,
+    model: String,
+    api_key: Option<String>,
+}
+
+pub struct OllamaBackend {
+    base_url: String,
+    model: String,
+}
+
+pub struct LlamaCppBackend {
+    base_url: String,
+    model: String,
+    api_key: Option<String>,
+}
+
+#[async_trait]
+impl ChatBackend for OpenAiBackend {
+    fn name(&self) -> &'static str {
+        "openai"
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn chat(&self, client: &Client, request: &ChatRequest) -> Result<Completion, GeneratorError> {
+        let request_data = openai_request_data(&self.model, request, false);
+
+        let response = openai_post(client, &self.base_url, self.api_key.as_deref(), request, &request_data).await?;
+        read_openai_response(response).await
+    }
+
+    async fn chat_stream(&self, client: &Client, request: &ChatRequest, on_token: TokenSink<'_>) -> Result<Completion, GeneratorError> {
+        let request_data = openai_request_data(&self.model, request, true);
+
+        let response = openai_post(client, &self.base_url, self.api_key.as_deref(), request, &request_data).await?;
+        read_openai_stream(response, on_token).await
+    }
+}
+
+#[async_trait]
+impl ChatBackend for LlamaCppBackend {
+    fn name(&self) -> &'static str {
+        "llamacpp"
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn chat(&self, client: &Client, request: &ChatRequest) -> Result<Completion, GeneratorError> {
+        let request_data = self.request_data(request, false);
+
+        let response = openai_post(client, &self.base_url, self.api_key.as_deref(), request, &request_data).await?;
+        read_openai_response(response).await
+    }
+
+    async fn chat_stream(&self, client: &Client, request: &ChatRequest, on_token: TokenSink<'_>) -> Result<Completion, GeneratorError> {
+        let request_data = self.request_data(request, true);
+
+        let response = openai_post(client, &self.base_url, self.api_key.as_deref(), request, &request_data).await?;
+        read_openai_stream(response, on_token).await
+    }
+}
+
+impl LlamaCppBackend {
+    fn request_data(&self, request: &ChatRequest, stream: bool) -> serde_json::Value {
+        // llama.cpp serves whatever model it was launched with and ignores `model`, but its
+        // OpenAI-compatible endpoint still wants the field. `cache_prompt` keeps the KV cache
+        // between turns so long reply chains don't get re-evaluated from scratch.
+        let mut request_data = openai_request_data(&self.model, request, stream);
+        request_data["cache_prompt"] = json!(true);
+        request_data
+    }
+}
+
+#[async_trait]
+impl ChatBackend for OllamaBackend {
+    fn name(&self) -> &'static str {
+        "ollama"
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn chat(&self, client: &Client, request: &ChatRequest) -> Result<Completion, GeneratorError> {
+        let response = self.post(client, request, false).await?;
+        let response_json = read_json(response).await?;
+
+        // Extract response from Ollama format: message.content and message.tool_calls
+        let completion = Completion {
+            content: response_json["message"]["content"].as_str().unwrap_or_default().to_string(),
+            tool_calls: ollama_tool_calls(&response_json["message"], 0),
+            truncated: response_json["done_reason"] == "length",
+        };
+
+        if completion.content.is_empty() && completion.tool_calls.is_empty() {
+            eprintln!("No 'message.content' field in JSON: {:?}", response_json);
+            return Err(GeneratorError::EmptyChoices);
+        }
+
+        Ok(completion)
+    }
+
+    async fn chat_stream(&self, client: &Client, request: &ChatRequest, on_token: TokenSink<'_>) -> Result<Completion, GeneratorError> {
+        let response = check_status(self.post(client, request, true).await?).await?;
+
+        // Ollama streams newline-delimited JSON objects rather than SSE. Tool calls arrive
+        // whole, in a single chunk.
+        let mut completion = Completion::default();
+        let mut api_error = None;
+        for_each_line(response, |line| {
+            if line.trim().is_empty() {
+                return true;
+            }
+
+            let chunk: serde_json::Value = match serde_json::from_str(line) {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    eprintln!("Skipping malformed stream chunk {:?}: {}", line, e);
+                    return true;
+                }
+            };
+
+            if let Some(error) = chunk.get("error") {
+                eprintln!("Ollama API error: {}", error);
+                api_error = Some(GeneratorError::Api(error_message(error)));
+                return false;
+            }
+
+            if let Some(token) = chunk["message"]["content"].as_str() {
+                if !token.is_empty() {
+                    completion.content.push_str(token);
+                    on_token(token);
+                }
+            }
+
+            let calls = ollama_tool_calls(&chunk["message"], completion.tool_calls.len());
+            completion.tool_calls.extend(calls);
+
+            // Only the final chunk says why it stopped
+            if chunk["done_reason"] == "length" {
+                completion.truncated = true;
+            }
+
+            !chunk["done"].as_bool().unwrap_or(false)
+        }).await?;
+
+        finish_stream(api_error, completion)
+    }
+}
+
+impl OllamaBackend {
+    async fn post(&self, client: &Client, request: &ChatRequest, stream: bool) -> Result<Response, GeneratorError> {
+        let messages: Vec<serde_json::Value> = request.messages.iter().map(to_ollama_message).collect();
+
+        let mut request_data = json!({
+            "model": request.model.as_deref().unwrap_or(&self.model),
+            "messages": messages,
+            "stream": stream,
+            "options": {
+                "temperature": request.temperature,
+                "num_predict": request.max_tokens,
+            },
+        });
+        if !request.tools.is_empty() {
+            request_data["tools"] = json!(request.tools);
+        }
+
+        client
+            .post(format!("{}/api/chat", self.base_url))
+            .header("Content-Type", "application/json")
+            .timeout(request.timeout)
+            .json(&request_data)
+            .send()
+            .await
+            .map_err(GeneratorError::from)
+    }
+}
+
+fn openai_request_data(model: &str, request: &ChatRequest, stream: bool) -> serde_json::Value {
+    let mut request_data = json!({
+        "model": request.model.as_deref().unwrap_or(model),
+        "max_tokens": request.max_tokens,
+        "messages": request.messages,
+        "temperature": request.temperature,
+        "stream": stream,
     });
+    if !request.tools.is_empty() {
+        request_data["tools"] = json!(request.tools);
+    }
+    request_data
+}
+
+// Ollama sends arguments as an object and doesn't number its calls; normalise both
+fn ollama_tool_calls(message: &serde_json::Value, first_index: usize) -> Vec<ToolCall> {
+    message["tool_calls"]
+        .as_array()
+        .map(|calls| {
+            calls
+                .iter()
+                .enumerate()
+                .filter_map(|(i, call)| {
+                    let name = call["function"]["name"].as_str()?.to_string();
+                    let arguments = match &call["function"]["arguments"] {
+                        serde_json::Value::String(raw) => raw.clone(),
+                        serde_json::Value::Null => "{}".to_string(),
+                        other => other.to_string(),
+                    };
+                    Some(ToolCall { id: format!("call_{}", first_index + i), name, arguments })
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
 
-    let response = client
-        .post("http://localhost:11434/v1/chat/completions")
+async fn openai_post(client: &Client, base_url: &str, api_key: Option<&str>, request: &ChatRequest, request_data: &serde_json::Value) -> Result<Response, GeneratorError> {
+    let mut builder = client
+        .post(format!("{}/v1/chat/completions", base_url))
         .header("Content-Type", "application/json")
-        .json(&request_data)
-        .send()?;
+        .timeout(request.timeout)
+        .json(request_data);
+
+    if let Some(key) = api_key {
+        builder = builder.bearer_auth(key);
+    }
+
+    Ok(builder.send().await?)
+}
+
+async fn read_openai_response(response: Response) -> Result<Completion, GeneratorError> {
+    let response_json = read_json(response).await?;
+
+    // Extract response from OpenAI format: choices[0].message.content and .tool_calls
+    let message = &response_json["choices"][0]["message"];
+    let tool_calls = message["tool_calls"]
+        .as_array()
+        .map(|calls| {
+            calls
+                .iter()
+                .filter_map(|call| Some(ToolCall {
+                    id: call["id"].as_str().unwrap_or_default().to_string(),
+                    name: call["function"]["name"].as_str()?.to_string(),
+                    arguments: call["function"]["arguments"].as_str().unwrap_or("{}").to_string(),
+                }))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let completion = Completion {
+        content: message["content"].as_str().unwrap_or_default().to_string(),
+        tool_calls,
+        truncated: response_json["choices"][0]["finish_reason"] == "length",
+    };
+
+    if completion.content.is_empty() && completion.tool_calls.is_empty() {
+        eprintln!("No 'choices[0].message.content' field in JSON: {:?}", response_json);
+        return Err(GeneratorError::EmptyChoices);
+    }
+
+    Ok(completion)
+}
+
+// Reads a whole JSON body, turning non-2xx statuses and `{"error": ...}` payloads into errors
+async fn read_json(response: Response) -> Result<serde_json::Value, GeneratorError> {
+    let response = check_status(response).await?;
+    let body = response.text().await?;
+
+    let response_json: serde_json::Value = serde_json::from_str(&body)
+        .map_err(|e| GeneratorError::MalformedJson(format!("{} in {:?}", e, body)))?;
+
+    if let Some(error) = response_json.get("error") {
+        eprintln!("API error: {}", error);
+        return Err(GeneratorError::Api(error_message(error)));
+    }
+
+    Ok(response_json)
+}
 
+async fn check_status(response: Response) -> Result<Response, GeneratorError> {
     let status = response.status();
     println!("Response status: {}", status);
 
-    let response_json: serde_json::Value = response.json().unwrap();
-    println!("Response JSON: {:?}", response_json);
+    if status.is_success() {
+        return Ok(response);
+    }
 
-    // Check for error in response
-    if let Some(error) = response_json.get("error") {
-        eprintln!("OpenAI API error: {}", error);
-        return Ok(format!("Error from API: {}", error));
+    // Both OpenAI-style servers and Ollama usually explain failures in an `error` field
+    let body = response.text().await.unwrap_or_default();
+    eprintln!("API returned {}: {}", status, body);
+    match serde_json::from_str::<serde_json::Value>(&body) {
+        Ok(json) if json.get("error").is_some() => Err(GeneratorError::Api(error_message(&json["error"]))),
+        _ => Err(GeneratorError::Http { status: status.as_u16(), body }),
     }
+}
 
-    // Extract response from OpenAI format: choices[0].message.content
-    let completion_text = response_json["choices"][0]["message"]["content"]
+// OpenAI nests the text under `error.message`; Ollama and llama.cpp send a bare string
+fn error_message(error: &serde_json::Value) -> String {
+    error["message"]
         .as_str()
-        .unwrap_or_else(|| {
-            eprintln!("No 'choices[0].message.content' field in JSON: {:?}", response_json);
-            "Prompt machine broke - no response field"
+        .or_else(|| error.as_str())
+        .map(|s| s.to_string())
+        .unwrap_or_else(|| error.to_string())
+}
+
+fn finish_stream(api_error: Option<GeneratorError>, completion: Completion) -> Result<Completion, GeneratorError> {
+    match api_error {
+        Some(error) => Err(error),
+        None if completion.content.trim().is_empty() && completion.tool_calls.is_empty() => Err(GeneratorError::EmptyChoices),
+        None => Ok(completion),
+    }
+}
+
+// OpenAI-style servers stream Server-Sent Events: `data: {json}` lines, each carrying a
+// `choices[0].delta`, terminated by `data: [DONE]`.
+async fn read_openai_stream(response: Response, on_token: TokenSink<'_>) -> Result<Completion, GeneratorError> {
+    // Errors come back as a plain JSON body, not as an event stream
+    let response = check_status(response).await?;
+
+    let mut completion = Completion::default();
+    let mut api_error = None;
+    for_each_line(response, |line| {
+        let data = match line.strip_prefix("data:") {
+            Some(data) => data.trim(),
+            // Blank separators, comments and `event:` lines carry no tokens
+            None => return true,
+        };
+        if data == "[DONE]" {
+            return false;
+        }
+
+        let chunk: serde_json::Value = match serde_json::from_str(data) {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                eprintln!("Skipping malformed stream chunk {:?}: {}", data, e);
+                return true;
+            }
+        };
+
+        if let Some(error) = chunk.get("error") {
+            eprintln!("OpenAI API error: {}", error);
+            api_error = Some(GeneratorError::Api(error_message(error)));
+            return false;
+        }
+
+        if chunk["choices"][0]["finish_reason"] == "length" {
+            completion.truncated = true;
+        }
+
+        let delta = &chunk["choices"][0]["delta"];
+        if let Some(token) = delta["content"].as_str() {
+            if !token.is_empty() {
+                completion.content.push_str(token);
+                on_token(token);
+            }
+        }
+
+        // Tool calls arrive in fragments keyed by `index`: the id and name first, then the
+        // arguments string a piece at a time
+        if let Some(calls) = delta["tool_calls"].as_array() {
+            for call in calls {
+                let index = call["index"].as_u64().unwrap_or(0) as usize;
+                while completion.tool_calls.len() <= index {
+                    completion.tool_calls.push(ToolCall {
+                        id: format!("call_{}", completion.tool_calls.len()),
+                        name: String::new(),
+                        arguments: String::new(),
+                    });
+                }
+
+                let entry = &mut completion.tool_calls[index];
+                if let Some(id) = call["id"].as_str() {
+                    entry.id = id.to_string();
+                }
+                if let Some(name) = call["function"]["name"].as_str() {
+                    entry.name.push_str(name);
+                }
+                if let Some(arguments) = call["function"]["arguments"].as_str() {
+                    entry.arguments.push_str(arguments);
+                }
+            }
+        }
+
+        true
+    }).await?;
+
+    completion.tool_calls.retain(|call| !call.name.is_empty());
+    finish_stream(api_error, completion)
+}
+
+// Feeds each complete line of a streamed body to `f` until the body ends or `f` returns
+// false. Lines are only decoded once whole, so multibyte characters split across network
+// chunks survive intact.
+async fn for_each_line(response: Response, mut f: impl FnMut(&str) -> bool + Send) -> Result<(), GeneratorError> {
+    let mut stream = response.bytes_stream();
+    let mut pending: Vec<u8> = Vec::new();
+
+    while let Some(chunk) = stream.next().await {
+        pending.extend_from_slice(&chunk?);
+
+        while let Some(newline) = pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=newline).collect();
+            let line = String::from_utf8_lossy(&line);
+            if !f(line.trim_end_matches(['\r', '\n'])) {
+                return Ok(());
+            }
+        }
+    }
+
+    if !pending.is_empty() {
+        f(String::from_utf8_lossy(&pending).trim_end());
+    }
+
+    Ok(())
+}
+
+// Ollama's native API wants a plain string `content` plus a separate list of bare base64
+// `images`, rather than OpenAI's array of typed parts.
+fn to_ollama_message(message: &serde_json::Value) -> serde_json::Value {
+    if let Some(calls) = message["tool_calls"].as_array() {
+        // Arguments go back as objects, the way Ollama sent them
+        let tool_calls: Vec<serde_json::Value> = calls
+            .iter()
+            .map(|call| {
+                let arguments = call["function"]["arguments"]
+                    .as_str()
+                    .and_then(|raw| serde_json::from_str(raw).ok())
+                    .unwrap_or_else(|| json!({}));
+                json!({ "function": { "name": call["function"]["name"], "arguments": arguments } })
+            })
+            .collect();
+
+        return json!({
+            "role": "assistant",
+            "content": message["content"].as_str().unwrap_or_default(),
+            "tool_calls": tool_calls,
+        });
+    }
+
+    let parts = match message["content"].as_array() {
+        Some(parts) => parts,
+        None => return message.clone(),
+    };
+
+    let mut text = Vec::new();
+    let mut images = Vec::new();
+
+    for part in parts {
+        match part["type"].as_str() {
+            Some("text") => {
+                if let Some(t) = part["text"].as_str() {
+                    text.push(t.to_string());
+                }
+            }
+            Some("image_url") => {
+                if let Some(url) = part["image_url"]["url"].as_str() {
+                    let data = url.split_once(";base64,").map(|(_, data)| data).unwrap_or(url);
+                    images.push(data.to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    json!({
+        "role": message["role"],
+        "content": text.join("\n"),
+        "images": images,
+    })
+}
+
+pub fn backend_from_config(config: &BackendConfig) -> Box<dyn ChatBackend> {
+    let base_url = config.base_url();
+    let model = config.model.clone();
+
+    match config.kind {
+        BackendKind::OpenAi => Box::new(OpenAiBackend { base_url, model, api_key: config.api_key.clone() }),
+        BackendKind::Ollama => Box::new(OllamaBackend { base_url, model }),
+        BackendKind::LlamaCpp => Box::new(LlamaCppBackend { base_url, model, api_key: config.api_key.clone() }),
+    }
+}
+
+// Knobs for `txt2img`
+#[derive(Debug, Clone, Copy)]
+pub struct DreamOptions {
+    pub steps: u32,
+    pub width: u32,
+    pub height: u32,
+    pub seed: Option<i64>,
+}
+
+impl Default for DreamOptions {
+    fn default() -> Self {
+        DreamOptions {
+            steps: 25,
+            width: 512,
+            height: 512,
+            seed: None,
+        }
+    }
+}
+
+// Stable Diffusion txt2img endpoint used by `dream`
+const TXT2IMG_URL: &str = "http://localhost:11434/sdapi/v1/txt2img";
+
+pub struct Generator {
+    backend: Box<dyn ChatBackend>,
+    router: Router,
+    budget: ContextBudget,
+    circuit: CircuitBreaker,
+    resilience: ResilienceConfig,
+    // One pooled client for every request; connections to the model server are reused
+    client: Client,
+}
+
+impl Generator {
+    pub fn from_config(config: &Config) -> Self {
+        let backend = backend_from_config(&config.backend);
+        println!("Using {} backend at {} with model {}", backend.name(), config.backend.base_url(), backend.model());
+
+        let router = Router::new(&config.routing, backend.model());
+        println!("Routing images to {} and text to {}", router.vision_model(), router.text_model());
+
+        let client = Client::builder()
+            .connect_timeout(Duration::from_secs(10))
+            .pool_idle_timeout(Duration::from_secs(90))
+            .build()
+            .expect("Failed to build HTTP client");
+
+        let budget = ContextBudget::new(&config.context);
+
+        let circuit = CircuitBreaker::new(&config.resilience);
+
+        Generator { backend, router, budget, circuit, resilience: config.resilience.clone(), client }
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    pub fn router(&self) -> &Router {
+        &self.router
+    }
+
+    pub fn circuit(&self) -> &CircuitBreaker {
+        &self.circuit
+    }
+
+    pub fn budget(&self) -> &ContextBudget {
+        &self.budget
+    }
+
+    // The model `complete` talks to
+    pub fn default_model(&self) -> &str {
+        self.backend.model()
+    }
+
+    // Tokens are passed to `on_token` as the backend produces them; the returned `Completion`
+    // has the whole reply along with any tool calls and whether it ran into `max_tokens`.
+    pub async fn stream_chat_response(&self, chat: &ChatPrompt, on_token: TokenSink<'_>) -> Result<Completion, GeneratorError> {
+        let request = self.chat_request(chat)?;
+
+        let mut attempt = 0;
+        loop {
+            self.check_circuit()?;
+
+            // Once tokens have reached the caller a retry would repeat them, so only a
+            // failure before the first token is retried
+            let mut streamed = false;
+            let result = {
+                let mut sink = |token: &str| {
+                    streamed = true;
+                    on_token(token);
+                };
+                self.backend.chat_stream(&self.client, &request, &mut sink).await
+            };
+
+            match self.outcome(result, attempt, !streamed) {
+                Outcome::Finished(result) => return result,
+                Outcome::RetryAfter(delay) => tokio::time::sleep(delay).await,
+            }
+            attempt += 1;
+        }
+    }
+
+    // Single-shot prompt with no system message or history, used by the blog writer
+    pub async fn complete(&self, prompt: &str, temperature: f64, max_tokens: u32, timeout: Duration) -> Result<String, GeneratorError> {
+        let request = ChatRequest {
+            model: None,
+            messages: vec![json!({ "role": "user", "content": prompt })],
+            temperature,
+            max_tokens,
+            timeout,
+            tools: Vec::new(),
+        };
+
+        let mut attempt = 0;
+        loop {
+            self.check_circuit()?;
+
+            let result = self.backend.chat(&self.client, &request).await;
+            match self.outcome(result, attempt, true) {
+                Outcome::Finished(result) => return result.map(|completion| completion.content),
+                Outcome::RetryAfter(delay) => tokio::time::sleep(delay).await,
+            }
+            attempt += 1;
+        }
+    }
+
+    fn check_circuit(&self) -> Result<(), GeneratorError> {
+        self.circuit.allow().map_err(|retry_in| GeneratorError::CircuitOpen { retry_in })
+    }
+
+    // Records the attempt with the circuit breaker and decides whether to go again
+    fn outcome<T>(&self, result: Result<T, GeneratorError>, attempt: u32, can_retry: bool) -> Outcome<T> {
+        let error = match result {
+            Ok(value) => {
+                self.circuit.record_success();
+                return Outcome::Finished(Ok(value));
+            }
+            Err(error) => error,
+        };
+
+        if !error.is_backend_failure() {
+            // The backend answered, it just didn't like the request
+            self.circuit.record_success();
+            return Outcome::Finished(Err(error));
+        }
+
+        self.circuit.record_failure(&error.to_string());
+
+        if !can_retry || !error.is_retryable() || attempt >= self.resilience.max_retries {
+            return Outcome::Finished(Err(error));
+        }
+
+        let backoff = self.resilience.initial_backoff_ms.saturating_mul(1 << attempt.min(16));
+        let delay = Duration::from_millis(backoff.min(self.resilience.max_backoff_ms));
+        eprintln!("Attempt {} failed ({}), retrying in {}ms", attempt + 1, error, delay.as_millis());
+        Outcome::RetryAfter(delay)
+    }
+
+    fn chat_request(&self, chat: &ChatPrompt) -> Result<ChatRequest, GeneratorError> {
+        let system = json!({
+            "role": "system",
+            "content": chat.system_prompt
+        });
+        let user = user_message(&chat.prompt, &chat.images);
+        if !chat.images.is_empty() {
+            println!("Sending request with {} image(s)", chat.images.len());
+        }
+
+        // The current turn, plus any tool calls made while answering it
+        let mut current = vec![user];
+        current.extend(chat.tool_turns.iter().cloned());
+
+        // Make sure everything fits the model's window, dropping the oldest history first
+        let model = chat.model.as_deref().unwrap_or(self.backend.model());
+        let mut history = chat.history.clone();
+        let image_room = self.budget.max_images().saturating_sub(chat.images.len());
+        let dropped_images = tokens::limit_images(&mut history, image_room);
+        if dropped_images > 0 {
+            println!("Left {} older image(s) out of the request", dropped_images);
+        }
+        let trimmed = self.budget
+            .fit(model, &system, &mut history, &current)
+            .map_err(|o| GeneratorError::ContextOverflow { needed: o.needed, available: o.available })?;
+
+        if trimmed.dropped > 0 || trimmed.truncated {
+            println!(
+                "Trimmed history for {}: dropped {} turn(s){}, ~{} of {} tokens used",
+                model,
+                trimmed.dropped,
+                if trimmed.truncated { ", truncated 1" } else { "" },
+                trimmed.estimated,
+                self.budget.context_size(model),
+            );
+        }
+
+        let mut messages = vec![system];
+        messages.extend(history);
+        messages.extend(current);
+
+        Ok(ChatRequest {
+            model: chat.model.clone(),
+            messages,
+            max_tokens: self.budget.max_tokens(),
+            temperature: chat.temperature,
+            timeout: Duration::from_secs(360),
+            tools: chat.tools.clone(),
+        })
+    }
+
+    // Returns the first generated image as base64, if the server produced one
+    pub async fn txt2img(&self, prompt: &str, options: &DreamOptions) -> Result<Option<String>, GeneratorError> {
+        let body = json!({
+            "prompt": prompt,
+            "steps": options.steps,
+            "width": options.width,
+            "height": options.height,
+            // -1 asks the server for a random seed
+            "seed": options.seed.unwrap_or(-1),
+        });
+
+        let response = self.client
+            .post(TXT2IMG_URL)
+            .timeout(Duration::from_secs(300))
+            .json(&body)
+            .send()
+            .await?;
+        let json = read_json(response).await?;
+
+        Ok(json["images"][0].as_str().map(|s| s.to_string()))
+    }
+}
+
+// A user turn, in the OpenAI content-array format when it carries images
+pub fn user_message(prompt: &str, images: &[String]) -> serde_json::Value {
+    if images.is_empty() {
+        return json!({
+            "role": "user",
+            "content": prompt
+        });
+    }
+
+    // OpenAI vision format: content is an array of text and image_url objects
+    let mut content_parts = vec![
+        json!({
+            "type": "text",
+            "text": prompt
+        })
+    ];
+
+    for img_base64 in images {
+        content_parts.push(json!({
+            "type": "image_url",
+            "image_url": {
+                "url": format!("data:image/jpeg;base64,{}", img_base64)
+            }
+        }));
+    }
+
+    json!({
+        "role": "user",
+        "content": content_parts
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // Serves a single response on a free local port: `status`, then the body as a chunked
+    // transfer with a pause after each of `chunks`, so the client reads them as separate
+    // pieces rather than one buffer
+    async fn serve(status: &'static str, chunks: Vec<Vec<u8>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 4096];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                match socket.read(&mut buffer).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(read) => request.extend_from_slice(&buffer[..read]),
+                }
+            }
+
+            let head = format!("HTTP/1.1 {}\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n", status);
+            socket.write_all(head.as_bytes()).await.ok();
+            for chunk in chunks {
+                let mut framed = format!("{:x}\r\n", chunk.len()).into_bytes();
+                framed.extend_from_slice(&chunk);
+                framed.extend_from_slice(b"\r\n");
+                socket.write_all(&framed).await.ok();
+                socket.flush().await.ok();
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            socket.write_all(b"0\r\n\r\n").await.ok();
         });
 
-    Ok(completion_text.to_string())
+        format!("http://{}", address)
+    }
+
+    async fn respond(status: &'static str, chunks: &[&[u8]]) -> Response {
+        let base = serve(status, chunks.iter().map(|chunk| chunk.to_vec()).collect()).await;
+        reqwest::get(base).await.unwrap()
+    }
+
+    fn sse(delta: serde_json::Value) -> String {
+        format!("data: {}\n\n", json!({ "choices": [{ "delta": delta }] }))
+    }
+
+    async fn read_stream(chunks: &[&[u8]]) -> (Result<Completion, GeneratorError>, Vec<String>) {
+        let response = respond("200 OK", chunks).await;
+        let mut tokens = Vec::new();
+        let result = read_openai_stream(response, &mut |token: &str| tokens.push(token.to_string())).await;
+        (result, tokens)
+    }
+
+    #[tokio::test]
+    async fn joins_sse_lines_split_across_chunks() {
+        let first = sse(json!({ "content": "Hel" }));
+        let second = sse(json!({ "content": "lo" }));
+        let (a, b) = second.split_at(12);
+
+        let (result, tokens) = read_stream(&[first.as_bytes(), a.as_bytes(), b.as_bytes(), b"data: [DONE]\n\n"]).await;
+        assert_eq!(result.unwrap().content, "Hello");
+        assert_eq!(tokens, ["Hel", "lo"]);
+    }
+
+    #[tokio::test]
+    async fn keeps_multibyte_characters_split_between_chunks() {
+        let line = sse(json!({ "content": "héllo 😀" }));
+        let bytes = line.as_bytes();
+        // Cut inside the two bytes of "é" and again inside the four of "😀"
+        let e = line.find('é').unwrap() + 1;
+        let emoji = line.find('😀').unwrap() + 2;
+
+        let (result, tokens) = read_stream(&[&bytes[..e], &bytes[e..emoji], &bytes[emoji..]]).await;
+        assert_eq!(result.unwrap().content, "héllo 😀");
+        assert_eq!(tokens, ["héllo 😀"]);
+    }
+
+    #[tokio::test]
+    async fn stops_at_done() {
+        let before = sse(json!({ "content": "kept" }));
+        let after = sse(json!({ "content": "dropped" }));
+
+        let (result, tokens) = read_stream(&[before.as_bytes(), b"data: [DONE]\n\n", after.as_bytes()]).await;
+        assert_eq!(result.unwrap().content, "kept");
+        assert_eq!(tokens, ["kept"]);
+    }
+
+    #[tokio::test]
+    async fn skips_lines_without_tokens() {
+        let token = sse(json!({ "content": "hi" }));
+        let stop = format!("data: {}\n\n", json!({ "choices": [{ "delta": {}, "finish_reason": "length" }] }));
+        let chunks: [&[u8]; 5] = [b": keep-alive\n\n", b"event: message\n", b"data: {not json\n\n", token.as_bytes(), stop.as_bytes()];
+
+        let (result, tokens) = read_stream(&chunks).await;
+        let completion = result.unwrap();
+        assert_eq!(completion.content, "hi");
+        assert!(completion.truncated);
+        assert_eq!(tokens, ["hi"]);
+    }
+
+    #[tokio::test]
+    async fn assembles_tool_call_deltas() {
+        let chunks = [
+            sse(json!({ "tool_calls": [{ "index": 0, "id": "call_abc", "function": { "name": "calculator", "arguments": "" } }] })),
+            sse(json!({ "tool_calls": [{ "index": 0, "function": { "arguments": "{\"expression\":" } }] })),
+            sse(json!({ "tool_calls": [{ "index": 1, "function": { "name": "current_datetime", "arguments": "{}" } }] })),
+            sse(json!({ "tool_calls": [{ "index": 0, "function": { "arguments": " \"1 + 1\"}" } }] })),
+            // Arguments for a call that never got a name
+            sse(json!({ "tool_calls": [{ "index": 3, "function": { "arguments": "{}" } }] })),
+        ];
+        let chunks: Vec<&[u8]> = chunks.iter().map(|chunk| chunk.as_bytes()).collect();
+
+        let (result, tokens) = read_stream(&chunks).await;
+        let completion = result.unwrap();
+        assert!(tokens.is_empty());
+
+        let calls: Vec<_> = completion.tool_calls.iter().map(|c| (c.id.as_str(), c.name.as_str(), c.arguments.as_str())).collect();
+        assert_eq!(calls, [
+            ("call_abc", "calculator", "{\"expression\": \"1 + 1\"}"),
+            ("call_1", "current_datetime", "{}"),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn stream_errors() {
+        let token = sse(json!({ "content": "partial" }));
+        let error = format!("data: {}\n\n", json!({ "error": { "message": "overloaded" } }));
+        let (result, _) = read_stream(&[token.as_bytes(), error.as_bytes()]).await;
+        assert!(matches!(result, Err(GeneratorError::Api(message)) if message == "overloaded"));
+
+        let (result, _) = read_stream(&[b"data: [DONE]\n\n"]).await;
+        assert!(matches!(result, Err(GeneratorError::EmptyChoices)));
+    }
+
+    #[tokio::test]
+    async fn reads_whole_lines() {
+        let response = respond("200 OK", &[b"one\r\ntw", b"o\n\nthr", b"ee"]).await;
+        let mut lines = Vec::new();
+        for_each_line(response, |line| {
+            lines.push(line.to_string());
+            true
+        }).await.unwrap();
+        // The last line still counts without a newline
+        assert_eq!(lines, ["one", "two", "", "three"]);
+
+        let response = respond("200 OK", &[b"one\ntwo\nthree\n"]).await;
+        let mut lines = Vec::new();
+        for_each_line(response, |line| {
+            lines.push(line.to_string());
+            line != "two"
+        }).await.unwrap();
+        assert_eq!(lines, ["one", "two"]);
+    }
+
+    #[tokio::test]
+    async fn maps_error_statuses() {
+        assert!(check_status(respond("200 OK", &[b"fine"]).await).await.is_ok());
+
+        let openai = br#"{"error": {"message": "model not loaded", "type": "server_error"}}"#;
+        let result = check_status(respond("500 Internal Server Error", &[openai]).await).await;
+        assert!(matches!(result, Err(GeneratorError::Api(message)) if message == "model not loaded"));
+
+        let ollama = br#"{"error": "model 'nope' not found"}"#;
+        let result = check_status(respond("404 Not Found", &[ollama]).await).await;
+        assert!(matches!(result, Err(GeneratorError::Api(message)) if message == "model 'nope' not found"));
+
+        let result = check_status(respond("503 Service Unavailable", &[b"try later"]).await).await;
+        assert!(matches!(result, Err(GeneratorError::Http { status: 503, body }) if body == "try later"));
+    }
+
+    #[test]
+    fn reads_error_messages() {
+        assert_eq!(error_message(&json!({ "message": "bad request", "code": 400 })), "bad request");
+        assert_eq!(error_message(&json!("bare string")), "bare string");
+        assert_eq!(error_message(&json!({ "code": 400 })), r#"{"code":400}"#);
+    }
+
+    #[tokio::test]
+    async fn maps_reqwest_errors() {
+        // Accepts connections but never answers
+        let silent = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let error = Client::new()
+            .get(format!("http://{}", silent.local_addr().unwrap()))
+            .timeout(Duration::from_millis(100))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(matches!(GeneratorError::from(error), GeneratorError::Timeout));
+
+        // Nothing listening any more
+        let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let error = reqwest::get(format!("http://{}", address)).await.unwrap_err();
+        assert!(matches!(GeneratorError::from(error), GeneratorError::Connection(_)));
+
+        let error = respond("200 OK", &[b"not json"]).await.json::<serde_json::Value>().await.unwrap_err();
+        assert!(matches!(GeneratorError::from(error), GeneratorError::MalformedJson(_)));
+
+        let error = respond("502 Bad Gateway", &[b""]).await.error_for_status().unwrap_err();
+        assert!(matches!(GeneratorError::from(error), GeneratorError::Http { status: 502, .. }));
+    }
+
+    #[test]
+    fn moves_images_out_for_ollama() {
+        let message = user_message("what's this?", &["Zmlyc3Q=".to_string(), "c2Vjb25k".to_string()]);
+        assert_eq!(to_ollama_message(&message), json!({
+            "role": "user",
+            "content": "what's this?",
+            "images": ["Zmlyc3Q=", "c2Vjb25k"],
+        }));
+
+        // Plain text goes through untouched
+        let message = json!({ "role": "system", "content": "You are Egghead." });
+        assert_eq!(to_ollama_message(&message), message);
+    }
+
+    #[test]
+    fn sends_tool_call_arguments_to_ollama_as_objects() {
+        let completion = Completion {
+            content: String::new(),
+            tool_calls: vec![
+                ToolCall { id: "call_0".to_string(), name: "calculator".to_string(), arguments: r#"{"expression": "2 ^ 8"}"#.to_string() },
+                ToolCall { id: "call_1".to_string(), name: "current_datetime".to_string(), arguments: "not json".to_string() },
+            ],
+            truncated: false,
+        };
+
+        assert_eq!(to_ollama_message(&completion.assistant_message()), json!({
+            "role": "assistant",
+            "content": "",
+            "tool_calls": [
+                { "function": { "name": "calculator", "arguments": { "expression": "2 ^ 8" } } },
+                { "function": { "name": "current_datetime", "arguments": {} } },
+            ],
+        }));
+    }
+
+    #[test]
+    fn numbers_ollama_tool_calls() {
+        let message = json!({ "tool_calls": [
+            { "function": { "name": "calculator", "arguments": { "expression": "1 + 1" } } },
+            { "function": { "name": "current_datetime" } },
+            { "function": { "arguments": {} } },
+        ] });
+
+        let calls: Vec<_> = ollama_tool_calls(&message, 2).into_iter().map(|c| (c.id, c.name, c.arguments)).collect();
+        assert_eq!(calls, [
+            ("call_2".to_string(), "calculator".to_string(), r#"{"expression":"1 + 1"}"#.to_string()),
+            ("call_3".to_string(), "current_datetime".to_string(), "{}".to_string()),
+        ]);
+    }
+
+    async fn ollama_stream(chunks: &[&[u8]]) -> (Result<Completion, GeneratorError>, Vec<String>) {
+        let base = serve("200 OK", chunks.iter().map(|chunk| chunk.to_vec()).collect()).await;
+        let backend = OllamaBackend { base_url: base, model: "test".to_string() };
+        let request = ChatRequest {
+            model: None,
+            messages: vec![json!({ "role": "user", "content": "hi" })],
+            temperature: 0.5,
+            max_tokens: 64,
+            timeout: Duration::from_secs(5),
+            tools: Vec::new(),
+        };
+
+        let mut tokens = Vec::new();
+        let result = backend.chat_stream(&Client::new(), &request, &mut |token: &str| tokens.push(token.to_string())).await;
+        (result, tokens)
+    }
+
+    #[tokio::test]
+    async fn reads_ollama_ndjson_until_done() {
+        let chunks: [&[u8]; 4] = [
+            br#"{"message": {"role": "assistant", "content": "Hi"}, "done": false}"#,
+            b"\n{\"message\": {\"role\": \"assistant\", \"content\": \" th",
+            b"ere\"}, \"done\": false}\n{\"message\": {\"role\": \"assistant\", \"content\": \"\"}, \"done\": true, \"done_reason\": \"length\"}\n",
+            br#"{"message": {"role": "assistant", "content": "after done"}, "done": false}"#,
+        ];
+
+        let (result, tokens) = ollama_stream(&chunks).await;
+        let completion = result.unwrap();
+        assert_eq!(completion.content, "Hi there");
+        assert!(completion.truncated);
+        assert_eq!(tokens, ["Hi", " there"]);
+    }
+
+    #[tokio::test]
+    async fn reads_ollama_tool_calls_and_errors() {
+        let chunk = json!({ "message": { "role": "assistant", "content": "", "tool_calls": [
+            { "function": { "name": "calculator", "arguments": { "expression": "6 * 7" } } },
+        ] }, "done": true, "done_reason": "stop" });
+        let line = format!("{}\n", chunk);
+
+        let (result, tokens) = ollama_stream(&[line.as_bytes()]).await;
+        let completion = result.unwrap();
+        assert!(tokens.is_empty());
+        assert!(!completion.truncated);
+        assert_eq!(completion.tool_calls.len(), 1);
+        assert_eq!(completion.tool_calls[0].name, "calculator");
+        assert_eq!(completion.tool_calls[0].arguments, r#"{"expression":"6 * 7"}"#);
+
+        let (result, _) = ollama_stream(&[b"{\"error\": \"out of memory\"}\n"]).await;
+        assert!(matches!(result, Err(GeneratorError::Api(message)) if message == "out of memory"));
+    }
 }
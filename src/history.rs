@@ -0,0 +1,218 @@
+use serenity::model::channel::{Channel, GuildChannel, Message};
+use serenity::model::id::ChannelId;
+use serenity::prelude::*;
+
+use crate::bounded::BoundedMap;
+use crate::{conversation, db};
+use crate::{DatabasePath, MessageCache};
+
+// Messages fetched while walking reply chains, kept so the next turn of a conversation
+// doesn't fetch them all again
+const MAX_CACHED: usize = 1000;
+
+pub type MessageMap = BoundedMap<Message, MAX_CACHED>;
+
+// Builds the earlier turns of the conversation `msg` belongs to, oldest first, with their
+// images. At most `max_messages` are kept here, and images are only downloaded from Discord up
+// to `max_images`; the generator trims further to fit the model's context window.
+pub async fn get_conversation_history(ctx: &Context, msg: &Message, bot_id: u64, max_messages: usize, max_images: usize) -> Vec<serde_json::Value> {
+    let mut image_budget = max_images;
+
+    // Inside egghead's own threads the whole thread is the conversation, replies or not
+    if let Some(thread) = bot_thread(ctx, msg, bot_id).await {
+        return thread_history(ctx, msg, &thread, bot_id, max_messages, &mut image_budget).await;
+    }
+
+    if msg.message_reference.is_none() {
+        println!("Not in a reply thread, no conversation history loaded");
+        return Vec::new();
+    }
+
+    let history = reply_chain(ctx, msg, bot_id, max_messages, &mut image_budget).await;
+    println!("Loaded {} messages from reply thread", history.len());
+    history
+}
+
+// Follows the replies up from `msg` one hop at a time, up to `max_depth` messages. A message
+// in the conversation store brings the turns stored before it along, and the walk carries on
+// from whatever the oldest of those replied to; anything else comes from the cache, the message
+// itself (Discord includes the message it replies to), or a fetch by id.
+async fn reply_chain(ctx: &Context, msg: &Message, bot_id: u64, max_depth: usize, image_budget: &mut usize) -> Vec<serde_json::Value> {
+    let db_path = {
+        let data_read = ctx.data.read().await;
+        data_read.get::<DatabasePath>().expect("Expected DatabasePath in TypeMap.").clone()
+    };
+
+    // Newest first while walking
+    let mut chain: Vec<serde_json::Value> = Vec::new();
+    let mut next = reply_target(msg);
+    let mut referenced = msg.referenced_message.as_deref().cloned();
+
+    while chain.len() < max_depth {
+        let (channel_id, parent_id) = match next {
+            Some(target) => target,
+            None => break,
+        };
+
+        let remaining = max_depth - chain.len();
+        match db::with_connection(db_path.clone(), move |conn| conversation::chain(conn, parent_id, remaining)).await {
+            Ok(turns) if !turns.is_empty() => {
+                chain.extend(turns.iter().rev().flat_map(|turn| turn.messages().into_iter().rev()));
+                // The store only has the turns egghead took part in; the message the oldest
+                // of them replied to may be anyone's
+                let oldest = &turns[0];
+                next = oldest.parent_id.map(|id| (ChannelId(oldest.channel_id), id));
+                referenced = None;
+                continue;
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("Failed to read the conversation store: {:?}", e),
+        }
+
+        let parent = match referenced.take() {
+            Some(parent) if parent.id.0 == parent_id => Some(parent),
+            _ => fetch_message(ctx, channel_id, parent_id).await,
+        };
+        let parent = match parent {
+            Some(parent) => parent,
+            // Deleted, or somewhere we can't read
+            None => break,
+        };
+
+        chain.extend(history_messages(&parent, bot_id, image_budget).await.into_iter().rev());
+        next = reply_target(&parent);
+        referenced = parent.referenced_message.as_deref().cloned();
+    }
+
+    chain.reverse();
+    chain
+}
+
+// The channel and id of the message `message` replies to
+fn reply_target(message: &Message) -> Option<(ChannelId, u64)> {
+    let reference = message.message_reference.as_ref()?;
+    Some((reference.channel_id, reference.message_id?.0))
+}
+
+async fn fetch_message(ctx: &Context, channel_id: ChannelId, message_id: u64) -> Option<Message> {
+    let cache = {
+        let data_read = ctx.data.read().await;
+        data_read.get::<MessageCache>().expect("Expected MessageCache in TypeMap.").clone()
+    };
+
+    if let Some(message) = cache.read().await.get(message_id) {
+        return Some(message.clone());
+    }
+
+    match channel_id.message(&ctx.http, message_id).await {
+        Ok(message) => {
+            cache.write().await.insert(message.id.0, message.clone());
+            Some(message)
+        }
+        Err(e) => {
+            eprintln!("Failed to fetch message {} in the reply chain: {:?}", message_id, e);
+            None
+        }
+    }
+}
+
+// The thread channel `msg` was sent in, if egghead started it or it was started for egghead:
+// on one of its messages, or on a message that mentions it
+async fn bot_thread(ctx: &Context, msg: &Message, bot_id: u64) -> Option<GuildChannel> {
+    let thread = match msg.channel_id.to_channel(&ctx).await {
+        Ok(Channel::Guild(channel)) if channel.thread_metadata.is_some() => channel,
+        _ => return None,
+    };
+
+    if thread.owner_id.map(|owner| owner.0) == Some(bot_id) {
+        return Some(thread);
+    }
+
+    let starter = thread_starter(ctx, &thread).await?;
+    if starter.author.id.0 == bot_id || starter.mentions.iter().any(|user| user.id.0 == bot_id) {
+        return Some(thread);
+    }
+
+    None
+}
+
+// A thread started on a message shares that message's id, and the message lives in the parent
+// channel. Threads started from scratch have none.
+async fn thread_starter(ctx: &Context, thread: &GuildChannel) -> Option<Message> {
+    let parent = thread.parent_id?;
+    parent.message(&ctx.http, thread.id.0).await.ok()
+}
+
+// The most recent `max_messages` of the thread before `msg`, oldest first, led by the message
+// the thread was started on
+async fn thread_history(ctx: &Context, msg: &Message, thread: &GuildChannel, bot_id: u64, max_messages: usize, image_budget: &mut usize) -> Vec<serde_json::Value> {
+    let limit = max_messages.clamp(1, 100) as u64;
+    // Newest first, so the most recent images get the budget
+    let mut messages = match msg.channel_id.messages(&ctx.http, |retriever| retriever.limit(limit).before(msg.id)).await {
+        Ok(messages) => messages,
+        Err(e) => {
+            eprintln!("Failed to fetch thread messages: {:?}", e);
+            Vec::new()
+        }
+    };
+
+    // A thread that's outgrown the window has moved on from its opening message
+    if messages.len() < max_messages {
+        if let Some(starter) = thread_starter(ctx, thread).await {
+            messages.push(starter);
+        }
+    }
+
+    let mut history = Vec::new();
+    for message in &messages {
+        history.extend(history_messages(message, bot_id, image_budget).await.into_iter().rev());
+    }
+    history.reverse();
+    println!("Loaded {} messages from thread channel {}", history.len(), thread.name);
+    history
+}
+
+// `message` as chat turns: egghead's own messages are the assistant's, everyone else's the
+// user's with the mention taken out. Image attachments are downloaded while `image_budget`
+// lasts. Empty for messages with neither text nor images.
+async fn history_messages(message: &Message, bot_id: u64, image_budget: &mut usize) -> Vec<serde_json::Value> {
+    let role = if message.author.id.0 == bot_id {
+        "assistant"
+    } else {
+        "user"
+    };
+
+    let mut content = message.content.clone();
+    if role == "user" {
+        let mention_formats = vec![
+            format!("<@{}>", bot_id),
+            format!("<@!{}>", bot_id),
+        ];
+        for mention in &mention_formats {
+            content = content.replace(mention, "");
+        }
+        content = content.trim().to_string();
+    }
+
+    let mut images = Vec::new();
+    for attachment in message.attachments.iter().filter(|a| crate::is_image(a)) {
+        if *image_budget == 0 {
+            break;
+        }
+        if let Some(image) = crate::download_image(&attachment.url).await {
+            images.push(image);
+            *image_budget -= 1;
+        }
+    }
+
+    let turn = conversation::Turn {
+        message_id: message.id.0,
+        parent_id: message.message_reference.as_ref().and_then(|reference| reference.message_id).map(|id| id.0),
+        channel_id: message.channel_id.0,
+        user_id: message.author.id.0,
+        role,
+        content,
+        images,
+    };
+    turn.messages()
+}
@@ -1,18 +1,44 @@
+mod bounded;
+mod buttons;
+mod circuit;
+mod config;
+mod conversation;
+mod db;
 mod generator;
+mod history;
+mod memory;
+mod news;
+mod overflow;
+mod permissions;
+mod persona;
+mod ratelimit;
+mod reactions;
+mod routing;
+mod scheduler;
+mod slash;
+mod split;
+mod streaming;
+mod summarize;
+mod tokens;
+mod tools;
+// Blog generation and the API server are switched off in main() for now, but the module is
+// kept compiling so it can be re-enabled without bit rot.
+#[allow(dead_code)]
 mod blog;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::sync::atomic::AtomicUsize;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use std::time::Duration;
 
 use serenity::async_trait;
-use serenity::framework::standard::macros::{command, group, hook};
-use serenity::framework::standard::CommandResult;
+use serenity::framework::standard::macros::{check, command, group, hook};
+use serenity::framework::standard::{Args, CommandOptions, CommandResult, DispatchError, Reason};
 use serenity::framework::standard::StandardFramework;
-use serenity::http::Typing;
+use serenity::http::{Http, Typing};
 use serenity::model::channel::Message;
+use serenity::model::application::interaction::Interaction;
 use serenity::model::gateway::Ready;
 use serenity::prelude::*;
 use tokio::sync::RwLock;
@@ -47,10 +73,80 @@ impl TypeMapKey for BlogDatabasePath {
     type Value = Arc<String>;
 }
 
+struct BotConfig;
+
+impl TypeMapKey for BotConfig {
+    type Value = Arc<config::Config>;
+}
+
+struct DatabasePath;
+
+impl TypeMapKey for DatabasePath {
+    type Value = Arc<String>;
+}
+
+// Most recent routing decision per channel, for `e.model`
+struct LastRoute;
+
+impl TypeMapKey for LastRoute {
+    type Value = Arc<RwLock<HashMap<u64, routing::Route>>>;
+}
+
+struct GenerationQueue;
+
+impl TypeMapKey for GenerationQueue {
+    type Value = scheduler::Scheduler;
+}
+
+struct ChatGenerator;
+
+impl TypeMapKey for ChatGenerator {
+    type Value = Arc<generator::Generator>;
+}
+
+// Streamed replies the Regenerate / Continue / Stop buttons act on
+struct Generations;
+
+impl TypeMapKey for Generations {
+    type Value = Arc<RwLock<buttons::GenerationMap>>;
+}
+
+// Messages fetched while walking reply chains (see history.rs)
+struct MessageCache;
+
+impl TypeMapKey for MessageCache {
+    type Value = Arc<RwLock<history::MessageMap>>;
+}
+
+struct RateLimits;
+
+impl TypeMapKey for RateLimits {
+    type Value = Arc<ratelimit::RateLimiter>;
+}
+
+struct BotOwners;
+
+impl TypeMapKey for BotOwners {
+    type Value = Arc<HashSet<u64>>;
+}
+
+struct ChatTools;
+
+impl TypeMapKey for ChatTools {
+    type Value = Arc<tools::ToolRegistry>;
+}
+
 #[group]
-#[commands(help, blog, dream)]
+#[description("USAGE")]
+#[commands(help, ping, ask, read, right, left, green, news, blog, dream, memory, persona, overflow, perms, model, status, stats)]
 struct General;
 
+// Listed under their own heading in `e.help` so people know not to expect much
+#[group]
+#[description("EXPERIMENTAL - HERE BE DRAGONS")]
+#[commands(tldr, react, code)]
+struct Experimental;
+
 #[hook]
 async fn before(ctx: &Context, msg: &Message, command_name: &str) -> bool {
     println!("Running command '{}' invoked by '{}'", command_name, msg.author.tag());
@@ -80,17 +176,65 @@ async fn before(ctx: &Context, msg: &Message, command_name: &str) -> bool {
         *entry += 1;
     }
 
+    // Commands that go to the model draw on the same budgets as mentions
+    if let Some(kind) = ratelimit::Kind::for_command(command_name) {
+        if let Err(wait) = rate_limit(ctx, msg, kind).await {
+            msg.reply(&ctx.http, ratelimit::slow_down(wait)).await.ok();
+            return false;
+        }
+    }
+
     true
 }
 
+async fn rate_limit(ctx: &Context, msg: &Message, kind: ratelimit::Kind) -> Result<(), Duration> {
+    let roles = msg.member.as_ref().map(|member| member.roles.clone()).unwrap_or_default();
+    ratelimit::check(ctx, kind, msg.author.id, msg.channel_id, msg.guild_id, &roles).await
+}
+
+#[hook]
+async fn dispatch_error(ctx: &Context, msg: &Message, error: DispatchError, command_name: &str) {
+    let response = match error {
+        DispatchError::CheckFailed(_, Reason::User(reason)) => reason,
+        DispatchError::OnlyForGuilds => format!("`{}` only works in servers.", command_name),
+        _ => {
+            println!("Unhandled dispatch error in '{}': {:?}", command_name, error);
+            return;
+        }
+    };
+
+    msg.reply(&ctx.http, response).await.ok();
+}
+
+// Commands declare who may run them with `#[checks(Admin)]` or `#[checks(Owner)]`, and are
+// open to everyone without either; everyone else gets the reason. Owners pass every check.
+#[check]
+#[name = "Admin"]
+async fn admin_check(ctx: &Context, msg: &Message, _args: &mut Args, _options: &CommandOptions) -> Result<(), Reason> {
+    require(ctx, msg, permissions::Level::Admin).await
+}
+
+#[check]
+#[name = "Owner"]
+async fn owner_check(ctx: &Context, msg: &Message, _args: &mut Args, _options: &CommandOptions) -> Result<(), Reason> {
+    require(ctx, msg, permissions::Level::Owner).await
+}
+
+async fn require(ctx: &Context, msg: &Message, required: permissions::Level) -> Result<(), Reason> {
+    if permissions::level(ctx, msg).await >= required {
+        Ok(())
+    } else {
+        Err(Reason::User(permissions::refusal(required, "do that")))
+    }
+}
+
 struct Handler;
 
 #[async_trait]
 impl EventHandler for Handler {
     async fn message(&self, ctx: Context, msg: Message) {
         if msg.mentions_me(&ctx.http).await.unwrap_or(false) {
-            let typing: _ = Typing::start(ctx.http.clone(), msg.channel_id.0.clone())
-            .expect("Typing failed");
+            let typing = Typing::start(ctx.http.clone(), msg.channel_id.0).ok();
 
             // Extract prompt by removing bot mentions
             // Discord mentions are in the format <@USER_ID> or <@!USER_ID>
@@ -108,179 +252,483 @@ impl EventHandler for Handler {
 
             println!("{:?}", prompt);
 
-            // Process image attachments
-            let images: Vec<String> = if !msg.attachments.is_empty() {
-                let mut img_vec = Vec::new();
-                for attachment in &msg.attachments {
-                    // Check if it's an image by content type or file extension
-                    let is_image = attachment.content_type.as_ref()
-                        .map(|ct| ct.starts_with("image/"))
-                        .unwrap_or_else(|| {
-                            let ext = attachment.filename.to_lowercase();
-                            ext.ends_with(".png")
-                                || ext.ends_with(".jpg")
-                                || ext.ends_with(".jpeg")
-                                || ext.ends_with(".gif")
-                                || ext.ends_with(".webp")
-                        });
-
-                    if is_image {
-                        println!("Downloading image: {}", attachment.url);
-                        // Download the image and convert to base64
-                        match reqwest::get(&attachment.url).await {
-                            Ok(response) => {
-                                match response.bytes().await {
-                                    Ok(bytes) => {
-                                        use base64::{Engine as _, engine::general_purpose};
-                                        let base64_string = general_purpose::STANDARD.encode(&bytes);
-                                        img_vec.push(base64_string);
-                                        println!("Successfully encoded image to base64");
-                                    }
-                                    Err(e) => eprintln!("Failed to read image bytes: {:?}", e),
-                                }
-                            }
-                            Err(e) => eprintln!("Failed to download image: {:?}", e),
-                        }
-                    }
+            if let Err(wait) = rate_limit(&ctx, &msg, ratelimit::Kind::Chat).await {
+                msg.reply(&ctx.http, ratelimit::slow_down(wait)).await.ok();
+                if let Some(typing) = typing {
+                    typing.stop();
                 }
-                img_vec
-            } else {
-                Vec::new()
-            };
+                return
+            }
+
+            if let Some(fact) = memory::detect(&prompt) {
+                let response = remember(&ctx, &msg, &fact).await;
+                msg.reply(&ctx.http, response).await.ok();
+                if let Some(typing) = typing {
+                    typing.stop();
+                }
+                return
+            }
 
-            let images_opt = if images.is_empty() { None } else { Some(images) };
+            // Process image attachments
+            let mut images = Vec::new();
+            for attachment in msg.attachments.iter().filter(|a| is_image(a)) {
+                if let Some(image) = download_image(&attachment.url).await {
+                    images.push(image);
+                }
+            }
 
             // Fetch conversation history
             let bot_id = ctx.cache.current_user().id.0;
-            let conversation_history = get_conversation_history(&ctx, &msg, bot_id).await;
-            let history_opt = if conversation_history.is_empty() { None } else { Some(conversation_history) };
-
-            let runner = tokio::task::spawn_blocking(move || {
-                println!("Thread Spawned!");
-                // This is running on a thread where blocking is fine.
-                let response = generator::get_chat_response("0.85", "You are Egghead, the world's smartest computer.", &prompt, images_opt, history_opt).unwrap();
-                response
-            });
-
-            let reply = runner.await.unwrap();
+            let context = {
+                let data_read = ctx.data.read().await;
+                data_read.get::<BotConfig>().expect("Expected BotConfig in TypeMap.").context.clone()
+            };
+            let conversation_history = history::get_conversation_history(&ctx, &msg, bot_id, context.max_history_messages, context.max_images).await;
 
-            send_message_in_parts(&ctx.http, &msg, &reply).await.unwrap();
+            answer(&ctx, &msg, prompt, images, conversation_history, None).await;
 
-            typing.stop().unwrap();
+            if let Some(typing) = typing {
+                typing.stop();
+            }
             return
         }
     }
 
-    async fn ready(&self, _: Context, ready: Ready) {
+    async fn ready(&self, ctx: Context, ready: Ready) {
         println!("{} is connected!", ready.user.name);
+
+        slash::register(&ctx).await;
+    }
+
+    async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        match interaction {
+            Interaction::ApplicationCommand(command) => slash::handle(&ctx, &command).await,
+            Interaction::MessageComponent(component) => buttons::handle(&ctx, &component).await,
+            _ => {}
+        }
     }
 }
 
-async fn send_message_in_parts(http: &serenity::http::Http, msg: &Message, text: &str) -> CommandResult {
-    const MAX_LENGTH: usize = 2000;
+// Answers `prompt` with a streamed reply to `msg`. `instructions` are added to the persona's
+// system prompt for commands that want a particular kind of answer.
+async fn answer(ctx: &Context, msg: &Message, prompt: String, images: Vec<String>, history: Vec<serde_json::Value>, instructions: Option<&str>) {
+    let (forced_model, prompt) = routing::take_model_flag(&prompt);
+    let turn = conversation::Turn {
+        message_id: msg.id.0,
+        parent_id: msg.message_reference.as_ref().and_then(|reference| reference.message_id).map(|id| id.0),
+        channel_id: msg.channel_id.0,
+        user_id: msg.author.id.0,
+        role: "user",
+        content: prompt.clone(),
+        images: images.clone(),
+    };
+
+    let request = buttons::Request {
+        prompt,
+        forced_model,
+        images,
+        history,
+        instructions: instructions.map(|i| i.to_string()),
+        temperature: None,
+        turn,
+    };
+    answer_request(ctx, msg, request).await;
+}
+
+// Resolves the persona and model, waits its turn in the queue, then runs the generation (and
+// any tool calls). The reply gets a Stop button while it streams, and Regenerate (plus
+// Continue when it was cut off) once it's done; the buttons rerun `request`.
+async fn answer_request(ctx: &Context, msg: &Message, request: buttons::Request) {
+    let (generator, config, db_path) = {
+        let data_read = ctx.data.read().await;
+        (
+            data_read.get::<ChatGenerator>().expect("Expected ChatGenerator in TypeMap.").clone(),
+            data_read.get::<BotConfig>().expect("Expected BotConfig in TypeMap.").clone(),
+            data_read.get::<DatabasePath>().expect("Expected DatabasePath in TypeMap.").clone(),
+        )
+    };
+
+    let persona = resolve_persona(db_path.clone(), config.clone(), msg.guild_id.map(|g| g.0), msg.channel_id.0).await;
+
+    if request.forced_model.is_some() && permissions::level(ctx, msg).await < routing::FORCE_MODEL_LEVEL {
+        msg.reply(&ctx.http, permissions::refusal(routing::FORCE_MODEL_LEVEL, "pick the model with `--model`")).await.ok();
+        return;
+    }
+    // Images from earlier turns need the vision model as much as new ones
+    let has_images = !request.images.is_empty()
+        || (config.context.max_images > 0 && tokens::count_images(&request.history) > 0);
+    let route = generator.router().route(&request.prompt, has_images, request.forced_model.clone(), persona.model);
+    println!("Routing to model '{}' ({})", route.model, route.reason);
+    remember_route(ctx, msg.channel_id.0, route.clone()).await;
+
+    let system_prompt = match &request.instructions {
+        Some(instructions) => format!("{}\n\n{}", persona.system_prompt, instructions),
+        None => persona.system_prompt,
+    };
+    let system_prompt = with_memories(ctx, system_prompt, &msg.author, msg.guild_id.map(|g| g.0), &request.prompt).await;
+    let temperature = request.temperature.unwrap_or(persona.temperature);
+
+    let chat = generator::ChatPrompt {
+        model: Some(route.model),
+        temperature,
+        system_prompt,
+        prompt: request.prompt.clone(),
+        images: request.images.clone(),
+        history: request.history.clone(),
+        tools: Vec::new(),
+        tool_turns: Vec::new(),
+    };
+
+    let (registry, blog_db_path) = {
+        let data_read = ctx.data.read().await;
+        (
+            data_read.get::<ChatTools>().expect("Expected ChatTools in TypeMap.").clone(),
+            data_read.get::<BlogDatabasePath>().expect("Expected BlogDatabasePath in TypeMap.").clone(),
+        )
+    };
+    let tool_context = tools::ToolContext {
+        ctx: ctx.clone(),
+        guild_id: msg.guild_id.map(|g| g.0),
+        channel_id: msg.channel_id.0,
+        user_id: msg.author.id.0,
+        db_path,
+        blog_db_path,
+    };
+    let max_tool_rounds = if config.tools.enabled { config.tools.max_iterations } else { 0 };
+
+    let queue = {
+        let data_read = ctx.data.read().await;
+        data_read.get::<GenerationQueue>().expect("Expected GenerationQueue in TypeMap.").clone()
+    };
+
+    let mut ticket = match queue.enqueue(msg.author.id.0) {
+        Ok(ticket) => ticket,
+        Err(full) => {
+            let response = format!("You already have {} requests waiting. Let those finish first!", full.queued);
+            msg.reply(&ctx.http, response).await.ok();
+            return;
+        }
+    };
+
+    let placeholder = match ticket.position() {
+        Some(position) => queue_status(position),
+        None => streaming::PLACEHOLDER.to_string(),
+    };
+
+    let overflow = overflow_policy(ctx, msg.guild_id.map(|g| g.0)).await;
+    let mut reply = match streaming::StreamingReply::start(&ctx.http, msg, &placeholder, overflow).await {
+        Ok(reply) => reply,
+        Err(why) => {
+            println!("Error sending reply: {:?}", why);
+            return;
+        }
+    };
+
+    // Hold the slot until the generation is done
+    let _permit = loop {
+        if let Some(permit) = ticket.wait_timeout(QUEUE_POLL_INTERVAL).await {
+            break permit;
+        }
+        if let Some(position) = ticket.position() {
+            reply.set_status(&ctx.http, &queue_status(position)).await.ok();
+        }
+    };
+    reply.set_status(&ctx.http, streaming::PLACEHOLDER).await.ok();
+
+    let generations = {
+        let data_read = ctx.data.read().await;
+        data_read.get::<Generations>().expect("Expected Generations in TypeMap.").clone()
+    };
+    let cancel = Arc::new(tokio::sync::Notify::new());
+    let turn = request.turn.clone();
+    generations.write().await.insert(reply.key(), buttons::Generation {
+        requester: msg.author.id.0,
+        message: msg.clone(),
+        request,
+        temperature,
+        cancel: Some(cancel.clone()),
+        text: String::new(),
+    });
+    reply.set_buttons(&ctx.http, &[buttons::Action::Stop]).await.ok();
 
-    let chunks: Vec<_> = text.as_bytes().chunks(MAX_LENGTH).collect();
+    // The generation and the Discord edits run side by side on this task: tokens are
+    // handed over a channel so the reply can be edited while the model is still talking.
+    let (token_tx, mut token_rx) = tokio::sync::mpsc::unbounded_channel::<String>();
 
-    for (i, chunk) in chunks.iter().enumerate() {
-        let content = String::from_utf8_lossy(chunk);
+    // Stopping drops the generation future, and the HTTP request with it
+    let generation = async move {
+        let mut on_token = |token: &str| {
+            token_tx.send(token.to_string()).ok();
+        };
+        tokio::select! {
+            result = generate_with_tools(&generator, &registry, &tool_context, chat, max_tool_rounds, &mut on_token) => Some(result),
+            _ = cancel.notified() => None,
+        }
+    };
 
-        if i == 0 {
-            // First message should be a reply to create the thread
-            if let Err(why) = msg.reply(http, &content).await {
-                println!("Error sending reply: {:?}", why);
+    let relay = async {
+        while let Some(token) = token_rx.recv().await {
+            if let Err(why) = reply.push(&ctx.http, &token).await {
+                println!("Error updating reply: {:?}", why);
             }
-        } else {
-            // Subsequent messages just go to the channel
-            if let Err(why) = msg.channel_id.say(http, &content).await {
-                println!("Error sending message: {:?}", why);
+        }
+    };
+
+    let (result, _) = tokio::join!(generation, relay);
+
+    let mut text = String::new();
+    let mut truncated = false;
+    let notice = match result {
+        Some(Ok(completion)) => {
+            text = completion.content;
+            truncated = completion.truncated;
+            None
+        }
+        Some(Err(e)) => {
+            eprintln!("Generation failed: {}", e);
+            Some(friendly_error(&e))
+        }
+        None => {
+            println!("Generation stopped by '{}'", msg.author.tag());
+            Some("Stopped.".to_string())
+        }
+    };
+
+    if let Some(notice) = notice {
+        // Keep whatever already streamed in and tack the explanation on the end
+        let notice = if reply.is_empty() { notice } else { format!("\n\n*({})*", notice) };
+        if let Err(why) = reply.push(&ctx.http, &notice).await {
+            println!("Error updating reply: {:?}", why);
+        }
+    }
+
+    if let Err(why) = reply.finish(&ctx.http).await {
+        println!("Error finishing reply: {:?}", why);
+    }
+
+    let mut actions = vec![buttons::Action::Regenerate, buttons::Action::Hotter];
+    if truncated {
+        actions.push(buttons::Action::Continue);
+    }
+    if let Err(why) = reply.set_buttons(&ctx.http, &actions).await {
+        println!("Error updating reply buttons: {:?}", why);
+    }
+
+    // The question goes in with its answer. A long answer spans several messages; each of them
+    // stands for the whole answer, so a reply to any part carries on from it.
+    if !text.trim().is_empty() {
+        let answers = reply.sent().iter().map(|&(channel_id, message_id)| conversation::Turn {
+            message_id,
+            parent_id: Some(msg.id.0),
+            channel_id,
+            user_id: msg.author.id.0,
+            role: "assistant",
+            content: text.clone(),
+            images: Vec::new(),
+        });
+        record_turns(ctx, std::iter::once(turn).chain(answers).collect()).await;
+    }
+
+    let mut generations = generations.write().await;
+    if let Some(generation) = generations.get_mut(reply.key()) {
+        generation.cancel = None;
+        generation.text = text;
+    }
+}
+
+async fn record_turns(ctx: &Context, turns: Vec<conversation::Turn>) {
+    let db_path = {
+        let data_read = ctx.data.read().await;
+        data_read.get::<DatabasePath>().expect("Expected DatabasePath in TypeMap.").clone()
+    };
+
+    let result = db::with_connection(db_path, move |conn| {
+        for turn in &turns {
+            conversation::record(conn, turn)?;
+        }
+        Ok(())
+    }).await;
+
+    if let Err(e) = result {
+        eprintln!("Failed to record conversation turns: {:?}", e);
+    }
+}
+
+// Keeps going while the model asks for tools, feeding the results back each round. The last
+// round offers no tools, so the model has to answer with what it has.
+async fn generate_with_tools(
+    generator: &generator::Generator,
+    registry: &tools::ToolRegistry,
+    tool_context: &tools::ToolContext,
+    mut chat: generator::ChatPrompt,
+    max_rounds: usize,
+    on_token: generator::TokenSink<'_>,
+) -> Result<generator::Completion, generator::GeneratorError> {
+    let mut round = 0;
+    loop {
+        let final_round = round >= max_rounds;
+        chat.tools = if final_round { Vec::new() } else { registry.definitions() };
+
+        let completion = generator.stream_chat_response(&chat, &mut *on_token).await?;
+
+        if final_round || completion.tool_calls.is_empty() {
+            return Ok(completion);
+        }
+
+        // Anything said before the calls stays in the reply; keep it apart from the answer
+        if !completion.content.trim().is_empty() {
+            on_token("\n\n");
+        }
+
+        chat.tool_turns.push(completion.assistant_message());
+        for call in &completion.tool_calls {
+            let result = registry.call(tool_context, call).await;
+            chat.tool_turns.push(call.result_message(&result));
+        }
+        round += 1;
+    }
+}
+
+// Check if it's an image by content type or file extension
+fn is_image(attachment: &serenity::model::channel::Attachment) -> bool {
+    attachment.content_type.as_ref()
+        .map(|ct| ct.starts_with("image/"))
+        .unwrap_or_else(|| {
+            let ext = attachment.filename.to_lowercase();
+            ext.ends_with(".png")
+                || ext.ends_with(".jpg")
+                || ext.ends_with(".jpeg")
+                || ext.ends_with(".gif")
+                || ext.ends_with(".webp")
+        })
+}
+
+// Download the image and convert to base64
+async fn download_image(url: &str) -> Option<String> {
+    println!("Downloading image: {}", url);
+    match reqwest::get(url).await {
+        Ok(response) => {
+            match response.bytes().await {
+                Ok(bytes) => {
+                    use base64::{Engine as _, engine::general_purpose};
+                    println!("Successfully encoded image to base64");
+                    Some(general_purpose::STANDARD.encode(&bytes))
+                }
+                Err(e) => {
+                    eprintln!("Failed to read image bytes: {:?}", e);
+                    None
+                }
             }
         }
+        Err(e) => {
+            eprintln!("Failed to download image: {:?}", e);
+            None
+        }
     }
-    Ok(())
 }
 
-async fn get_conversation_history(ctx: &Context, msg: &Message, bot_id: u64) -> Vec<serde_json::Value> {
-    use serde_json::json;
+async fn resolve_persona(db_path: Arc<String>, config: Arc<config::Config>, guild_id: Option<u64>, channel_id: u64) -> persona::Persona {
+    let defaults = config.persona.clone();
+    let result = db::with_connection(db_path, move |conn| {
+        persona::resolve(conn, guild_id, channel_id, &defaults)
+    }).await;
 
-    let mut history = Vec::new();
+    result.unwrap_or_else(|e| {
+        eprintln!("Failed to resolve persona, using defaults: {:?}", e);
+        persona::Persona::from_defaults(&config.persona)
+    })
+}
 
-    // Check if this message is a reply to another message (part of a thread)
-    let thread_root = if let Some(ref referenced_msg) = msg.referenced_message {
-        // We're in a reply thread, use the referenced message as the root
-        Some(referenced_msg.id)
-    } else {
-        // Not a reply, just fetch recent channel messages
-        None
+// What to do with answers too long for one message here: the guild's choice, else the config's
+async fn overflow_policy(ctx: &Context, guild_id: Option<u64>) -> overflow::OverflowPolicy {
+    let (config, db_path) = {
+        let data_read = ctx.data.read().await;
+        (
+            data_read.get::<BotConfig>().expect("Expected BotConfig in TypeMap.").clone(),
+            data_read.get::<DatabasePath>().expect("Expected DatabasePath in TypeMap.").clone(),
+        )
     };
 
-    // Only load context if we're in a thread (reply chain)
-    if let Some(root_id) = thread_root {
-        println!("Detected reply thread, fetching thread messages starting from {}", root_id);
+    let guild_id = match guild_id {
+        Some(guild_id) => guild_id,
+        None => return config.overflow.default_policy,
+    };
 
-        // Fetch messages and filter for ones that are part of this thread
-        match msg.channel_id.messages(&ctx.http, |retriever| retriever.limit(100).before(msg.id)).await {
-            Ok(messages) => {
-                let mut thread_messages = Vec::new();
+    match db::with_connection(db_path, move |conn| overflow::get_policy(conn, guild_id)).await {
+        Ok(policy) => policy.unwrap_or(config.overflow.default_policy),
+        Err(e) => {
+            eprintln!("Failed to look up overflow policy, using the default: {:?}", e);
+            config.overflow.default_policy
+        }
+    }
+}
 
-                // Find all messages that are part of this reply chain
-                for message in messages.iter().rev() {
-                    // Include the root message
-                    if message.id == root_id {
-                        thread_messages.push(message.clone());
-                    }
-                    // Include messages that reference the root or are part of the chain
-                    else if let Some(ref_msg) = &message.referenced_message {
-                        if ref_msg.id == root_id || thread_messages.iter().any(|m: &Message| m.id == ref_msg.id) {
-                            thread_messages.push(message.clone());
-                        }
-                    }
-                }
+// `system_prompt` plus whatever egghead remembers about `user` here that bears on `prompt`
+async fn with_memories(ctx: &Context, system_prompt: String, user: &serenity::model::user::User, guild_id: Option<u64>, prompt: &str) -> String {
+    let db_path = {
+        let data_read = ctx.data.read().await;
+        data_read.get::<DatabasePath>().expect("Expected DatabasePath in TypeMap.").clone()
+    };
 
-                // Limit to last 10 messages in the thread
-                let start_idx = if thread_messages.len() > 10 { thread_messages.len() - 10 } else { 0 };
+    let (user_id, guild_id, prompt) = (user.id.0, guild_id.unwrap_or(0), prompt.to_string());
+    match db::with_connection(db_path, move |conn| memory::relevant(conn, user_id, guild_id, &prompt)).await {
+        Ok(memories) if !memories.is_empty() => {
+            format!("{}\n\n{}", system_prompt, memory::prompt_section(&user.name, &memories))
+        }
+        Ok(_) => system_prompt,
+        Err(e) => {
+            eprintln!("Failed to look up memories: {:?}", e);
+            system_prompt
+        }
+    }
+}
 
-                for message in &thread_messages[start_idx..] {
-                    let role = if message.author.id.0 == bot_id {
-                        "assistant"
-                    } else {
-                        "user"
-                    };
-
-                    let mut content = message.content.clone();
-                    if role == "user" {
-                        let mention_formats = vec![
-                            format!("<@{}>", bot_id),
-                            format!("<@!{}>", bot_id),
-                        ];
-                        for mention in &mention_formats {
-                            content = content.replace(mention, "");
-                        }
-                        content = content.trim().to_string();
-                    }
+// How often a queued request re-checks its place in line
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_secs(3);
 
-                    if !content.is_empty() {
-                        history.push(json!({
-                            "role": role,
-                            "content": content
-                        }));
-                    }
-                }
+fn queue_status(position: usize) -> String {
+    format!("*You're #{} in line...*", position)
+}
 
-                println!("Loaded {} messages from reply thread", history.len());
-            }
-            Err(e) => {
-                eprintln!("Failed to fetch thread messages: {:?}", e);
-            }
+async fn remember_route(ctx: &Context, channel_id: u64, route: routing::Route) {
+    let routes_lock = {
+        let data_read = ctx.data.read().await;
+        data_read.get::<LastRoute>().expect("Expected LastRoute in TypeMap.").clone()
+    };
+
+    routes_lock.write().await.insert(channel_id, route);
+}
+
+// What users see when a generation fails. Each variant gets its own wording so a report in
+// chat tells us which kind of failure it was without digging through logs.
+fn friendly_error(error: &generator::GeneratorError) -> String {
+    use generator::GeneratorError;
+
+    match error {
+        GeneratorError::Timeout => "I thought about that for too long and lost my train of thought. Try again?".to_string(),
+        GeneratorError::Connection(_) => "I can't reach my brain right now. It might be restarting, try again in a minute.".to_string(),
+        GeneratorError::Http { .. } => "My brain returned an error. Try again in a bit.".to_string(),
+        GeneratorError::MalformedJson(_) => "My brain said something I couldn't understand. Try again?".to_string(),
+        GeneratorError::EmptyChoices => "I drew a complete blank on that one. Try rephrasing?".to_string(),
+        GeneratorError::Api(_) => "My brain refused that request. Try something different?".to_string(),
+        GeneratorError::ContextOverflow { .. } => "That's too much for me to read in one go. Try something shorter?".to_string(),
+        GeneratorError::CircuitOpen { retry_in } => {
+            format!("The brain is rebooting. Give it about {}s and try again.", retry_in.as_secs().max(1))
         }
-    } else {
-        // Not in a thread - don't load any context
-        println!("Not in a reply thread, no conversation history loaded");
     }
+}
+
+async fn send_message_in_parts(ctx: &Context, msg: &Message, text: &str) -> CommandResult {
+    let policy = overflow_policy(ctx, msg.guild_id.map(|g| g.0)).await;
 
-    history
+    if let Err(why) = overflow::send(&ctx.http, msg, text, policy).await {
+        println!("Error sending reply: {:?}", why);
+    }
+    Ok(())
 }
 
-async fn blog_post_generator_task(db_path: String, interval_minutes: u64) {
+#[allow(dead_code)]
+async fn blog_post_generator_task(generator: Arc<generator::Generator>, db_path: String, interval_minutes: u64) {
     let mut interval = tokio::time::interval(Duration::from_secs(interval_minutes * 60));
 
     loop {
@@ -288,44 +736,39 @@ async fn blog_post_generator_task(db_path: String, interval_minutes: u64) {
 
         println!("Generating new blog post...");
 
-        // Run the blog post generation in a blocking task
-        let db_path_clone = db_path.clone();
-        let result = tokio::task::spawn_blocking(move || {
-            match blog::generate_blog_post() {
-                Ok(post) => {
-                    // Open connection for this operation only
-                    match rusqlite::Connection::open(&db_path_clone) {
-                        Ok(conn) => {
-                            match blog::save_blog_post(&conn, &post) {
-                                Ok(id) => {
-                                    println!("Successfully saved blog post with ID: {}", id);
-                                    println!("Location: {}", post.location);
-                                    println!("Activity: {}", post.activity);
-                                    Ok(())
-                                }
-                                Err(e) => {
-                                    eprintln!("Failed to save blog post: {:?}", e);
-                                    Err(format!("Save error: {:?}", e))
-                                }
+        let result = match blog::generate_blog_post(&generator).await {
+            Ok(post) => {
+                // Open connection for this operation only
+                match rusqlite::Connection::open(&db_path) {
+                    Ok(conn) => {
+                        match blog::save_blog_post(&conn, &post) {
+                            Ok(id) => {
+                                println!("Successfully saved blog post with ID: {}", id);
+                                println!("Location: {}", post.location);
+                                println!("Activity: {}", post.activity);
+                                Ok(())
+                            }
+                            Err(e) => {
+                                eprintln!("Failed to save blog post: {:?}", e);
+                                Err(format!("Save error: {:?}", e))
                             }
-                        }
-                        Err(e) => {
-                            eprintln!("Failed to open database: {:?}", e);
-                            Err(format!("DB open error: {:?}", e))
                         }
                     }
-                }
-                Err(e) => {
-                    eprintln!("Failed to generate blog post: {:?}", e);
-                    Err(format!("Generation error: {:?}", e))
+                    Err(e) => {
+                        eprintln!("Failed to open database: {:?}", e);
+                        Err(format!("DB open error: {:?}", e))
+                    }
                 }
             }
-        }).await;
+            Err(e) => {
+                eprintln!("Failed to generate blog post: {:?}", e);
+                Err(format!("Generation error: {:?}", e))
+            }
+        };
 
         match result {
-            Ok(Ok(_)) => println!("Blog post generation completed successfully"),
-            Ok(Err(e)) => eprintln!("Blog post generation error: {}", e),
-            Err(e) => eprintln!("Task join error: {:?}", e),
+            Ok(_) => println!("Blog post generation completed successfully"),
+            Err(e) => eprintln!("Blog post generation error: {}", e),
         }
     }
 }
@@ -334,10 +777,24 @@ async fn blog_post_generator_task(db_path: String, interval_minutes: u64) {
 async fn main() {
     let token = env::var("DISCORD_TOKEN").expect("Expected a token in the environment");
 
+    let config = Arc::new(config::load());
+    let generator = Arc::new(generator::Generator::from_config(&config));
+
+    let bot_db_path = db::default_path();
+    match db::init_database(&bot_db_path) {
+        Ok(_) => println!("Bot database initialized at: {}", bot_db_path),
+        Err(e) => {
+            eprintln!("Failed to initialize bot database: {:?}", e);
+            panic!("Cannot start without bot database");
+        }
+    };
+
     let framework = StandardFramework::new()
         .configure(|c| c.with_whitespace(true).prefix("e."))
         .before(before)
-        .group(&GENERAL_GROUP);
+        .on_dispatch_error(dispatch_error)
+        .group(&GENERAL_GROUP)
+        .group(&EXPERIMENTAL_GROUP);
 
     let intents = GatewayIntents::GUILD_MESSAGES
         | GatewayIntents::DIRECT_MESSAGES
@@ -371,14 +828,7 @@ async fn main() {
 
     /*
     // Initialize the blog database
-    // Use ~/.config/egghead/blog.sqlite as the default path
-    let db_path = env::var("BLOG_DB_PATH").unwrap_or_else(|_| {
-        let home = env::var("HOME").unwrap_or_else(|_| ".".to_string());
-        let config_dir = format!("{}/.config/egghead", home);
-        // Create the directory if it doesn't exist
-        std::fs::create_dir_all(&config_dir).ok();
-        format!("{}/blog.sqlite", config_dir)
-    });
+    let db_path = blog::default_db_path();
 
     // Initialize the database (create table if needed)
     match blog::init_database(&db_path) {
@@ -391,8 +841,6 @@ async fn main() {
         }
     };
 
-    let db_path_arc = Arc::new(db_path.clone());
-
     // Get the blog post generation interval (default: 20 minutes = 3 times per hour)
     let blog_interval = env::var("BLOG_INTERVAL_MINUTES")
         .unwrap_or_else(|_| "20".to_string())
@@ -407,8 +855,9 @@ async fn main() {
 
     // Spawn the blog post generator task
     let db_path_generator = db_path.clone();
+    let blog_generator = generator.clone();
     tokio::spawn(async move {
-        blog_post_generator_task(db_path_generator, blog_interval).await;
+        blog_post_generator_task(blog_generator, db_path_generator, blog_interval).await;
     });
 
     // Spawn the HTTP API server
@@ -429,8 +878,19 @@ async fn main() {
 
         data.insert::<MessageCount>(Arc::new(AtomicUsize::new(0)));
 
-        // Blog database path disabled along with blog functionality
-        // data.insert::<BlogDatabasePath>(db_path_arc);
+        data.insert::<BotConfig>(config.clone());
+        data.insert::<ChatGenerator>(generator.clone());
+        data.insert::<ChatTools>(Arc::new(tools::ToolRegistry::with_defaults()));
+        data.insert::<DatabasePath>(Arc::new(bot_db_path));
+        data.insert::<LastRoute>(Arc::new(RwLock::new(HashMap::default())));
+        data.insert::<GenerationQueue>(scheduler::Scheduler::new(&config.queue));
+        data.insert::<Generations>(Arc::new(RwLock::new(buttons::GenerationMap::default())));
+        data.insert::<MessageCache>(Arc::new(RwLock::new(history::MessageMap::default())));
+        data.insert::<RateLimits>(Arc::new(ratelimit::RateLimiter::new(&config.rate_limits)));
+        data.insert::<BotOwners>(Arc::new(bot_owners(&client.cache_and_http.http, &config).await));
+
+        // The blog itself is disabled, but the blog_lookup tool still reads whatever posts exist
+        data.insert::<BlogDatabasePath>(Arc::new(blog::default_db_path()));
     }
 
     if let Err(why) = client.start().await {
@@ -438,100 +898,569 @@ async fn main() {
     }
 }
 
+// The application's owner, or every member of the team that owns it, plus `permissions.owners`
+async fn bot_owners(http: &Http, config: &config::Config) -> HashSet<u64> {
+    let mut owners: HashSet<u64> = config.permissions.owners.iter().copied().collect();
+
+    match http.get_current_application_info().await {
+        Ok(info) => match info.team {
+            Some(team) => owners.extend(team.members.iter().map(|member| member.user.id.0)),
+            None => {
+                owners.insert(info.owner.id.0);
+            }
+        },
+        Err(e) => eprintln!("Failed to fetch application info, only configured owners count: {:?}", e),
+    }
+
+    owners
+}
+
+// Built from the registered commands' own metadata, so it lists exactly what exists
+fn help_text() -> String {
+    let mut text = "I'm egghead, the world's smartest computer. My vast processing resources facilitate understanding beyond human capacity.\n".to_string();
+
+    for group in [&GENERAL_GROUP, &EXPERIMENTAL_GROUP] {
+        text.push_str(&format!("\n*{}*\n", group.options.description.unwrap_or(group.name)));
+
+        for command in group.options.commands {
+            let options = command.options;
+            let usage = options.usage.map(|u| format!(" {}", u)).unwrap_or_default();
+            text.push_str(&format!("`e.{}{}` - {}\n", options.names[0], usage, options.desc.unwrap_or("")));
+        }
+    }
+
+    text.push_str("\n`/ask`, `/dream`, `/blog` and `/help` also work as slash commands, with extra options.\n");
+    text.push_str("\nReport serious issues to `toaster repairguy#1101`.");
+    text
+}
+
 #[command]
+#[description("Displays this help message")]
 async fn help(ctx: &Context, msg: &Message) -> CommandResult {
-    let message = "I'm egghead, the workd's smartest computer. My vast processing resources facilitate understanding beyond human capacity.\n
-    \n
-    *USAGE*
-    `e.help` - Displays this help message.
-    `ping` - Pongs back
-    `ask <prompt>` - Responds to prompt
-    `right` - FOX articles, autocompleted
-    `green`
-    `left` - PBS articles, autocompleted
-    `react <temp>` - Reacts to the last-sent message with set temp
-    `read <lines>` - Reads the number of lines and responds
-    `blog` - Shows a random blog post from Egghead's life
-    --- HELL FEATURE LINE ---
-    --EXPERIMENTAL FEATURES--
-    ----------BELOW----------
-    ---- HERE BE DRAGONS ----
-    `tldr` - Summarizes stuff
-    `code` - Codes
-    `help`
-    \n
-    Report serious issues to `toaster repairguy#1101`.";
-
-    msg.reply(
-        ctx.clone(),
-        &message
-    ).await.unwrap();
+    send_message_in_parts(ctx, msg, &help_text()).await?;
 
     Ok(())
 }
 
 #[command]
-async fn blog(ctx: &Context, msg: &Message) -> CommandResult {
-    let response = "Blog functionality is currently disabled. The blog post generator and API server are not running.";
-
-    send_message_in_parts(&ctx.http, msg, response).await?;
+#[description("Pongs back")]
+async fn ping(ctx: &Context, msg: &Message) -> CommandResult {
+    msg.reply(&ctx.http, "Pong!").await?;
 
     Ok(())
 }
 
 #[command]
-async fn dream(ctx: &Context, msg: &Message) -> CommandResult {
-    let prompt: String = msg.content.split_whitespace().skip(1).collect::<Vec<_>>().join(" ");
-
-    if prompt.is_empty() {
-        msg.reply(&ctx.http, "Usage: `e.dream <prompt>`").await?;
+#[description("Responds to prompt, like mentioning me but without the reply chain")]
+#[usage("<prompt>")]
+async fn ask(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let prompt = args.rest().trim().to_string();
+    if prompt.is_empty() && msg.attachments.is_empty() {
+        msg.reply(&ctx.http, "Usage: `e.ask <prompt>`").await?;
         return Ok(());
     }
 
-    let _typing = Typing::start(ctx.http.clone(), msg.channel_id.0).expect("Typing failed");
+    let typing = Typing::start(ctx.http.clone(), msg.channel_id.0).ok();
 
-    let prompt_owned = prompt.clone();
-    let result = tokio::task::spawn_blocking(move || {
-        let client = reqwest::blocking::Client::builder()
-            .timeout(std::time::Duration::from_secs(300))
-            .build()
-            .ok()?;
+    let mut images = Vec::new();
+    for attachment in msg.attachments.iter().filter(|a| is_image(a)) {
+        if let Some(image) = download_image(&attachment.url).await {
+            images.push(image);
+        }
+    }
 
-        let body = serde_json::json!({
-            "prompt": prompt_owned,
-            "steps": 25,
-            "width": 512,
-            "height": 512,
-        });
+    answer(ctx, msg, prompt, images, Vec::new(), None).await;
 
-        let resp = client
-            .post("http://localhost:11434/sdapi/v1/txt2img")
-            .json(&body)
-            .send()
-            .ok()?;
+    if let Some(typing) = typing {
+        typing.stop();
+    }
+    Ok(())
+}
 
-        let json: serde_json::Value = resp.json().ok()?;
-        json["images"][0].as_str().map(|s| s.to_string())
-    }).await.unwrap();
+#[command]
+#[description("Reads the number of lines and responds")]
+#[usage("<lines>")]
+async fn read(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let lines = args.single::<u64>().unwrap_or(10).clamp(1, 50);
 
-    match result {
-        Some(b64_string) => {
-            use base64::{Engine as _, engine::general_purpose};
-            match general_purpose::STANDARD.decode(&b64_string) {
-                Ok(image_bytes) => {
-                    let temp_path = format!("/tmp/egghead_dream_{}.png", msg.id.0);
-                    if let Err(e) = std::fs::write(&temp_path, &image_bytes) {
-                        eprintln!("Failed to write temp image: {:?}", e);
-                        msg.reply(&ctx.http, "Failed to save generated image.").await.ok();
-                        return Ok(());
-                    }
+    let typing = Typing::start(ctx.http.clone(), msg.channel_id.0).ok();
 
-                    if let Err(e) = msg.channel_id.send_message(&ctx.http, |m| {
-                        m.content(format!("Dream: {}", prompt))
-                            .add_file(std::path::Path::new(&temp_path))
-                    }).await {
-                        eprintln!("Failed to send image: {:?}", e);
-                    }
+    let transcript = channel_transcript(ctx, msg, lines).await?;
+    if transcript.is_empty() {
+        msg.reply(&ctx.http, "There's nothing here to read.").await?;
+        return Ok(());
+    }
+
+    let prompt = format!("Here are the last messages in this channel:\n\n{}\n\nJoin in with a reply.", transcript);
+    answer(ctx, msg, prompt, Vec::new(), Vec::new(), Some("You're reading along in a group chat. Reply as a participant would; don't summarise.")).await;
+
+    if let Some(typing) = typing {
+        typing.stop();
+    }
+    Ok(())
+}
+
+#[command]
+#[description("Right-leaning articles (FOX by default), autocompleted")]
+async fn right(ctx: &Context, msg: &Message) -> CommandResult {
+    autocomplete_news(ctx, msg, "right").await
+}
+
+#[command]
+#[description("Left-leaning articles (PBS by default), autocompleted")]
+async fn left(ctx: &Context, msg: &Message) -> CommandResult {
+    autocomplete_news(ctx, msg, "left").await
+}
+
+#[command]
+#[description("Environmental articles (the Guardian by default), autocompleted")]
+async fn green(ctx: &Context, msg: &Message) -> CommandResult {
+    autocomplete_news(ctx, msg, "green").await
+}
+
+#[command]
+#[description("Lists the news feeds, or autocompletes an article from one")]
+#[usage("[feed]")]
+async fn news(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let feed = args.rest().trim().to_lowercase();
+    if !feed.is_empty() {
+        // Only an article goes to the model, so only that is rate limited (see `before`)
+        if let Err(wait) = rate_limit(ctx, msg, ratelimit::Kind::Chat).await {
+            msg.reply(&ctx.http, ratelimit::slow_down(wait)).await?;
+            return Ok(());
+        }
+        return autocomplete_news(ctx, msg, &feed).await;
+    }
+
+    let config = {
+        let data_read = ctx.data.read().await;
+        data_read.get::<BotConfig>().expect("Expected BotConfig in TypeMap.").clone()
+    };
+
+    let mut names: Vec<_> = config.news.feeds.iter().collect();
+    names.sort_by_key(|(name, _)| name.as_str());
+    let response = if names.is_empty() {
+        "No news feeds are configured.".to_string()
+    } else {
+        names
+            .iter()
+            .map(|(name, feed)| format!("`e.news {}` - {}", name, feed.name))
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+
+    send_message_in_parts(ctx, msg, &response).await?;
+
+    Ok(())
+}
+
+// Picks a headline from the named feed (see `config.news`) and has the model write the rest
+async fn autocomplete_news(ctx: &Context, msg: &Message, feed_name: &str) -> CommandResult {
+    let (generator, config) = {
+        let data_read = ctx.data.read().await;
+        (
+            data_read.get::<ChatGenerator>().expect("Expected ChatGenerator in TypeMap.").clone(),
+            data_read.get::<BotConfig>().expect("Expected BotConfig in TypeMap.").clone(),
+        )
+    };
+
+    let feed = match config.news.feeds.get(feed_name) {
+        Some(feed) => feed,
+        None => {
+            msg.reply(&ctx.http, format!("There's no `{}` news feed. `e.news` lists them.", feed_name)).await?;
+            return Ok(());
+        }
+    };
+
+    let typing = Typing::start(ctx.http.clone(), msg.channel_id.0).ok();
+
+    let headlines = match news::fetch_feed(generator.client(), feed, config.news.headlines_per_feed).await {
+        Ok(headlines) if !headlines.is_empty() => headlines,
+        Ok(_) => {
+            msg.reply(&ctx.http, format!("{} has no headlines right now.", feed.name)).await?;
+            return Ok(());
+        }
+        Err(e) => {
+            eprintln!("Failed to fetch {} headlines: {:?}", feed.name, e);
+            msg.reply(&ctx.http, format!("I couldn't reach {}. Try again later.", feed.name)).await?;
+            return Ok(());
+        }
+    };
+
+    // Any headline will do; the clock is random enough
+    let pick = chrono::Utc::now().timestamp_subsec_nanos() as usize % headlines.len();
+    let headline = &headlines[pick];
+
+    let _permit = match wait_for_turn(ctx, msg).await {
+        Some(permit) => permit,
+        None => return Ok(()),
+    };
+
+    let prompt = news::article_prompt(&feed.name, headline);
+    let response = match generator.complete(&prompt, 0.9, 512, Duration::from_secs(180)).await {
+        Ok(article) => {
+            let source = if headline.link.is_empty() { feed.name.clone() } else { format!("<{}>", headline.link) };
+            format!("**{}**\n\n{}\n\nSource: {}", headline.title, article.trim(), source)
+        }
+        Err(e) => {
+            eprintln!("Article generation failed: {}", e);
+            friendly_error(&e)
+        }
+    };
+
+    send_message_in_parts(ctx, msg, &response).await?;
+
+    if let Some(typing) = typing {
+        typing.stop();
+    }
+    Ok(())
+}
+
+#[command]
+#[description("Summarizes the last N messages (default 50), everything since a time (`2h`, `14:30` UTC), or this whole thread")]
+#[usage("[N|since <time>|thread]")]
+async fn tldr(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let range = match summarize::parse_range(args.rest()) {
+        Ok(range) => range,
+        Err(e) => {
+            msg.reply(&ctx.http, format!("{}. Usage: `e.tldr [N|since <time>|thread]`", e)).await?;
+            return Ok(());
+        }
+    };
+
+    if let summarize::Range::Thread = range {
+        let in_thread = matches!(
+            msg.channel_id.to_channel(&ctx).await,
+            Ok(serenity::model::channel::Channel::Guild(channel)) if channel.thread_metadata.is_some()
+        );
+        if !in_thread {
+            msg.reply(&ctx.http, "`e.tldr thread` only works inside a thread.").await?;
+            return Ok(());
+        }
+    }
+
+    let typing = Typing::start(ctx.http.clone(), msg.channel_id.0).ok();
+
+    let messages = fetch_messages(ctx, msg, range).await?;
+    let lines: Vec<String> = messages
+        .iter()
+        .filter(|m| !m.content.trim().is_empty())
+        .map(|m| format!("{}: {}", m.author.name, m.content))
+        .collect();
+
+    if lines.is_empty() {
+        msg.reply(&ctx.http, "There's nothing here to summarize.").await?;
+        return Ok(());
+    }
+
+    let generator = {
+        let data_read = ctx.data.read().await;
+        data_read.get::<ChatGenerator>().expect("Expected ChatGenerator in TypeMap.").clone()
+    };
+
+    // One slot for the whole map-reduce, so a long summary doesn't interleave with other chats
+    let _permit = match wait_for_turn(ctx, msg).await {
+        Some(permit) => permit,
+        None => return Ok(()),
+    };
+
+    let response = match summarize::summarize(&generator, &lines).await {
+        Ok(summary) => {
+            let mut people: Vec<&str> = messages.iter().map(|m| m.author.name.as_str()).collect();
+            people.sort_unstable();
+            people.dedup();
+            format!("**TL;DR** of {} messages from {} people:\n\n{}", lines.len(), people.len(), summary.trim())
+        }
+        Err(e) => {
+            eprintln!("Summary failed: {}", e);
+            friendly_error(&e)
+        }
+    };
+
+    send_message_in_parts(ctx, msg, &response).await?;
+
+    if let Some(typing) = typing {
+        typing.stop();
+    }
+    Ok(())
+}
+
+// Messages before `msg` in its channel covered by `range`, oldest first. Pages back through
+// the channel 100 at a time, stopping at `summarize::MAX_MESSAGES`.
+async fn fetch_messages(ctx: &Context, msg: &Message, range: summarize::Range) -> serenity::Result<Vec<Message>> {
+    let (limit, since) = match range {
+        summarize::Range::Last(count) => (count, None),
+        summarize::Range::Since(since) => (summarize::MAX_MESSAGES, Some(since)),
+        summarize::Range::Thread => (summarize::MAX_MESSAGES, None),
+    };
+
+    let mut collected = Vec::new();
+    let mut before = msg.id;
+    'pages: while (collected.len() as u64) < limit {
+        let page_size = (limit - collected.len() as u64).min(100);
+        let page = msg.channel_id.messages(&ctx.http, |retriever| retriever.limit(page_size).before(before)).await?;
+        let exhausted = (page.len() as u64) < page_size;
+
+        // Newest first
+        for message in page {
+            if since.is_some_and(|since| message.timestamp.unix_timestamp() < since) {
+                break 'pages;
+            }
+            before = message.id;
+            collected.push(message);
+        }
+
+        if exhausted {
+            break;
+        }
+    }
+
+    collected.reverse();
+    Ok(collected)
+}
+
+#[command]
+#[description("Reacts to the last-sent message with one to three emoji, at set temp")]
+#[usage("[temp]")]
+async fn react(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let temperature = args.single::<f64>().unwrap_or(1.0).clamp(0.0, 2.0);
+
+    let previous = msg.channel_id.messages(&ctx.http, |retriever| retriever.limit(1).before(msg.id)).await?;
+    let target = match previous.into_iter().next() {
+        Some(target) => target,
+        None => {
+            msg.reply(&ctx.http, "There's nothing to react to.").await?;
+            return Ok(());
+        }
+    };
+
+    let generator = {
+        let data_read = ctx.data.read().await;
+        data_read.get::<ChatGenerator>().expect("Expected ChatGenerator in TypeMap.").clone()
+    };
+
+    let _permit = match wait_for_turn(ctx, msg).await {
+        Some(permit) => permit,
+        None => return Ok(()),
+    };
+
+    let custom = match msg.guild_id {
+        Some(guild_id) => guild_id.emojis(&ctx.http).await.unwrap_or_else(|e| {
+            eprintln!("Failed to fetch guild emoji: {:?}", e);
+            Vec::new()
+        }),
+        None => Vec::new(),
+    };
+
+    let content = if target.content.trim().is_empty() { "(an attachment)" } else { target.content.as_str() };
+    let prompt = reactions::prompt(&target.author.name, content, &custom);
+
+    // If the model rambles instead of answering with emoji, ask once more a bit cooler
+    let mut chosen = Vec::new();
+    for attempt_temperature in [temperature, temperature.min(0.5)] {
+        match generator.complete(&prompt, attempt_temperature, 32, Duration::from_secs(60)).await {
+            Ok(text) => {
+                chosen = reactions::parse(&text, &custom);
+                if chosen.is_empty() {
+                    println!("No usable emoji in reaction output {:?}", text);
+                }
+            }
+            Err(e) => {
+                eprintln!("Reaction generation failed: {}", e);
+                msg.reply(&ctx.http, friendly_error(&e)).await?;
+                return Ok(());
+            }
+        }
+        if !chosen.is_empty() {
+            break;
+        }
+    }
+
+    let mut failed = Vec::new();
+    for reaction in reactions::or_fallback(chosen) {
+        if let Err(e) = target.react(&ctx.http, reaction.clone()).await {
+            eprintln!("Failed to add reaction {}: {:?}", reaction, e);
+            failed.push(reaction.to_string());
+        }
+    }
+
+    if !failed.is_empty() {
+        let response = format!("I couldn't add {}. I might be missing the Add Reactions permission here.", failed.join(" "));
+        msg.reply(&ctx.http, response).await?;
+    }
+
+    Ok(())
+}
+
+#[command]
+#[description("Codes")]
+#[usage("<request>")]
+async fn code(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let prompt = args.rest().trim().to_string();
+    if prompt.is_empty() {
+        msg.reply(&ctx.http, "Usage: `e.code <request>`").await?;
+        return Ok(());
+    }
+
+    let typing = Typing::start(ctx.http.clone(), msg.channel_id.0).ok();
+
+    answer(ctx, msg, prompt, Vec::new(), Vec::new(), Some("Answer with working code in fenced markdown code blocks tagged with the language. Keep explanations short.")).await;
+
+    if let Some(typing) = typing {
+        typing.stop();
+    }
+    Ok(())
+}
+
+// The last `count` messages before `msg` as "name: text" lines, oldest first
+async fn channel_transcript(ctx: &Context, msg: &Message, count: u64) -> serenity::Result<String> {
+    let messages = msg.channel_id.messages(&ctx.http, |retriever| retriever.limit(count).before(msg.id)).await?;
+
+    Ok(messages
+        .iter()
+        .rev()
+        .filter(|m| !m.content.trim().is_empty())
+        .map(|m| format!("{}: {}", m.author.name, m.content))
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+// Takes a place in the generation queue without a progress message, for quick jobs.
+// None when the user already has too much queued (they've been told).
+async fn wait_for_turn(ctx: &Context, msg: &Message) -> Option<scheduler::Permit> {
+    let queue = {
+        let data_read = ctx.data.read().await;
+        data_read.get::<GenerationQueue>().expect("Expected GenerationQueue in TypeMap.").clone()
+    };
+
+    match queue.enqueue(msg.author.id.0) {
+        Ok(mut ticket) => Some(ticket.wait().await),
+        Err(full) => {
+            msg.reply(&ctx.http, format!("You already have {} requests waiting. Let those finish first!", full.queued)).await.ok();
+            None
+        }
+    }
+}
+
+#[command]
+#[description("Shows a post from Egghead's blog: a random one, the latest, or one by number")]
+#[usage("[random|latest|<id>]")]
+async fn blog(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let choice = args.single::<String>().unwrap_or_else(|_| "random".to_string()).to_lowercase();
+    let response = match choice.as_str() {
+        "random" | "latest" => slash::read_blog(ctx, &choice, None).await,
+        // `e.blog 12` and `e.blog id 12` both work
+        "id" => match args.single::<i64>() {
+            Ok(id) => slash::read_blog(ctx, "id", Some(id)).await,
+            Err(_) => "Usage: `e.blog id <number>`".to_string(),
+        },
+        other => match other.trim_start_matches('#').parse::<i64>() {
+            Ok(id) => slash::read_blog(ctx, "id", Some(id)).await,
+            Err(_) => "Usage: `e.blog [random|latest|<id>]`".to_string(),
+        },
+    };
+
+    send_message_in_parts(ctx, msg, &response).await?;
+
+    Ok(())
+}
+
+#[command]
+#[description("Dreams up an image")]
+#[usage("<prompt>")]
+async fn dream(ctx: &Context, msg: &Message) -> CommandResult {
+    let prompt: String = msg.content.split_whitespace().skip(1).collect::<Vec<_>>().join(" ");
+
+    if prompt.is_empty() {
+        msg.reply(&ctx.http, "Usage: `e.dream <prompt>`").await?;
+        return Ok(());
+    }
+
+    let _typing = Typing::start(ctx.http.clone(), msg.channel_id.0).ok();
+
+    let (generator, queue) = {
+        let data_read = ctx.data.read().await;
+        (
+            data_read.get::<ChatGenerator>().expect("Expected ChatGenerator in TypeMap.").clone(),
+            data_read.get::<GenerationQueue>().expect("Expected GenerationQueue in TypeMap.").clone(),
+        )
+    };
+
+    // Image jobs share the chat queue; they compete for the same box
+    let mut ticket = match queue.enqueue(msg.author.id.0) {
+        Ok(ticket) => ticket,
+        Err(full) => {
+            msg.reply(&ctx.http, format!("You already have {} requests waiting. Let those finish first!", full.queued)).await?;
+            return Ok(());
+        }
+    };
+
+    let mut queue_message = match ticket.position() {
+        Some(position) => msg.reply(&ctx.http, queue_status(position)).await.ok(),
+        None => None,
+    };
+
+    let _permit = loop {
+        if let Some(permit) = ticket.wait_timeout(QUEUE_POLL_INTERVAL).await {
+            break permit;
+        }
+        if let (Some(position), Some(status)) = (ticket.position(), queue_message.as_mut()) {
+            let content = queue_status(position);
+            if status.content != content {
+                status.edit(&ctx.http, |m| m.content(&content)).await.ok();
+            }
+        }
+    };
+
+    if let Some(status) = queue_message {
+        status.delete(&ctx.http).await.ok();
+    }
+
+    let result = generator.txt2img(&prompt, &generator::DreamOptions::default()).await;
+
+    match result {
+        Ok(Some(b64_string)) => {
+            use base64::{Engine as _, engine::general_purpose};
+            match general_purpose::STANDARD.decode(&b64_string) {
+                Ok(image_bytes) => {
+                    let temp_path = format!("/tmp/egghead_dream_{}.png", msg.id.0);
+                    if let Err(e) = std::fs::write(&temp_path, &image_bytes) {
+                        eprintln!("Failed to write temp image: {:?}", e);
+                        msg.reply(&ctx.http, "Failed to save generated image.").await.ok();
+                        return Ok(());
+                    }
+
+                    let caption = format!("Dream: {}", prompt);
+                    let sent = msg.channel_id.send_message(&ctx.http, |m| {
+                        m.reference_message(msg)
+                            .content(&caption)
+                            .add_file(std::path::Path::new(&temp_path))
+                    }).await;
+
+                    match sent {
+                        // Remembered with the image, so replies to it can ask about the picture
+                        Ok(sent) => record_turns(ctx, vec![
+                            conversation::Turn {
+                                message_id: msg.id.0,
+                                parent_id: msg.message_reference.as_ref().and_then(|reference| reference.message_id).map(|id| id.0),
+                                channel_id: msg.channel_id.0,
+                                user_id: msg.author.id.0,
+                                role: "user",
+                                content: prompt.clone(),
+                                images: Vec::new(),
+                            },
+                            conversation::Turn {
+                                message_id: sent.id.0,
+                                parent_id: Some(msg.id.0),
+                                channel_id: sent.channel_id.0,
+                                user_id: msg.author.id.0,
+                                role: "assistant",
+                                content: caption,
+                                images: vec![b64_string],
+                            },
+                        ]).await,
+                        Err(e) => eprintln!("Failed to send image: {:?}", e),
+                    }
 
                     std::fs::remove_file(&temp_path).ok();
                 }
@@ -541,14 +1470,591 @@ async fn dream(ctx: &Context, msg: &Message) -> CommandResult {
                 }
             }
         }
-        None => {
+        Ok(None) => {
             msg.reply(&ctx.http, "Failed to generate image. Make sure the SD model is loaded.").await.ok();
         }
+        Err(e) => {
+            eprintln!("txt2img request failed: {}", e);
+            msg.reply(&ctx.http, friendly_error(&e)).await.ok();
+        }
     }
 
     Ok(())
 }
 
+#[command]
+#[description("Shows, adds or forgets what I remember about you")]
+#[usage("[list [all]|add <fact>|forget <id|all>]")]
+#[sub_commands(memory_list, memory_add, memory_forget)]
+async fn memory(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    list_memories(ctx, msg, args.rest().trim() == "all").await
+}
+
+#[command("list")]
+async fn memory_list(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    list_memories(ctx, msg, args.rest().trim() == "all").await
+}
+
+#[command("add")]
+async fn memory_add(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let fact = args.rest().trim();
+    if fact.is_empty() {
+        msg.reply(&ctx.http, "Usage: `e.memory add <fact>`").await?;
+        return Ok(());
+    }
+
+    let response = remember(ctx, msg, fact).await;
+    msg.reply(&ctx.http, response).await?;
+
+    Ok(())
+}
+
+#[command("forget")]
+async fn memory_forget(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let which = args.rest().trim().to_lowercase();
+    let user_id = msg.author.id.0;
+
+    let db_path = {
+        let data_read = ctx.data.read().await;
+        data_read.get::<DatabasePath>().expect("Expected DatabasePath in TypeMap.").clone()
+    };
+
+    let response = if which == "all" {
+        let result = db::with_connection(db_path, move |conn| {
+            Ok((memory::forget_all(conn, user_id)?, conversation::forget_user(conn, user_id)?))
+        }).await;
+        match result {
+            Ok((0, 0)) => "I didn't remember anything about you.".to_string(),
+            Ok((facts, turns)) => format!(
+                "Forgot {} fact(s) about you, everywhere, and deleted {} stored conversation message(s) of yours and my answers to them.",
+                facts, turns
+            ),
+            Err(e) => {
+                eprintln!("Failed to forget memories: {:?}", e);
+                "Failed to forget. Try again?".to_string()
+            }
+        }
+    } else if let Ok(id) = which.parse::<i64>() {
+        match db::with_connection(db_path, move |conn| memory::forget(conn, user_id, id)).await {
+            Ok(true) => format!("Forgot #{}.", id),
+            Ok(false) => format!("You don't have a memory #{}. `e.memory list` shows them.", id),
+            Err(e) => {
+                eprintln!("Failed to forget memory: {:?}", e);
+                "Failed to forget. Try again?".to_string()
+            }
+        }
+    } else {
+        "Usage: `e.memory forget <id|all>`".to_string()
+    };
+    msg.reply(&ctx.http, response).await?;
+
+    Ok(())
+}
+
+// Stores `fact` about the author for this guild (or their DMs) and says how that went
+async fn remember(ctx: &Context, msg: &Message, fact: &str) -> String {
+    if fact.chars().count() > memory::MAX_FACT_LENGTH {
+        return format!("That's a bit long to remember. Keep it under {} characters?", memory::MAX_FACT_LENGTH);
+    }
+
+    let db_path = {
+        let data_read = ctx.data.read().await;
+        data_read.get::<DatabasePath>().expect("Expected DatabasePath in TypeMap.").clone()
+    };
+
+    let (user_id, guild_id, stored) = (msg.author.id.0, msg.guild_id.map(|g| g.0).unwrap_or(0), fact.to_string());
+    let result = db::with_connection(db_path, move |conn| {
+        memory::add(conn, user_id, guild_id, &stored, memory::Source::User)
+    }).await;
+
+    match result {
+        Ok(id) => format!("Got it, I'll remember that {} (#{}). `e.memory` shows everything I know about you.", fact, id),
+        Err(e) => {
+            eprintln!("Failed to save memory: {:?}", e);
+            "Failed to remember that. Try again?".to_string()
+        }
+    }
+}
+
+// Lists the author's memories for this guild, or with `everywhere` all of them, in a DM
+async fn list_memories(ctx: &Context, msg: &Message, everywhere: bool) -> CommandResult {
+    let db_path = {
+        let data_read = ctx.data.read().await;
+        data_read.get::<DatabasePath>().expect("Expected DatabasePath in TypeMap.").clone()
+    };
+
+    let user_id = msg.author.id.0;
+    let scope = if everywhere { None } else { Some(msg.guild_id.map(|g| g.0).unwrap_or(0)) };
+    let memories = match db::with_connection(db_path, move |conn| memory::list(conn, user_id, scope)).await {
+        Ok(memories) => memories,
+        Err(e) => {
+            eprintln!("Failed to list memories: {:?}", e);
+            msg.reply(&ctx.http, "Failed to look up your memories.").await?;
+            return Ok(());
+        }
+    };
+
+    if memories.is_empty() {
+        let response = if everywhere { "I don't remember anything about you." } else { "I don't remember anything about you here." };
+        msg.reply(&ctx.http, response).await?;
+        return Ok(());
+    }
+
+    let mut response = String::from("**What I remember about you**\n");
+    for memory in &memories {
+        let place = match (everywhere, memory.guild_id) {
+            (false, _) => String::new(),
+            (true, 0) => " (DMs)".to_string(),
+            (true, guild_id) => {
+                let name = serenity::model::id::GuildId(guild_id).name(&ctx.cache).unwrap_or_else(|| guild_id.to_string());
+                format!(" ({})", name)
+            }
+        };
+        let source = if memory.from_model { ", noted by me" } else { "" };
+        response.push_str(&format!(
+            "`#{}` {}{} *({}{})*\n",
+            memory.id,
+            memory.fact,
+            place,
+            memory.created_at.format("%Y-%m-%d"),
+            source
+        ));
+    }
+    response.push_str("\nForget one with `e.memory forget <id>`, or everything with `e.memory forget all`.");
+
+    if everywhere && msg.guild_id.is_some() {
+        // Facts from other servers stay between egghead and the user
+        let mut sent = msg.author.create_dm_channel(&ctx.http).await.map(|dm| dm.id);
+        if let Ok(dm) = sent {
+            for chunk in split::split_message(&response, split::MAX_LENGTH) {
+                if let Err(why) = dm.say(&ctx.http, chunk).await {
+                    sent = Err(why);
+                    break;
+                }
+            }
+        }
+        let reply = match sent {
+            Ok(_) => "Sent you everything I remember in a DM.",
+            Err(_) => "I couldn't DM you. Try `e.memory list all` in a DM with me.",
+        };
+        msg.reply(&ctx.http, reply).await?;
+        return Ok(());
+    }
+
+    send_message_in_parts(ctx, msg, &response).await
+}
+
+#[command]
+#[description("Shows or changes how I behave in this channel or server")]
+#[usage("[show|set|reset]")]
+#[only_in(guilds)]
+#[sub_commands(persona_show, persona_set, persona_reset)]
+async fn persona(ctx: &Context, msg: &Message) -> CommandResult {
+    show_persona(ctx, msg).await
+}
+
+#[command("show")]
+#[only_in(guilds)]
+async fn persona_show(ctx: &Context, msg: &Message) -> CommandResult {
+    show_persona(ctx, msg).await
+}
+
+#[command("set")]
+#[only_in(guilds)]
+#[checks(Admin)]
+async fn persona_set(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    const USAGE: &str = "Usage: `e.persona set <channel|guild> <prompt|temperature|model> <value>`";
+
+    let scope = args.single::<String>().ok().and_then(|s| persona_scope(msg, &s));
+    let field = args.single::<String>().ok().and_then(|f| persona::Field::parse(&f));
+    let value = args.rest().trim().to_string();
+
+    let (scope, field) = match (scope, field) {
+        (Some(scope), Some(field)) if !value.is_empty() => (scope, field),
+        _ => {
+            msg.reply(&ctx.http, USAGE).await?;
+            return Ok(());
+        }
+    };
+
+    if field == persona::Field::Temperature {
+        match value.parse::<f64>() {
+            Ok(t) if (0.0..=2.0).contains(&t) => {}
+            _ => {
+                msg.reply(&ctx.http, "Temperature must be a number between 0 and 2.").await?;
+                return Ok(());
+            }
+        }
+    }
+
+    let db_path = {
+        let data_read = ctx.data.read().await;
+        data_read.get::<DatabasePath>().expect("Expected DatabasePath in TypeMap.").clone()
+    };
+
+    let stored_value = value.clone();
+    let result = db::with_connection(db_path, move |conn| {
+        persona::set_field(conn, scope, field, &stored_value)
+    }).await;
+
+    match result {
+        Ok(()) => msg.reply(&ctx.http, format!("Updated the {} persona.", scope.label())).await?,
+        Err(e) => {
+            eprintln!("Failed to save persona: {:?}", e);
+            msg.reply(&ctx.http, "Failed to save the persona.").await?
+        }
+    };
+
+    Ok(())
+}
+
+#[command("reset")]
+#[only_in(guilds)]
+#[checks(Admin)]
+async fn persona_reset(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let scope = match args.single::<String>().ok().and_then(|s| persona_scope(msg, &s)) {
+        Some(scope) => scope,
+        None => {
+            msg.reply(&ctx.http, "Usage: `e.persona reset <channel|guild>`").await?;
+            return Ok(());
+        }
+    };
+
+    let db_path = {
+        let data_read = ctx.data.read().await;
+        data_read.get::<DatabasePath>().expect("Expected DatabasePath in TypeMap.").clone()
+    };
+
+    let result = db::with_connection(db_path, move |conn| persona::reset(conn, scope)).await;
+
+    let response = match result {
+        Ok(true) => format!("Reset the {} persona.", scope.label()),
+        Ok(false) => format!("There was no {} persona to reset.", scope.label()),
+        Err(e) => {
+            eprintln!("Failed to reset persona: {:?}", e);
+            "Failed to reset the persona.".to_string()
+        }
+    };
+    msg.reply(&ctx.http, response).await?;
+
+    Ok(())
+}
+
+fn persona_scope(msg: &Message, value: &str) -> Option<persona::Scope> {
+    match value.to_lowercase().as_str() {
+        "channel" => Some(persona::Scope::Channel(msg.channel_id.0)),
+        "guild" | "server" => msg.guild_id.map(|g| persona::Scope::Guild(g.0)),
+        _ => None,
+    }
+}
+
+async fn show_persona(ctx: &Context, msg: &Message) -> CommandResult {
+    let (config, db_path) = {
+        let data_read = ctx.data.read().await;
+        (
+            data_read.get::<BotConfig>().expect("Expected BotConfig in TypeMap.").clone(),
+            data_read.get::<DatabasePath>().expect("Expected DatabasePath in TypeMap.").clone(),
+        )
+    };
+
+    let persona = resolve_persona(db_path, config.clone(), msg.guild_id.map(|g| g.0), msg.channel_id.0).await;
+
+    let response = format!(
+        "**Persona for this channel**\n**Prompt** ({}): {}\n**Temperature** ({}): {}\n**Model** ({}): {}",
+        persona.system_prompt_source,
+        persona.system_prompt,
+        persona.temperature_source,
+        persona.temperature,
+        persona.model_source,
+        persona.model.as_deref().unwrap_or("backend default"),
+    );
+
+    send_message_in_parts(ctx, msg, &response).await?;
+
+    Ok(())
+}
+
+#[command]
+#[description("Shows or changes what happens to answers too long for one message")]
+#[usage("[set <messages|thread|file>]")]
+#[only_in(guilds)]
+#[sub_commands(overflow_set)]
+async fn overflow(ctx: &Context, msg: &Message) -> CommandResult {
+    let policy = overflow_policy(ctx, msg.guild_id.map(|g| g.0)).await;
+
+    let response = format!(
+        "Long answers here go to **{}**. Change it with `e.overflow set <messages|thread|file>`.",
+        policy.label()
+    );
+    msg.reply(&ctx.http, response).await?;
+
+    Ok(())
+}
+
+#[command("set")]
+#[only_in(guilds)]
+#[checks(Admin)]
+async fn overflow_set(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let (policy, guild_id) = match (overflow::OverflowPolicy::parse(args.rest()), msg.guild_id) {
+        (Some(policy), Some(guild_id)) => (policy, guild_id.0),
+        _ => {
+            msg.reply(&ctx.http, "Usage: `e.overflow set <messages|thread|file>`").await?;
+            return Ok(());
+        }
+    };
+
+    let db_path = {
+        let data_read = ctx.data.read().await;
+        data_read.get::<DatabasePath>().expect("Expected DatabasePath in TypeMap.").clone()
+    };
+
+    let result = db::with_connection(db_path, move |conn| overflow::set_policy(conn, guild_id, policy)).await;
+
+    let response = match result {
+        Ok(()) => format!("Long answers will go to **{}** from now on.", policy.label()),
+        Err(e) => {
+            eprintln!("Failed to save overflow policy: {:?}", e);
+            "Failed to save the overflow policy.".to_string()
+        }
+    };
+    msg.reply(&ctx.http, response).await?;
+
+    Ok(())
+}
+
+#[command]
+#[description("Shows or changes which roles count as admins here")]
+#[usage("[add|remove <role>]")]
+#[only_in(guilds)]
+#[sub_commands(perms_add, perms_remove)]
+async fn perms(ctx: &Context, msg: &Message) -> CommandResult {
+    let guild_id = match msg.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Ok(()),
+    };
+
+    let db_path = {
+        let data_read = ctx.data.read().await;
+        data_read.get::<DatabasePath>().expect("Expected DatabasePath in TypeMap.").clone()
+    };
+
+    let level = permissions::level(ctx, msg).await;
+    let admin_roles = db::with_connection(db_path, move |conn| permissions::admin_roles(conn, guild_id.0)).await?;
+    let guild_roles = guild_id.roles(&ctx.http).await.unwrap_or_default();
+
+    let roles = if admin_roles.is_empty() {
+        "none yet, so members with Manage Server count as admins".to_string()
+    } else {
+        admin_roles
+            .iter()
+            .map(|id| match guild_roles.get(&serenity::model::id::RoleId(*id)) {
+                Some(role) => format!("@{}", role.name),
+                None => format!("deleted role {}", id),
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    let mut response = format!(
+        "**Permissions here**\nYour level: {}\nAdmin roles: {}\nThe server owner and members with Administrator are always admins.",
+        level.label(),
+        roles,
+    );
+    for (check, commands) in checked_commands() {
+        response.push_str(&format!("\n{} only: {}", check, commands.join(", ")));
+    }
+    response.push_str("\nChange the roles with `e.perms add <role>` and `e.perms remove <role>`.");
+
+    send_message_in_parts(ctx, msg, &response).await?;
+
+    Ok(())
+}
+
+#[command("add")]
+#[only_in(guilds)]
+#[checks(Admin)]
+async fn perms_add(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    edit_admin_roles(ctx, msg, args.rest(), true).await
+}
+
+#[command("remove")]
+#[only_in(guilds)]
+#[checks(Admin)]
+async fn perms_remove(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    edit_admin_roles(ctx, msg, args.rest(), false).await
+}
+
+async fn edit_admin_roles(ctx: &Context, msg: &Message, value: &str, add: bool) -> CommandResult {
+    let usage = if add { "Usage: `e.perms add <role>`" } else { "Usage: `e.perms remove <role>`" };
+    let guild_id = match msg.guild_id {
+        Some(guild_id) if !value.trim().is_empty() => guild_id,
+        _ => {
+            msg.reply(&ctx.http, usage).await?;
+            return Ok(());
+        }
+    };
+
+    let guild_roles = guild_id.roles(&ctx.http).await?;
+    let (role_id, name) = match permissions::find_role(&guild_roles, value) {
+        Some(role) => (role.id.0, role.name.clone()),
+        // Roles deleted since they were added can still be removed by id
+        None => match value.trim().parse::<u64>() {
+            Ok(id) if !add => (id, id.to_string()),
+            _ => {
+                msg.reply(&ctx.http, format!("I can't find a role called `{}` here.", value.trim())).await?;
+                return Ok(());
+            }
+        },
+    };
+
+    let db_path = {
+        let data_read = ctx.data.read().await;
+        data_read.get::<DatabasePath>().expect("Expected DatabasePath in TypeMap.").clone()
+    };
+
+    let result = db::with_connection(db_path, move |conn| {
+        if add {
+            permissions::add_admin_role(conn, guild_id.0, role_id)
+        } else {
+            permissions::remove_admin_role(conn, guild_id.0, role_id)
+        }
+    }).await;
+
+    let response = match (result, add) {
+        (Ok(true), true) => format!("Members of @{} are admins now.", name),
+        (Ok(false), true) => format!("@{} is already an admin role.", name),
+        (Ok(true), false) => format!("@{} isn't an admin role any more.", name),
+        (Ok(false), false) => format!("@{} wasn't an admin role.", name),
+        (Err(e), _) => {
+            eprintln!("Failed to save admin roles: {:?}", e);
+            "Failed to save the admin roles.".to_string()
+        }
+    };
+    msg.reply(&ctx.http, response).await?;
+
+    Ok(())
+}
+
+// Commands (subcommands included) that declare a check, grouped by check, as `e.` invocations
+fn checked_commands() -> Vec<(&'static str, Vec<String>)> {
+    fn walk(commands: &[&'static serenity::framework::standard::Command], prefix: &str, found: &mut Vec<(&'static str, Vec<String>)>) {
+        for command in commands {
+            let name = format!("{}{}", prefix, command.options.names[0]);
+            for check in command.options.checks {
+                let invocation = format!("`e.{}`", name);
+                match found.iter_mut().find(|(existing, _)| *existing == check.name) {
+                    Some((_, commands)) => commands.push(invocation),
+                    None => found.push((check.name, vec![invocation])),
+                }
+            }
+            walk(command.options.sub_commands, &format!("{} ", name), found);
+        }
+    }
+
+    let mut found = Vec::new();
+    for group in [&GENERAL_GROUP, &EXPERIMENTAL_GROUP] {
+        walk(group.options.commands, "", &mut found);
+    }
+    found
+}
+
+#[command]
+#[description("Shows which model answers what, and which one answered last here")]
+async fn model(ctx: &Context, msg: &Message) -> CommandResult {
+    let (generator, routes_lock) = {
+        let data_read = ctx.data.read().await;
+        (
+            data_read.get::<ChatGenerator>().expect("Expected ChatGenerator in TypeMap.").clone(),
+            data_read.get::<LastRoute>().expect("Expected LastRoute in TypeMap.").clone(),
+        )
+    };
+
+    let router = generator.router();
+    let mut response = format!(
+        "**Model routing**\nImages: `{}`\nText: `{}`\n",
+        router.vision_model(),
+        router.text_model(),
+    );
+
+    for (keyword, model) in router.rules() {
+        response.push_str(&format!("Keyword `{}`: `{}`\n", keyword, model));
+    }
+    response.push_str("Add `--model <name>` to a prompt to pick one yourself.\n");
+
+    match routes_lock.read().await.get(&msg.channel_id.0) {
+        Some(route) => response.push_str(&format!("\nLast answer here came from `{}` ({}).", route.model, route.reason)),
+        None => response.push_str("\nI haven't answered anything in this channel yet."),
+    }
+
+    send_message_in_parts(ctx, msg, &response).await?;
+
+    Ok(())
+}
+
+#[command]
+#[description("Shows whether my brain is up and how long the queue is")]
+async fn status(ctx: &Context, msg: &Message) -> CommandResult {
+    let generator = {
+        let data_read = ctx.data.read().await;
+        data_read.get::<ChatGenerator>().expect("Expected ChatGenerator in TypeMap.").clone()
+    };
+
+    let queue = {
+        let data_read = ctx.data.read().await;
+        data_read.get::<GenerationQueue>().expect("Expected GenerationQueue in TypeMap.").clone()
+    };
+
+    let status = generator.circuit().status();
+    let state = match status.state {
+        circuit::CircuitState::Closed => "closed - taking requests".to_string(),
+        circuit::CircuitState::Open { retry_in } => format!("open - rebooting, next attempt in {}s", retry_in.as_secs().max(1)),
+        circuit::CircuitState::HalfOpen => "half-open - checking whether the brain is back".to_string(),
+    };
+
+    let mut response = format!(
+        "**Brain status**\nCircuit: {}\nConsecutive failures: {}",
+        state,
+        status.consecutive_failures,
+    );
+    if let Some(error) = status.last_error {
+        response.push_str(&format!("\nLast failure: {}", error));
+    }
+
+    let queue_status = queue.status();
+    response.push_str(&format!(
+        "\nQueue: {}/{} running, {} waiting",
+        queue_status.running,
+        queue_status.max_concurrent,
+        queue_status.waiting,
+    ));
+
+    send_message_in_parts(ctx, msg, &response).await?;
+
+    Ok(())
+}
+
+#[command]
+#[description("Shows how often each command has run since I started (owners only)")]
+#[checks(Owner)]
+async fn stats(ctx: &Context, msg: &Message) -> CommandResult {
+    let counter = {
+        let data_read = ctx.data.read().await;
+        data_read.get::<CommandCounter>().expect("Expected CommandCounter in TypeMap.").clone()
+    };
+
+    let mut counts: Vec<(String, u64)> = counter.read().await.iter().map(|(name, count)| (name.clone(), *count)).collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    let mut response = "**Commands run since startup**".to_string();
+    for (name, count) in counts {
+        response.push_str(&format!("\n`{}`: {}", name, count));
+    }
+
+    send_message_in_parts(ctx, msg, &response).await?;
+
+    Ok(())
+}
+
 /*
 // ORIGINAL BLOG COMMAND (DISABLED)
 // Uncomment this and comment out the above function to re-enable blog functionality
@@ -611,7 +2117,7 @@ async fn blog_original(ctx: &Context, msg: &Message) -> CommandResult {
         }
     }).await.unwrap();
 
-    send_message_in_parts(&ctx.http, msg, &response).await?;
+    send_message_in_parts(ctx, msg, &response).await?;
 
     Ok(())
 }
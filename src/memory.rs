@@ -0,0 +1,289 @@
+use std::collections::HashSet;
+
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, params};
+
+// Longest fact worth keeping; anything longer is a conversation, not a fact
+pub const MAX_FACT_LENGTH: usize = 300;
+// Facts kept per user per guild. The oldest go when a new one doesn't fit.
+const MAX_PER_SCOPE: usize = 50;
+// Facts put in front of the model for one request
+const MAX_IN_PROMPT: usize = 8;
+
+// Words too common to say whether a fact has anything to do with a prompt
+const STOP_WORDS: &[&str] = &[
+    "the", "and", "for", "you", "your", "that", "this", "with", "have", "are", "was", "what", "how",
+    "why", "can", "not", "but", "use", "like", "about", "from", "they", "them", "their", "there",
+];
+
+// Facts are kept per user per guild; DMs are guild 0
+pub fn create_table(conn: &Connection) -> Result<(), rusqlite::Error> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS user_memories (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER NOT NULL,
+            guild_id INTEGER NOT NULL,
+            fact TEXT NOT NULL,
+            source TEXT NOT NULL,
+            created_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+// Who put a fact there
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    // The user asked for it to be remembered
+    User,
+    // The model decided it was worth remembering (see the `remember` tool)
+    Model,
+}
+
+impl Source {
+    fn label(&self) -> &'static str {
+        match self {
+            Source::User => "user",
+            Source::Model => "model",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Memory {
+    pub id: i64,
+    pub guild_id: u64,
+    pub fact: String,
+    pub from_model: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+// Spots "remember that I use NixOS" (or "remember: ...", with or without "please") and returns
+// the fact to keep. "Remember when ...?" and the like are left for the model.
+pub fn detect(prompt: &str) -> Option<String> {
+    let prompt = prompt.trim();
+    let rest = strip_prefix_ignore_case(prompt, "please ").unwrap_or(prompt);
+    let rest = strip_prefix_ignore_case(rest, "remember")?;
+    let rest = strip_prefix_ignore_case(rest, " that ").or_else(|| rest.strip_prefix(':'))?;
+
+    let fact = rest.trim().trim_end_matches(['.', '!']).trim();
+    if fact.is_empty() || fact.ends_with('?') {
+        return None;
+    }
+    Some(fact.to_string())
+}
+
+fn strip_prefix_ignore_case<'a>(text: &'a str, prefix: &str) -> Option<&'a str> {
+    let head = text.get(..prefix.len())?;
+    if head.eq_ignore_ascii_case(prefix) {
+        Some(&text[prefix.len()..])
+    } else {
+        None
+    }
+}
+
+pub fn add(conn: &Connection, user_id: u64, guild_id: u64, fact: &str, source: Source) -> Result<i64, rusqlite::Error> {
+    conn.execute(
+        "INSERT INTO user_memories (user_id, guild_id, fact, source, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![user_id as i64, guild_id as i64, fact, source.label(), Utc::now().timestamp()],
+    )?;
+    let id = conn.last_insert_rowid();
+
+    conn.execute(
+        "DELETE FROM user_memories WHERE user_id = ?1 AND guild_id = ?2 AND id NOT IN (
+            SELECT id FROM user_memories WHERE user_id = ?1 AND guild_id = ?2 ORDER BY id DESC LIMIT ?3
+        )",
+        params![user_id as i64, guild_id as i64, MAX_PER_SCOPE as i64],
+    )?;
+
+    Ok(id)
+}
+
+// A user's facts, oldest first: for one guild, or everywhere when `guild_id` is None
+pub fn list(conn: &Connection, user_id: u64, guild_id: Option<u64>) -> Result<Vec<Memory>, rusqlite::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT id, guild_id, fact, source, created_at FROM user_memories
+         WHERE user_id = ?1 AND (?2 IS NULL OR guild_id = ?2)
+         ORDER BY id",
+    )?;
+
+    let memories = stmt
+        .query_map(params![user_id as i64, guild_id.map(|g| g as i64)], |row| {
+            let source: String = row.get(3)?;
+            Ok(Memory {
+                id: row.get(0)?,
+                guild_id: row.get::<_, i64>(1)? as u64,
+                fact: row.get(2)?,
+                from_model: source == Source::Model.label(),
+                created_at: DateTime::from_timestamp(row.get(4)?, 0).unwrap_or_default(),
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(memories)
+}
+
+// Deletes one of the user's facts; false if they have none with that id
+pub fn forget(conn: &Connection, user_id: u64, id: i64) -> Result<bool, rusqlite::Error> {
+    let deleted = conn.execute(
+        "DELETE FROM user_memories WHERE id = ?1 AND user_id = ?2",
+        params![id, user_id as i64],
+    )?;
+    Ok(deleted > 0)
+}
+
+// Deletes everything stored about the user, in every guild
+pub fn forget_all(conn: &Connection, user_id: u64) -> Result<usize, rusqlite::Error> {
+    conn.execute("DELETE FROM user_memories WHERE user_id = ?1", params![user_id as i64])
+}
+
+// The facts worth showing the model for `prompt`: all of them while there are only a few,
+// otherwise the ones sharing the most words with it, newest first on a tie
+pub fn relevant(conn: &Connection, user_id: u64, guild_id: u64, prompt: &str) -> Result<Vec<Memory>, rusqlite::Error> {
+    let mut memories = list(conn, user_id, Some(guild_id))?;
+    if memories.len() <= MAX_IN_PROMPT {
+        return Ok(memories);
+    }
+
+    let prompt_words = words(prompt);
+    memories.reverse();
+    memories.sort_by_key(|memory| std::cmp::Reverse(words(&memory.fact).intersection(&prompt_words).count()));
+    memories.truncate(MAX_IN_PROMPT);
+    Ok(memories)
+}
+
+fn words(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.len() >= 3)
+        .map(|word| word.to_lowercase())
+        .filter(|word| !STOP_WORDS.contains(&word.as_str()))
+        .collect()
+}
+
+// The system prompt addition for `name`'s facts
+pub fn prompt_section(name: &str, memories: &[Memory]) -> String {
+    let facts: Vec<String> = memories.iter().map(|memory| format!("- {}", memory.fact)).collect();
+    format!(
+        "What you remember about {} from earlier conversations (use it when relevant, don't recite it):\n{}",
+        name,
+        facts.join("\n")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        create_table(&conn).unwrap();
+        conn
+    }
+
+    fn facts(memories: &[Memory]) -> Vec<&str> {
+        memories.iter().map(|memory| memory.fact.as_str()).collect()
+    }
+
+    #[test]
+    fn detects_facts_to_remember() {
+        assert_eq!(detect("remember that I use NixOS."), Some("I use NixOS".to_string()));
+        assert_eq!(detect("  Please REMEMBER that my cat is called Egg!"), Some("my cat is called Egg".to_string()));
+        assert_eq!(detect("remember: tabs, not spaces"), Some("tabs, not spaces".to_string()));
+
+        assert_eq!(detect("remember when we talked about Rust?"), None);
+        assert_eq!(detect("remember that time I asked about monads?"), None);
+        assert_eq!(detect("remember that"), None);
+        assert_eq!(detect("do you remember that I use NixOS"), None);
+        assert_eq!(detect("rémember that"), None);
+    }
+
+    #[test]
+    fn keeps_facts_per_user_and_guild() {
+        let conn = store();
+        add(&conn, 1, 100, "likes Rust", Source::User).unwrap();
+        add(&conn, 1, 200, "likes Go", Source::Model).unwrap();
+        add(&conn, 2, 100, "likes Zig", Source::User).unwrap();
+
+        let everywhere = list(&conn, 1, None).unwrap();
+        assert_eq!(facts(&everywhere), ["likes Rust", "likes Go"]);
+        assert!(!everywhere[0].from_model);
+        assert!(everywhere[1].from_model);
+        assert_eq!(facts(&list(&conn, 1, Some(200)).unwrap()), ["likes Go"]);
+    }
+
+    #[test]
+    fn drops_the_oldest_past_the_limit() {
+        let conn = store();
+        for i in 0..MAX_PER_SCOPE + 5 {
+            add(&conn, 1, 100, &format!("fact {}", i), Source::User).unwrap();
+        }
+        add(&conn, 1, 200, "elsewhere", Source::User).unwrap();
+
+        let kept = list(&conn, 1, Some(100)).unwrap();
+        assert_eq!(kept.len(), MAX_PER_SCOPE);
+        assert_eq!(kept[0].fact, "fact 5");
+        assert_eq!(kept[MAX_PER_SCOPE - 1].fact, format!("fact {}", MAX_PER_SCOPE + 4));
+        // Other guilds have their own allowance
+        assert_eq!(facts(&list(&conn, 1, Some(200)).unwrap()), ["elsewhere"]);
+    }
+
+    #[test]
+    fn forgets_only_your_own_facts() {
+        let conn = store();
+        let mine = add(&conn, 1, 100, "mine", Source::User).unwrap();
+        let theirs = add(&conn, 2, 100, "theirs", Source::User).unwrap();
+
+        assert!(!forget(&conn, 1, theirs).unwrap());
+        assert_eq!(facts(&list(&conn, 2, None).unwrap()), ["theirs"]);
+
+        assert!(forget(&conn, 1, mine).unwrap());
+        assert!(!forget(&conn, 1, mine).unwrap());
+        assert!(list(&conn, 1, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn forgets_everything_everywhere() {
+        let conn = store();
+        add(&conn, 1, 100, "one", Source::User).unwrap();
+        add(&conn, 1, 200, "two", Source::Model).unwrap();
+        add(&conn, 2, 100, "theirs", Source::User).unwrap();
+
+        assert_eq!(forget_all(&conn, 1).unwrap(), 2);
+        assert!(list(&conn, 1, None).unwrap().is_empty());
+        assert_eq!(list(&conn, 2, None).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn shows_every_fact_while_there_are_few() {
+        let conn = store();
+        add(&conn, 1, 100, "uses NixOS", Source::User).unwrap();
+        add(&conn, 1, 100, "has a cat", Source::User).unwrap();
+
+        let relevant = relevant(&conn, 1, 100, "nothing in common").unwrap();
+        assert_eq!(facts(&relevant), ["uses NixOS", "has a cat"]);
+    }
+
+    #[test]
+    fn ranks_facts_by_shared_words() {
+        let conn = store();
+        add(&conn, 1, 100, "writes Rust for work", Source::User).unwrap();
+        add(&conn, 1, 100, "prefers Rust with tokio", Source::User).unwrap();
+        for i in 0..MAX_IN_PROMPT {
+            add(&conn, 1, 100, &format!("unrelated fact {}", i), Source::User).unwrap();
+        }
+
+        let relevant = relevant(&conn, 1, 100, "How do I use tokio in Rust?").unwrap();
+        assert_eq!(relevant.len(), MAX_IN_PROMPT);
+        assert_eq!(facts(&relevant[..2]), ["prefers Rust with tokio", "writes Rust for work"]);
+        // The rest tie at nothing in common, newest first
+        assert_eq!(relevant[2].fact, format!("unrelated fact {}", MAX_IN_PROMPT - 1));
+        assert_eq!(relevant[MAX_IN_PROMPT - 1].fact, "unrelated fact 2");
+    }
+
+    #[test]
+    fn ignores_short_and_common_words() {
+        assert_eq!(words("How do I use the API for this?"), HashSet::from(["api".to_string()]));
+    }
+}
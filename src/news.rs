@@ -0,0 +1,231 @@
+use std::time::Duration;
+
+use futures_util::future::join_all;
+
+use crate::config::NewsFeed;
+
+#[derive(Debug, Clone)]
+pub struct Headline {
+    pub title: String,
+    pub link: String,
+    pub description: String,
+}
+
+// Newest `limit` items from one RSS or Atom feed
+pub async fn fetch_headlines(client: &reqwest::Client, url: &str, limit: usize) -> Result<Vec<Headline>, Box<dyn std::error::Error + Send + Sync>> {
+    let response = client
+        .get(url)
+        .timeout(Duration::from_secs(30))
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+
+    let mut headlines = match rss::Channel::read_from(&response[..]) {
+        Ok(channel) => channel
+            .items()
+            .iter()
+            .filter_map(|item| {
+                Some(Headline {
+                    title: item.title()?.trim().to_string(),
+                    link: item.link().unwrap_or_default().to_string(),
+                    description: item.description().map(strip_tags).unwrap_or_default(),
+                })
+            })
+            .collect::<Vec<_>>(),
+        // Not RSS; try Atom before giving up
+        Err(rss_error) => {
+            let feed = atom_syndication::Feed::read_from(&response[..])
+                .map_err(|atom_error| format!("neither RSS ({}) nor Atom ({})", rss_error, atom_error))?;
+            feed.entries()
+                .iter()
+                .map(|entry| Headline {
+                    title: entry.title().trim().to_string(),
+                    link: entry
+                        .links()
+                        .iter()
+                        .find(|link| link.rel() == "alternate")
+                        .or_else(|| entry.links().first())
+                        .map(|link| link.href().to_string())
+                        .unwrap_or_default(),
+                    description: entry
+                        .summary()
+                        .map(|summary| strip_tags(summary))
+                        .unwrap_or_default(),
+                })
+                .filter(|headline| !headline.title.is_empty())
+                .collect()
+        }
+    };
+
+    headlines.truncate(limit);
+    Ok(headlines)
+}
+
+// Headlines from every URL of `feed`, fetched side by side. A URL that fails is logged and
+// skipped; it's only an error when none of them answer.
+pub async fn fetch_feed(client: &reqwest::Client, feed: &NewsFeed, limit: usize) -> Result<Vec<Headline>, Box<dyn std::error::Error + Send + Sync>> {
+    let results = join_all(feed.urls.iter().map(|url| fetch_headlines(client, url, limit))).await;
+
+    let mut headlines = Vec::new();
+    let mut last_error = None;
+    for (url, result) in feed.urls.iter().zip(results) {
+        match result {
+            Ok(mut found) => headlines.append(&mut found),
+            Err(e) => {
+                eprintln!("Failed to fetch feed {}: {:?}", url, e);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    match last_error {
+        Some(e) if headlines.is_empty() => Err(e),
+        _ => Ok(headlines),
+    }
+}
+
+// The model writes the article body from the headline and standfirst, the way a phone
+// keyboard "autocompletes" a sentence
+pub fn article_prompt(outlet: &str, headline: &Headline) -> String {
+    format!(
+        "Write the news article published by {} under this headline, in their usual style.\n\nHeadline: {}\n{}\n\nWrite only the article text, a few short paragraphs.",
+        outlet, headline.title, headline.description
+    )
+}
+
+// Feed descriptions often carry HTML; the model only needs the words
+fn strip_tags(html: &str) -> String {
+    let mut text = String::new();
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            _ if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const RSS: &str = r#"<?xml version="1.0"?>
+<rss version="2.0"><channel><title>Test</title><link>http://example.com</link><description>Test feed</description>
+<item><title> First story </title><link>http://example.com/1</link><description>&lt;p&gt;The &lt;b&gt;first&lt;/b&gt; one&lt;/p&gt;</description></item>
+<item><title>Second story</title><link>http://example.com/2</link></item>
+<item><title>Third story</title><link>http://example.com/3</link></item>
+</channel></rss>"#;
+
+    const ATOM: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom"><title>Test</title><id>urn:test</id><updated>2024-01-01T00:00:00Z</updated>
+<entry><title>Atom story</title><id>urn:1</id><updated>2024-01-01T00:00:00Z</updated>
+<link rel="self" href="http://example.com/self"/><link rel="alternate" href="http://example.com/atom"/>
+<summary>A &lt;i&gt;short&lt;/i&gt; summary</summary></entry>
+</feed>"#;
+
+    // Serves the fixtures over plain HTTP on a free local port: /rss, /atom, and /broken (a
+    // 200 that isn't a feed). Anything else is a 404.
+    async fn serve() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(_) => return,
+                };
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buffer = [0; 1024];
+                    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                        match socket.read(&mut buffer).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(read) => request.extend_from_slice(&buffer[..read]),
+                        }
+                    }
+
+                    let request = String::from_utf8_lossy(&request);
+                    let path = request.split_whitespace().nth(1).unwrap_or("/");
+                    let (status, body) = match path {
+                        "/rss" => ("200 OK", RSS),
+                        "/atom" => ("200 OK", ATOM),
+                        "/broken" => ("200 OK", "<html>not a feed</html>"),
+                        _ => ("404 Not Found", "not found"),
+                    };
+                    let response = format!(
+                        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        body
+                    );
+                    socket.write_all(response.as_bytes()).await.ok();
+                });
+            }
+        });
+
+        format!("http://{}", address)
+    }
+
+    #[tokio::test]
+    async fn reads_rss() {
+        let base = serve().await;
+        let headlines = fetch_headlines(&reqwest::Client::new(), &format!("{}/rss", base), 20).await.unwrap();
+
+        let titles: Vec<_> = headlines.iter().map(|h| h.title.as_str()).collect();
+        assert_eq!(titles, ["First story", "Second story", "Third story"]);
+        assert_eq!(headlines[0].link, "http://example.com/1");
+        assert_eq!(headlines[0].description, "The first one");
+    }
+
+    #[tokio::test]
+    async fn reads_atom() {
+        let base = serve().await;
+        let headlines = fetch_headlines(&reqwest::Client::new(), &format!("{}/atom", base), 20).await.unwrap();
+
+        assert_eq!(headlines.len(), 1);
+        assert_eq!(headlines[0].title, "Atom story");
+        assert_eq!(headlines[0].link, "http://example.com/atom");
+        assert_eq!(headlines[0].description, "A short summary");
+    }
+
+    #[tokio::test]
+    async fn keeps_newest_per_feed() {
+        let base = serve().await;
+        let headlines = fetch_headlines(&reqwest::Client::new(), &format!("{}/rss", base), 2).await.unwrap();
+
+        let titles: Vec<_> = headlines.iter().map(|h| h.title.as_str()).collect();
+        assert_eq!(titles, ["First story", "Second story"]);
+    }
+
+    #[tokio::test]
+    async fn missing_or_broken_feeds_fail() {
+        let base = serve().await;
+        let client = reqwest::Client::new();
+
+        assert!(fetch_headlines(&client, &format!("{}/gone", base), 20).await.is_err());
+        assert!(fetch_headlines(&client, &format!("{}/broken", base), 20).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn feed_skips_failing_urls() {
+        let base = serve().await;
+        let client = reqwest::Client::new();
+        let feed = |paths: &[&str]| NewsFeed {
+            name: "Test".to_string(),
+            urls: paths.iter().map(|path| format!("{}{}", base, path)).collect(),
+        };
+
+        let headlines = fetch_feed(&client, &feed(&["/gone", "/rss", "/atom"]), 2).await.unwrap();
+        assert_eq!(headlines.len(), 3);
+
+        assert!(fetch_feed(&client, &feed(&["/gone", "/broken"]), 2).await.is_err());
+    }
+}
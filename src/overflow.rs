@@ -0,0 +1,290 @@
+use std::borrow::Cow;
+
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::Deserialize;
+use serenity::http::Http;
+use serenity::model::channel::{AttachmentType, ChannelType, Message};
+use serenity::model::id::ChannelId;
+
+use crate::split::{self, MAX_LENGTH};
+
+// Preview shown in the channel when an answer goes to a thread or a file
+pub const PREVIEW_LENGTH: usize = 400;
+// Discord's limit on thread names
+const THREAD_NAME_LENGTH: usize = 100;
+// Archive the thread after a day of silence
+const THREAD_ARCHIVE_MINUTES: u16 = 1440;
+
+// What to do with an answer too long for a single message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum OverflowPolicy {
+    // Keep posting follow-up messages in the channel
+    #[serde(rename = "messages")]
+    Messages,
+    // Post a preview, start a thread on it and carry on in there
+    #[serde(rename = "thread")]
+    Thread,
+    // Post a preview with the whole answer attached
+    #[serde(rename = "file")]
+    File,
+}
+
+impl OverflowPolicy {
+    pub fn parse(value: &str) -> Option<OverflowPolicy> {
+        match value.trim().to_lowercase().as_str() {
+            "messages" | "message" => Some(OverflowPolicy::Messages),
+            "thread" | "threads" => Some(OverflowPolicy::Thread),
+            "file" | "files" | "attachment" => Some(OverflowPolicy::File),
+            _ => None,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            OverflowPolicy::Messages => "messages",
+            OverflowPolicy::Thread => "thread",
+            OverflowPolicy::File => "file",
+        }
+    }
+}
+
+pub fn create_table(conn: &Connection) -> Result<(), rusqlite::Error> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS overflow_policies (
+            guild_id INTEGER PRIMARY KEY,
+            policy TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+pub fn get_policy(conn: &Connection, guild_id: u64) -> Result<Option<OverflowPolicy>, rusqlite::Error> {
+    let policy: Option<String> = conn
+        .query_row(
+            "SELECT policy FROM overflow_policies WHERE guild_id = ?1",
+            params![guild_id as i64],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    Ok(policy.as_deref().and_then(OverflowPolicy::parse))
+}
+
+pub fn set_policy(conn: &Connection, guild_id: u64, policy: OverflowPolicy) -> Result<(), rusqlite::Error> {
+    conn.execute(
+        "INSERT OR REPLACE INTO overflow_policies (guild_id, policy) VALUES (?1, ?2)",
+        params![guild_id as i64, policy.label()],
+    )?;
+
+    Ok(())
+}
+
+// Replies to `msg` with `text`, applying `policy` when it doesn't fit in one message.
+// Threads can't be made in DMs or inside other threads; those fall back to messages.
+pub async fn send(http: &Http, msg: &Message, text: &str, policy: OverflowPolicy) -> serenity::Result<()> {
+    if text.trim().chars().count() <= MAX_LENGTH || policy == OverflowPolicy::Messages {
+        return send_messages(http, msg, msg.channel_id, split::split_message(text, MAX_LENGTH), true).await;
+    }
+
+    let (preview, rest) = preview(text.trim(), PREVIEW_LENGTH);
+
+    match policy {
+        OverflowPolicy::File => {
+            let (filename, contents) = attachment(text);
+            msg.channel_id
+                .send_message(http, |m| {
+                    m.reference_message(msg)
+                        .content(format!("{}\n\n*(the full answer is attached)*", preview))
+                        .add_file(AttachmentType::Bytes { data: Cow::Owned(contents.into_bytes()), filename })
+                })
+                .await?;
+            Ok(())
+        }
+        _ => {
+            let first = msg.reply(http, format!("{}\n\n*(continued in the thread)*", preview)).await?;
+            match start_thread(http, &first, text).await {
+                Some(thread) => send_messages(http, msg, thread, split::split_message(&rest, MAX_LENGTH), false).await,
+                None => send_messages(http, msg, msg.channel_id, split::split_message(&rest, MAX_LENGTH), false).await,
+            }
+        }
+    }
+}
+
+async fn send_messages(http: &Http, msg: &Message, channel: ChannelId, chunks: Vec<String>, reply_first: bool) -> serenity::Result<()> {
+    for (i, chunk) in chunks.iter().enumerate() {
+        if i == 0 && reply_first {
+            // First message should be a reply to create the thread
+            msg.reply(http, chunk).await?;
+        } else {
+            channel.say(http, chunk).await?;
+        }
+    }
+    Ok(())
+}
+
+// Opens a public thread on `message`, named after the start of `text`. None if Discord won't
+// allow one here.
+pub async fn start_thread(http: &Http, message: &Message, text: &str) -> Option<ChannelId> {
+    let result = message
+        .channel_id
+        .create_public_thread(http, message.id, |t| {
+            t.name(thread_name(text))
+                .kind(ChannelType::PublicThread)
+                .auto_archive_duration(THREAD_ARCHIVE_MINUTES)
+        })
+        .await;
+
+    match result {
+        Ok(thread) => Some(thread.id),
+        Err(e) => {
+            eprintln!("Couldn't start a thread, falling back to messages: {:?}", e);
+            None
+        }
+    }
+}
+
+fn thread_name(text: &str) -> String {
+    let first_line = text
+        .lines()
+        .map(|line| line.trim_matches(|c: char| c.is_whitespace() || c == '#' || c == '*' || c == '`'))
+        .find(|line| !line.is_empty())
+        .unwrap_or("egghead's answer");
+
+    match first_line.char_indices().nth(THREAD_NAME_LENGTH - 3) {
+        Some((i, _)) => format!("{}...", &first_line[..i]),
+        None => first_line.to_string(),
+    }
+}
+
+// The start of `text` for the preview message and what follows it, with a code block cut
+// at the boundary closed off in the preview and reopened in the rest
+pub fn preview(text: &str, max_chars: usize) -> (String, String) {
+    let (head_end, tail_start) = split::break_point(text, max_chars - split::FENCE_RESERVE);
+    let mut head = text[..head_end].trim_end().to_string();
+    let rest = &text[tail_start..];
+
+    let rest = match split::close_fence(&mut head) {
+        Some(opener) => format!("{}\n{}", opener, rest.trim_start_matches(['\n', '\r'])),
+        None => rest.trim_start().to_string(),
+    };
+
+    (head, rest)
+}
+
+// Filename and contents for the file policy. An answer that's nothing but one code block is
+// attached as that code, with a matching extension; anything else as markdown.
+pub fn attachment(text: &str) -> (String, String) {
+    let trimmed = text.trim();
+
+    if let Some(body) = trimmed.strip_prefix("```").and_then(|t| t.strip_suffix("```")) {
+        if !body.contains("```") {
+            let (language, code) = body.split_once('\n').unwrap_or(("", body));
+            return (format!("answer.{}", extension(language.trim())), code.to_string());
+        }
+    }
+
+    ("answer.md".to_string(), trimmed.to_string())
+}
+
+fn extension(language: &str) -> &'static str {
+    match language.to_lowercase().as_str() {
+        "rust" | "rs" => "rs",
+        "python" | "py" => "py",
+        "javascript" | "js" => "js",
+        "typescript" | "ts" => "ts",
+        "go" | "golang" => "go",
+        "c" => "c",
+        "cpp" | "c++" => "cpp",
+        "csharp" | "cs" | "c#" => "cs",
+        "java" => "java",
+        "kotlin" | "kt" => "kt",
+        "ruby" | "rb" => "rb",
+        "bash" | "sh" | "shell" | "zsh" => "sh",
+        "nix" => "nix",
+        "html" => "html",
+        "css" => "css",
+        "json" => "json",
+        "toml" => "toml",
+        "yaml" | "yml" => "yaml",
+        "sql" => "sql",
+        "lua" => "lua",
+        _ => "txt",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preview_cuts_at_a_break() {
+        let text = format!("{}\n\n{}", "a".repeat(300), "b".repeat(500));
+        let (head, rest) = preview(&text, PREVIEW_LENGTH);
+        assert_eq!(head, "a".repeat(300));
+        assert_eq!(rest, "b".repeat(500));
+
+        let (head, rest) = preview("short answer", PREVIEW_LENGTH);
+        assert_eq!(head, "short answer");
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn preview_fits_with_multibyte_text() {
+        let text = "é".repeat(PREVIEW_LENGTH * 2);
+        let (head, rest) = preview(&text, PREVIEW_LENGTH);
+        assert!(head.chars().count() <= PREVIEW_LENGTH);
+        assert_eq!(format!("{}{}", head, rest), text);
+    }
+
+    #[test]
+    fn preview_closes_and_reopens_code() {
+        let text = format!("Here:\n\n```rust\n{}", "let x = 1;\n".repeat(100));
+        let (head, rest) = preview(&text, PREVIEW_LENGTH);
+
+        assert!(head.chars().count() <= PREVIEW_LENGTH);
+        assert!(head.starts_with("Here:\n\n```rust\n"));
+        assert!(head.ends_with("\n```"));
+        assert!(rest.starts_with("```rust\nlet x = 1;"));
+    }
+
+    #[test]
+    fn names_threads_after_the_first_line() {
+        assert_eq!(thread_name("\n\n## **Why the sky is blue**\nBecause..."), "Why the sky is blue");
+        assert_eq!(thread_name("```\n```"), "egghead's answer");
+        assert_eq!(thread_name(""), "egghead's answer");
+
+        let name = thread_name(&"é".repeat(300));
+        assert_eq!(name.chars().count(), THREAD_NAME_LENGTH);
+        assert!(name.ends_with("..."));
+        assert_eq!(thread_name(&"x".repeat(THREAD_NAME_LENGTH - 3)), "x".repeat(THREAD_NAME_LENGTH - 3));
+    }
+
+    #[test]
+    fn maps_languages_to_extensions() {
+        assert_eq!(extension("rust"), "rs");
+        assert_eq!(extension("Python"), "py");
+        assert_eq!(extension("c++"), "cpp");
+        assert_eq!(extension(""), "txt");
+        assert_eq!(extension("brainfuck"), "txt");
+    }
+
+    #[test]
+    fn attaches_a_lone_code_block_as_code() {
+        assert_eq!(
+            attachment("\n```python\nprint('hi')\n```\n"),
+            ("answer.py".to_string(), "print('hi')\n".to_string())
+        );
+        assert_eq!(attachment("```\nplain\n```"), ("answer.txt".to_string(), "plain\n".to_string()));
+    }
+
+    #[test]
+    fn attaches_anything_else_as_markdown() {
+        let text = "Some prose\n\n```rust\nfn main() {}\n```";
+        assert_eq!(attachment(text), ("answer.md".to_string(), text.to_string()));
+
+        let two_blocks = "```rust\nfn a() {}\n```\n\n```rust\nfn b() {}\n```";
+        assert_eq!(attachment(two_blocks), ("answer.md".to_string(), two_blocks.to_string()));
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+
+use rusqlite::{Connection, params};
+use serenity::model::channel::Message;
+use serenity::model::guild::Role;
+use serenity::model::id::{GuildId, RoleId, UserId};
+use serenity::model::permissions::Permissions;
+use serenity::prelude::*;
+
+use crate::db;
+use crate::{BotOwners, DatabasePath};
+
+// Who may run a command. Each level includes the ones below it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Everyone,
+    // Members of one of the guild's admin roles (see `e.perms`)
+    Admin,
+    // Whoever runs egghead: the application's owners and `permissions.owners`
+    Owner,
+}
+
+impl Level {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Level::Everyone => "everyone",
+            Level::Admin => "admin",
+            Level::Owner => "owner",
+        }
+    }
+}
+
+// The roles whose members count as admins, per guild
+pub fn create_table(conn: &Connection) -> Result<(), rusqlite::Error> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS admin_roles (
+            guild_id INTEGER NOT NULL,
+            role_id INTEGER NOT NULL,
+            PRIMARY KEY (guild_id, role_id)
+        )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+pub fn admin_roles(conn: &Connection, guild_id: u64) -> Result<Vec<u64>, rusqlite::Error> {
+    let mut stmt = conn.prepare("SELECT role_id FROM admin_roles WHERE guild_id = ?1 ORDER BY role_id")?;
+    let roles = stmt
+        .query_map(params![guild_id as i64], |row| row.get::<_, i64>(0).map(|id| id as u64))?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(roles)
+}
+
+// False if the role was already an admin role
+pub fn add_admin_role(conn: &Connection, guild_id: u64, role_id: u64) -> Result<bool, rusqlite::Error> {
+    let inserted = conn.execute(
+        "INSERT OR IGNORE INTO admin_roles (guild_id, role_id) VALUES (?1, ?2)",
+        params![guild_id as i64, role_id as i64],
+    )?;
+    Ok(inserted > 0)
+}
+
+// False if the role wasn't an admin role
+pub fn remove_admin_role(conn: &Connection, guild_id: u64, role_id: u64) -> Result<bool, rusqlite::Error> {
+    let deleted = conn.execute(
+        "DELETE FROM admin_roles WHERE guild_id = ?1 AND role_id = ?2",
+        params![guild_id as i64, role_id as i64],
+    )?;
+    Ok(deleted > 0)
+}
+
+// The author's level where `msg` was sent
+pub async fn level(ctx: &Context, msg: &Message) -> Level {
+    let roles = msg.member.as_ref().map(|member| member.roles.clone()).unwrap_or_default();
+    level_of(ctx, msg.author.id, msg.guild_id, &roles).await
+}
+
+// A user's level in `guild_id` (None for DMs), given their roles there. In a guild the owner
+// and anyone with Administrator are always admins. Until the guild maps any admin roles, so are
+// members with Manage Server, which is who could change egghead's settings before roles could
+// be mapped.
+pub async fn level_of(ctx: &Context, user_id: UserId, guild_id: Option<GuildId>, roles: &[RoleId]) -> Level {
+    let (owners, db_path) = {
+        let data_read = ctx.data.read().await;
+        (
+            data_read.get::<BotOwners>().expect("Expected BotOwners in TypeMap.").clone(),
+            data_read.get::<DatabasePath>().expect("Expected DatabasePath in TypeMap.").clone(),
+        )
+    };
+
+    if owners.contains(&user_id.0) {
+        return Level::Owner;
+    }
+    let guild_id = match guild_id {
+        Some(guild_id) => guild_id,
+        None => return Level::Everyone,
+    };
+
+    // egghead doesn't ask for guild events, so the guild usually has to be fetched. Without it
+    // the mapped admin roles still count; only the owner and permission checks need it.
+    let guild = match ctx.cache.guild(guild_id) {
+        Some(guild) => Some((guild.owner_id, guild.roles)),
+        None => match guild_id.to_partial_guild(&ctx.http).await {
+            Ok(guild) => Some((guild.owner_id, guild.roles)),
+            Err(e) => {
+                eprintln!("Failed to fetch guild {} for a permission check, going by admin roles alone: {:?}", guild_id, e);
+                None
+            }
+        },
+    };
+
+    let admin_roles = match db::with_connection(db_path, move |conn| admin_roles(conn, guild_id.0)).await {
+        Ok(roles) => Some(roles),
+        Err(e) => {
+            eprintln!("Failed to read admin roles for guild {}: {:?}", guild_id, e);
+            None
+        }
+    };
+
+    let guild = guild.as_ref().map(|(owner_id, roles)| (*owner_id, roles));
+    member_level(user_id, guild_id, roles, guild, admin_roles.as_deref())
+}
+
+// The rules behind `level_of`, given the guild's owner and roles and its mapped admin roles
+// (None where either couldn't be read)
+fn member_level(
+    user_id: UserId,
+    guild_id: GuildId,
+    roles: &[RoleId],
+    guild: Option<(UserId, &HashMap<RoleId, Role>)>,
+    admin_roles: Option<&[u64]>,
+) -> Level {
+    let mut permissions = Permissions::empty();
+    if let Some((owner_id, guild_roles)) = guild {
+        if owner_id == user_id {
+            return Level::Admin;
+        }
+        permissions = role_permissions(guild_roles, guild_id, roles);
+        if permissions.administrator() {
+            return Level::Admin;
+        }
+    }
+
+    let is_admin = match admin_roles {
+        Some([]) => permissions.manage_guild(),
+        Some(admin_roles) => roles.iter().any(|role| admin_roles.contains(&role.0)),
+        None => false,
+    };
+
+    if is_admin {
+        Level::Admin
+    } else {
+        Level::Everyone
+    }
+}
+
+// What someone below `required` is told when they try to `action`
+pub fn refusal(required: Level, action: &str) -> String {
+    match required {
+        Level::Owner => format!("Only egghead's owners can {}.", action),
+        _ => format!("Only server admins can {}. `e.perms` shows who they are.", action),
+    }
+}
+
+// Guild-wide permissions from @everyone plus the member's roles
+fn role_permissions(guild_roles: &HashMap<RoleId, Role>, guild_id: GuildId, member_roles: &[RoleId]) -> Permissions {
+    std::iter::once(&RoleId(guild_id.0))
+        .chain(member_roles)
+        .filter_map(|role_id| guild_roles.get(role_id))
+        .fold(Permissions::empty(), |permissions, role| permissions | role.permissions)
+}
+
+// A role by mention, id or (case-insensitive) name
+pub fn find_role<'a>(guild_roles: &'a HashMap<RoleId, Role>, value: &str) -> Option<&'a Role> {
+    let value = value.trim();
+    let id = serenity::utils::parse_role(value).or_else(|| value.parse().ok());
+    if let Some(role) = id.and_then(|id| guild_roles.get(&RoleId(id))) {
+        return Some(role);
+    }
+
+    let name = value.trim_start_matches('@');
+    guild_roles.values().find(|role| role.name.eq_ignore_ascii_case(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUILD: GuildId = GuildId(1);
+    const OWNER: UserId = UserId(10);
+    const MEMBER: UserId = UserId(20);
+
+    fn role(id: u64, name: &str, permissions: Permissions) -> Role {
+        serde_json::from_value(serde_json::json!({
+            "id": id.to_string(),
+            "guild_id": GUILD.0.to_string(),
+            "name": name,
+            "permissions": permissions.bits().to_string(),
+            "color": 0,
+            "hoist": false,
+            "managed": false,
+            "mentionable": true,
+            "position": 0,
+        }))
+        .unwrap()
+    }
+
+    // @everyone, Moderators (Manage Server), Admins (Administrator) and Artists (nothing)
+    fn guild_roles() -> HashMap<RoleId, Role> {
+        [
+            role(GUILD.0, "@everyone", Permissions::SEND_MESSAGES),
+            role(2, "Moderators", Permissions::MANAGE_GUILD),
+            role(3, "Admins", Permissions::ADMINISTRATOR),
+            role(4, "Artists", Permissions::empty()),
+        ]
+        .into_iter()
+        .map(|role| (role.id, role))
+        .collect()
+    }
+
+    #[test]
+    fn adds_up_role_permissions() {
+        let roles = guild_roles();
+        assert_eq!(role_permissions(&roles, GUILD, &[]), Permissions::SEND_MESSAGES);
+        assert_eq!(
+            role_permissions(&roles, GUILD, &[RoleId(2), RoleId(4)]),
+            Permissions::SEND_MESSAGES | Permissions::MANAGE_GUILD
+        );
+        // Roles that have since been deleted don't count
+        assert_eq!(role_permissions(&roles, GUILD, &[RoleId(99)]), Permissions::SEND_MESSAGES);
+    }
+
+    #[test]
+    fn finds_roles_by_mention_id_or_name() {
+        let roles = guild_roles();
+        let found = |value| find_role(&roles, value).map(|role| role.id.0);
+
+        assert_eq!(found("<@&2>"), Some(2));
+        assert_eq!(found(" 3 "), Some(3));
+        assert_eq!(found("artists"), Some(4));
+        assert_eq!(found("@Moderators"), Some(2));
+        assert_eq!(found("<@&99>"), None);
+        assert_eq!(found("Nobody"), None);
+    }
+
+    #[test]
+    fn owners_and_administrators_are_admins() {
+        let roles = guild_roles();
+        let guild = Some((OWNER, &roles));
+
+        assert_eq!(member_level(OWNER, GUILD, &[], guild, Some(&[4])), Level::Admin);
+        assert_eq!(member_level(MEMBER, GUILD, &[RoleId(3)], guild, Some(&[4])), Level::Admin);
+        assert_eq!(member_level(MEMBER, GUILD, &[], guild, Some(&[4])), Level::Everyone);
+    }
+
+    #[test]
+    fn manage_server_counts_until_admin_roles_are_mapped() {
+        let roles = guild_roles();
+        let guild = Some((OWNER, &roles));
+        let moderator = [RoleId(2)];
+
+        assert_eq!(member_level(MEMBER, GUILD, &moderator, guild, Some(&[])), Level::Admin);
+        // Once a role is mapped, only its members are admins
+        assert_eq!(member_level(MEMBER, GUILD, &moderator, guild, Some(&[4])), Level::Everyone);
+        assert_eq!(member_level(MEMBER, GUILD, &[RoleId(4)], guild, Some(&[4])), Level::Admin);
+        // Can't tell whether any are mapped, so don't assume not
+        assert_eq!(member_level(MEMBER, GUILD, &moderator, guild, None), Level::Everyone);
+    }
+
+    #[test]
+    fn admin_roles_count_without_the_guild() {
+        assert_eq!(member_level(MEMBER, GUILD, &[RoleId(4)], None, Some(&[4])), Level::Admin);
+        assert_eq!(member_level(MEMBER, GUILD, &[RoleId(2)], None, Some(&[])), Level::Everyone);
+    }
+
+    #[test]
+    fn stores_admin_roles_per_guild() {
+        let conn = Connection::open_in_memory().unwrap();
+        create_table(&conn).unwrap();
+
+        assert!(add_admin_role(&conn, 1, 30).unwrap());
+        assert!(add_admin_role(&conn, 1, 20).unwrap());
+        assert!(!add_admin_role(&conn, 1, 20).unwrap());
+        assert!(add_admin_role(&conn, 2, 40).unwrap());
+        assert_eq!(admin_roles(&conn, 1).unwrap(), vec![20, 30]);
+
+        assert!(remove_admin_role(&conn, 1, 30).unwrap());
+        assert!(!remove_admin_role(&conn, 1, 30).unwrap());
+        assert_eq!(admin_roles(&conn, 1).unwrap(), vec![20]);
+        assert_eq!(admin_roles(&conn, 2).unwrap(), vec![40]);
+    }
+}
@@ -0,0 +1,277 @@
+use rusqlite::{Connection, OptionalExtension, params};
+
+use crate::config::PersonaConfig;
+
+// Where an override is stored. A channel override beats a guild override, which beats the
+// defaults from config.
+#[derive(Debug, Clone, Copy)]
+pub enum Scope {
+    Guild(u64),
+    Channel(u64),
+}
+
+impl Scope {
+    fn key(&self) -> (&'static str, i64) {
+        match self {
+            Scope::Guild(id) => ("guild", *id as i64),
+            Scope::Channel(id) => ("channel", *id as i64),
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        self.key().0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    SystemPrompt,
+    Temperature,
+    Model,
+}
+
+impl Field {
+    pub fn parse(value: &str) -> Option<Field> {
+        match value.to_lowercase().as_str() {
+            "prompt" | "system" | "system_prompt" => Some(Field::SystemPrompt),
+            "temp" | "temperature" => Some(Field::Temperature),
+            "model" => Some(Field::Model),
+            _ => None,
+        }
+    }
+
+    fn column(&self) -> &'static str {
+        match self {
+            Field::SystemPrompt => "system_prompt",
+            Field::Temperature => "temperature",
+            Field::Model => "model",
+        }
+    }
+}
+
+// The values stored for one scope; unset fields fall through to the next scope
+#[derive(Debug, Clone, Default)]
+pub struct PersonaOverride {
+    pub system_prompt: Option<String>,
+    pub temperature: Option<f64>,
+    pub model: Option<String>,
+}
+
+// The effective persona for a request, with where each value came from
+#[derive(Debug, Clone)]
+pub struct Persona {
+    pub system_prompt: String,
+    pub temperature: f64,
+    // None means whatever model the backend is configured with
+    pub model: Option<String>,
+    pub system_prompt_source: &'static str,
+    pub temperature_source: &'static str,
+    pub model_source: &'static str,
+}
+
+impl Persona {
+    pub fn from_defaults(defaults: &PersonaConfig) -> Persona {
+        Persona {
+            system_prompt: defaults.system_prompt.clone(),
+            temperature: defaults.temperature,
+            model: None,
+            system_prompt_source: "default",
+            temperature_source: "default",
+            model_source: "default",
+        }
+    }
+}
+
+pub fn create_table(conn: &Connection) -> Result<(), rusqlite::Error> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS personas (
+            scope TEXT NOT NULL,
+            scope_id INTEGER NOT NULL,
+            system_prompt TEXT,
+            temperature REAL,
+            model TEXT,
+            PRIMARY KEY (scope, scope_id)
+        )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+pub fn get_override(conn: &Connection, scope: Scope) -> Result<Option<PersonaOverride>, rusqlite::Error> {
+    let (scope, scope_id) = scope.key();
+
+    conn.query_row(
+        "SELECT system_prompt, temperature, model FROM personas WHERE scope = ?1 AND scope_id = ?2",
+        params![scope, scope_id],
+        |row| {
+            Ok(PersonaOverride {
+                system_prompt: row.get(0)?,
+                temperature: row.get(1)?,
+                model: row.get(2)?,
+            })
+        },
+    )
+    .optional()
+}
+
+// `value` is stored as-is; callers validate temperatures before getting here
+pub fn set_field(conn: &Connection, scope: Scope, field: Field, value: &str) -> Result<(), rusqlite::Error> {
+    let (scope, scope_id) = scope.key();
+
+    conn.execute(
+        "INSERT OR IGNORE INTO personas (scope, scope_id) VALUES (?1, ?2)",
+        params![scope, scope_id],
+    )?;
+
+    let sql = format!("UPDATE personas SET {} = ?1 WHERE scope = ?2 AND scope_id = ?3", field.column());
+    match field {
+        Field::Temperature => {
+            let temperature: f64 = value.parse().map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+            conn.execute(&sql, params![temperature, scope, scope_id])?;
+        }
+        Field::SystemPrompt | Field::Model => {
+            conn.execute(&sql, params![value, scope, scope_id])?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn reset(conn: &Connection, scope: Scope) -> Result<bool, rusqlite::Error> {
+    let (scope, scope_id) = scope.key();
+
+    let removed = conn.execute(
+        "DELETE FROM personas WHERE scope = ?1 AND scope_id = ?2",
+        params![scope, scope_id],
+    )?;
+
+    Ok(removed > 0)
+}
+
+// Channel, then guild, then config defaults - field by field, so a channel can change just
+// the temperature and still inherit the guild's prompt.
+pub fn resolve(conn: &Connection, guild_id: Option<u64>, channel_id: u64, defaults: &PersonaConfig) -> Result<Persona, rusqlite::Error> {
+    let mut persona = Persona::from_defaults(defaults);
+
+    let mut layers = Vec::new();
+    if let Some(guild_id) = guild_id {
+        layers.push(Scope::Guild(guild_id));
+    }
+    layers.push(Scope::Channel(channel_id));
+
+    // Apply the broadest scope first so narrower ones overwrite it
+    for scope in layers {
+        if let Some(o) = get_override(conn, scope)? {
+            if let Some(system_prompt) = o.system_prompt {
+                persona.system_prompt = system_prompt;
+                persona.system_prompt_source = scope.label();
+            }
+            if let Some(temperature) = o.temperature {
+                persona.temperature = temperature;
+                persona.temperature_source = scope.label();
+            }
+            if let Some(model) = o.model {
+                persona.model = Some(model);
+                persona.model_source = scope.label();
+            }
+        }
+    }
+
+    Ok(persona)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUILD: u64 = 1;
+    const CHANNEL: u64 = 10;
+
+    fn store() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        create_table(&conn).unwrap();
+        conn
+    }
+
+    fn defaults() -> PersonaConfig {
+        PersonaConfig { system_prompt: "You are Egghead.".to_string(), temperature: 0.85 }
+    }
+
+    #[test]
+    fn falls_back_to_the_defaults() {
+        let persona = resolve(&store(), Some(GUILD), CHANNEL, &defaults()).unwrap();
+        assert_eq!(persona.system_prompt, "You are Egghead.");
+        assert_eq!(persona.temperature, 0.85);
+        assert_eq!(persona.model, None);
+        assert_eq!((persona.system_prompt_source, persona.temperature_source, persona.model_source), ("default", "default", "default"));
+    }
+
+    #[test]
+    fn channel_beats_guild() {
+        let conn = store();
+        set_field(&conn, Scope::Guild(GUILD), Field::SystemPrompt, "You are a pirate.").unwrap();
+        set_field(&conn, Scope::Channel(CHANNEL), Field::SystemPrompt, "You are a parrot.").unwrap();
+
+        let persona = resolve(&conn, Some(GUILD), CHANNEL, &defaults()).unwrap();
+        assert_eq!(persona.system_prompt, "You are a parrot.");
+        assert_eq!(persona.system_prompt_source, "channel");
+
+        // Other channels in the guild get the guild's
+        let persona = resolve(&conn, Some(GUILD), CHANNEL + 1, &defaults()).unwrap();
+        assert_eq!(persona.system_prompt, "You are a pirate.");
+        assert_eq!(persona.system_prompt_source, "guild");
+    }
+
+    #[test]
+    fn overrides_field_by_field() {
+        let conn = store();
+        set_field(&conn, Scope::Guild(GUILD), Field::SystemPrompt, "You are a pirate.").unwrap();
+        set_field(&conn, Scope::Guild(GUILD), Field::Temperature, "0.5").unwrap();
+        set_field(&conn, Scope::Channel(CHANNEL), Field::Temperature, "1.2").unwrap();
+        set_field(&conn, Scope::Channel(CHANNEL), Field::Model, "llama3").unwrap();
+
+        let persona = resolve(&conn, Some(GUILD), CHANNEL, &defaults()).unwrap();
+        assert_eq!((persona.system_prompt.as_str(), persona.system_prompt_source), ("You are a pirate.", "guild"));
+        assert_eq!((persona.temperature, persona.temperature_source), (1.2, "channel"));
+        assert_eq!((persona.model.as_deref(), persona.model_source), (Some("llama3"), "channel"));
+    }
+
+    #[test]
+    fn dms_only_have_channel_overrides() {
+        let conn = store();
+        set_field(&conn, Scope::Guild(GUILD), Field::Temperature, "0.1").unwrap();
+        set_field(&conn, Scope::Channel(CHANNEL), Field::Model, "llama3").unwrap();
+
+        let persona = resolve(&conn, None, CHANNEL, &defaults()).unwrap();
+        assert_eq!(persona.temperature_source, "default");
+        assert_eq!(persona.model.as_deref(), Some("llama3"));
+    }
+
+    #[test]
+    fn resets_one_scope() {
+        let conn = store();
+        set_field(&conn, Scope::Guild(GUILD), Field::Model, "mistral").unwrap();
+        set_field(&conn, Scope::Channel(CHANNEL), Field::Model, "llama3").unwrap();
+
+        assert!(reset(&conn, Scope::Channel(CHANNEL)).unwrap());
+        assert!(!reset(&conn, Scope::Channel(CHANNEL)).unwrap());
+
+        let persona = resolve(&conn, Some(GUILD), CHANNEL, &defaults()).unwrap();
+        assert_eq!((persona.model.as_deref(), persona.model_source), (Some("mistral"), "guild"));
+    }
+
+    #[test]
+    fn rejects_temperatures_that_arent_numbers() {
+        let conn = store();
+        assert!(set_field(&conn, Scope::Guild(GUILD), Field::Temperature, "hot").is_err());
+    }
+
+    #[test]
+    fn parses_field_names() {
+        assert_eq!(Field::parse("Prompt"), Some(Field::SystemPrompt));
+        assert_eq!(Field::parse("temp"), Some(Field::Temperature));
+        assert_eq!(Field::parse("model"), Some(Field::Model));
+        assert_eq!(Field::parse("colour"), None);
+    }
+}
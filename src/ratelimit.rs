@@ -0,0 +1,310 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serenity::model::id::{ChannelId, GuildId, RoleId, UserId};
+use serenity::prelude::*;
+
+use crate::config::{BucketConfig, RateLimitConfig};
+use crate::RateLimits;
+
+// Buckets kept before full ones (idle long enough to have refilled) are thrown away
+const MAX_BUCKETS: usize = 10_000;
+
+// Which budget a request draws from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Kind {
+    Chat,
+    Dream,
+}
+
+impl Kind {
+    // The budget a prefix command draws from; None for commands that don't touch the model.
+    // `news` only does with a feed named, so it checks for itself.
+    pub fn for_command(name: &str) -> Option<Kind> {
+        match name {
+            "dream" => Some(Kind::Dream),
+            "ask" | "read" | "code" | "right" | "left" | "green" | "tldr" | "react" => Some(Kind::Chat),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Scope {
+    User(u64),
+    Channel(u64),
+    Guild(u64),
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: &BucketConfig, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_minute / 60.0).min(limit.burst as f64);
+        self.updated = now;
+    }
+
+    // How long until there's a whole token to spend
+    fn wait(&self, limit: &BucketConfig) -> Duration {
+        if self.tokens >= 1.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64((1.0 - self.tokens) * 60.0 / limit.per_minute)
+    }
+}
+
+// Token buckets per user, channel and guild, one set each for chat and dreams. A request
+// goes through only if every bucket it touches has a token, and then takes one from each.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<HashMap<(Kind, Scope), Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> RateLimiter {
+        RateLimiter {
+            config: config.clone(),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Takes a token for the request, or says how long until it could have one
+    pub fn take(&self, kind: Kind, user_id: u64, channel_id: u64, guild_id: Option<u64>) -> Result<(), Duration> {
+        self.take_at(kind, user_id, channel_id, guild_id, Instant::now())
+    }
+
+    fn take_at(&self, kind: Kind, user_id: u64, channel_id: u64, guild_id: Option<u64>, now: Instant) -> Result<(), Duration> {
+        if !self.config.enabled {
+            return Ok(());
+        }
+
+        let budgets = match kind {
+            Kind::Chat => &self.config.chat,
+            Kind::Dream => &self.config.dream,
+        };
+        let mut scopes = vec![(Scope::User(user_id), &budgets.user), (Scope::Channel(channel_id), &budgets.channel)];
+        if let Some(guild_id) = guild_id {
+            scopes.push((Scope::Guild(guild_id), &budgets.guild));
+        }
+        scopes.retain(|(_, limit)| limit.is_limited());
+
+        let mut buckets = self.buckets.lock().unwrap();
+
+        let mut wait = Duration::ZERO;
+        for (scope, limit) in &scopes {
+            let bucket = buckets.entry((kind, *scope)).or_insert_with(|| Bucket {
+                tokens: limit.burst as f64,
+                updated: now,
+            });
+            bucket.refill(limit, now);
+            wait = wait.max(bucket.wait(limit));
+        }
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+
+        for (scope, _) in &scopes {
+            if let Some(bucket) = buckets.get_mut(&(kind, *scope)) {
+                bucket.tokens -= 1.0;
+            }
+        }
+
+        if buckets.len() > MAX_BUCKETS {
+            let config = &self.config;
+            buckets.retain(|(kind, scope), bucket| {
+                let budgets = match kind {
+                    Kind::Chat => &config.chat,
+                    Kind::Dream => &config.dream,
+                };
+                let limit = match scope {
+                    Scope::User(_) => &budgets.user,
+                    Scope::Channel(_) => &budgets.channel,
+                    Scope::Guild(_) => &budgets.guild,
+                };
+                bucket.refill(limit, now);
+                bucket.tokens < limit.burst as f64
+            });
+        }
+
+        Ok(())
+    }
+}
+
+// Takes a `kind` request out of the budgets it touches, or says how long until it fits. Members
+// with one of the exempt roles skip the check; `roles` are theirs in `guild_id`.
+pub async fn check(ctx: &Context, kind: Kind, user_id: UserId, channel_id: ChannelId, guild_id: Option<GuildId>, roles: &[RoleId]) -> Result<(), Duration> {
+    let limiter = {
+        let data_read = ctx.data.read().await;
+        data_read.get::<RateLimits>().expect("Expected RateLimits in TypeMap.").clone()
+    };
+
+    if let Some(guild_id) = guild_id {
+        if is_exempt(ctx, &limiter.config.exempt_roles, guild_id, roles).await {
+            return Ok(());
+        }
+    }
+
+    let result = limiter.take(kind, user_id.0, channel_id.0, guild_id.map(|id| id.0));
+    if let Err(wait) = &result {
+        println!("Rate limited {:?} request from user {} ({}s to wait)", kind, user_id, wait.as_secs());
+    }
+    result
+}
+
+// Exempt roles can be given by id or, so one config fits every guild, by name. egghead doesn't
+// ask for guild events, so names usually aren't cached and come from the API instead.
+async fn is_exempt(ctx: &Context, exempt_roles: &[String], guild_id: GuildId, roles: &[RoleId]) -> bool {
+    if exempt_roles.is_empty() || roles.is_empty() {
+        return false;
+    }
+    if roles.iter().any(|role_id| exempt_roles.contains(&role_id.0.to_string())) {
+        return true;
+    }
+
+    let names: Vec<String> = match roles.iter().map(|role_id| ctx.cache.role(guild_id, *role_id)).collect::<Option<Vec<_>>>() {
+        Some(cached) => cached.into_iter().map(|role| role.name).collect(),
+        None => match guild_id.roles(&ctx.http).await {
+            Ok(guild_roles) => roles.iter().filter_map(|role_id| guild_roles.get(role_id)).map(|role| role.name.clone()).collect(),
+            Err(e) => {
+                eprintln!("Failed to fetch roles for guild {}: {:?}", guild_id, e);
+                return false;
+            }
+        },
+    };
+
+    names.iter().any(|name| exempt_roles.iter().any(|exempt| name.eq_ignore_ascii_case(exempt)))
+}
+
+pub fn slow_down(wait: Duration) -> String {
+    format!("Slow down a little! Try again in {}s.", wait.as_secs().max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RateBudgets;
+
+    fn bucket(burst: u32, per_minute: f64) -> BucketConfig {
+        BucketConfig { burst, per_minute }
+    }
+
+    fn chat_limiter(chat: RateBudgets) -> RateLimiter {
+        RateLimiter::new(&RateLimitConfig {
+            chat,
+            ..RateLimitConfig::default()
+        })
+    }
+
+    fn user_only(burst: u32, per_minute: f64) -> RateBudgets {
+        RateBudgets { user: bucket(burst, per_minute), ..RateBudgets::default() }
+    }
+
+    #[test]
+    fn allows_a_burst_then_waits() {
+        let limiter = chat_limiter(user_only(3, 6.0));
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.take_at(Kind::Chat, 1, 10, Some(100), now).is_ok());
+        }
+        // One token every 10s
+        assert_eq!(limiter.take_at(Kind::Chat, 1, 10, Some(100), now), Err(Duration::from_secs(10)));
+        // Someone else has their own bucket
+        assert!(limiter.take_at(Kind::Chat, 2, 10, Some(100), now).is_ok());
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let limiter = chat_limiter(user_only(2, 6.0));
+        let start = Instant::now();
+        limiter.take_at(Kind::Chat, 1, 10, None, start).unwrap();
+        limiter.take_at(Kind::Chat, 1, 10, None, start).unwrap();
+
+        let later = start + Duration::from_secs(4);
+        assert_eq!(limiter.take_at(Kind::Chat, 1, 10, None, later), Err(Duration::from_secs(6)));
+
+        let later = start + Duration::from_secs(10);
+        assert!(limiter.take_at(Kind::Chat, 1, 10, None, later).is_ok());
+        assert!(limiter.take_at(Kind::Chat, 1, 10, None, later).is_err());
+
+        // Never refills past the burst
+        let much_later = start + Duration::from_secs(3600);
+        assert!(limiter.take_at(Kind::Chat, 1, 10, None, much_later).is_ok());
+        assert!(limiter.take_at(Kind::Chat, 1, 10, None, much_later).is_ok());
+        assert!(limiter.take_at(Kind::Chat, 1, 10, None, much_later).is_err());
+    }
+
+    #[test]
+    fn must_fit_every_scope() {
+        let limiter = chat_limiter(RateBudgets {
+            user: bucket(5, 60.0),
+            channel: bucket(2, 6.0),
+            guild: BucketConfig::default(),
+        });
+        let now = Instant::now();
+
+        assert!(limiter.take_at(Kind::Chat, 1, 10, Some(100), now).is_ok());
+        assert!(limiter.take_at(Kind::Chat, 2, 10, Some(100), now).is_ok());
+        // User 3 has tokens to spare, but the channel is out
+        assert_eq!(limiter.take_at(Kind::Chat, 3, 10, Some(100), now), Err(Duration::from_secs(10)));
+        assert!(limiter.take_at(Kind::Chat, 3, 11, Some(100), now).is_ok());
+
+        // A refused request takes nothing from the buckets that had room
+        let limiter = limiter_with_user_and_channel();
+        assert!(limiter.take_at(Kind::Chat, 1, 10, None, now).is_ok());
+        assert!(limiter.take_at(Kind::Chat, 1, 10, None, now).is_err());
+        assert!(limiter.take_at(Kind::Chat, 1, 11, None, now).is_ok());
+    }
+
+    // The user can make two requests, the channel only one
+    fn limiter_with_user_and_channel() -> RateLimiter {
+        chat_limiter(RateBudgets { user: bucket(2, 1.0), channel: bucket(1, 1.0), ..RateBudgets::default() })
+    }
+
+    #[test]
+    fn chat_and_dream_are_separate() {
+        let limiter = RateLimiter::new(&RateLimitConfig {
+            chat: user_only(1, 1.0),
+            dream: user_only(1, 1.0),
+            ..RateLimitConfig::default()
+        });
+        let now = Instant::now();
+
+        assert!(limiter.take_at(Kind::Chat, 1, 10, None, now).is_ok());
+        assert!(limiter.take_at(Kind::Chat, 1, 10, None, now).is_err());
+        assert!(limiter.take_at(Kind::Dream, 1, 10, None, now).is_ok());
+    }
+
+    #[test]
+    fn disabled_limits_everything_through() {
+        let limiter = RateLimiter::new(&RateLimitConfig {
+            enabled: false,
+            chat: user_only(1, 1.0),
+            ..RateLimitConfig::default()
+        });
+        let now = Instant::now();
+        for _ in 0..10 {
+            assert!(limiter.take_at(Kind::Chat, 1, 10, None, now).is_ok());
+        }
+
+        // An unlimited scope doesn't hold anything up either
+        let limiter = chat_limiter(RateBudgets::default());
+        for _ in 0..10 {
+            assert!(limiter.take_at(Kind::Chat, 1, 10, Some(100), now).is_ok());
+        }
+    }
+
+    #[test]
+    fn maps_commands_to_budgets() {
+        assert_eq!(Kind::for_command("dream"), Some(Kind::Dream));
+        assert_eq!(Kind::for_command("ask"), Some(Kind::Chat));
+        assert_eq!(Kind::for_command("right"), Some(Kind::Chat));
+        assert_eq!(Kind::for_command("help"), None);
+        assert_eq!(Kind::for_command("news"), None);
+    }
+}
@@ -0,0 +1,176 @@
+use serenity::model::channel::ReactionType;
+use serenity::model::guild::Emoji;
+
+// Discord lets anyone add up to 20 reactions, but more than a few from one bot is noise
+pub const MAX_REACTIONS: usize = 3;
+// What we react with when the model can't produce anything usable
+const FALLBACK: &str = "🤔";
+// Custom emoji names listed in the prompt; big servers have hundreds
+const MAX_CUSTOM_IN_PROMPT: usize = 50;
+
+pub fn prompt(author: &str, content: &str, custom: &[Emoji]) -> String {
+    let mut prompt = format!(
+        "React to this chat message with one to three emoji. Answer with the emoji only, separated by spaces.\n\n{}: {}",
+        author, content
+    );
+
+    let names: Vec<String> = custom
+        .iter()
+        .filter(|emoji| emoji.available)
+        .take(MAX_CUSTOM_IN_PROMPT)
+        .map(|emoji| format!(":{}:", emoji.name))
+        .collect();
+    if !names.is_empty() {
+        prompt.push_str(&format!("\n\nThis server's own emoji can be used too: {}", names.join(" ")));
+    }
+
+    prompt
+}
+
+// Pulls up to `MAX_REACTIONS` real emoji out of whatever the model said: Unicode emoji,
+// `:shortcode:`s, and this guild's custom emoji (by `<:name:id>` or `:name:`). Anything else
+// is ignored.
+pub fn parse(output: &str, custom: &[Emoji]) -> Vec<ReactionType> {
+    let mut reactions = Vec::new();
+
+    for token in output.split(|c: char| c.is_whitespace() || c == ',') {
+        if let Some(reaction) = custom_reaction(token, custom) {
+            reactions.push(reaction);
+            continue;
+        }
+
+        if let Some(name) = token.strip_prefix(':').and_then(|t| t.strip_suffix(':')) {
+            if let Some(emoji) = emojis::get_by_shortcode(name) {
+                reactions.push(ReactionType::Unicode(emoji.as_str().to_string()));
+            }
+            continue;
+        }
+
+        reactions.extend(unicode_emoji(token).into_iter().map(ReactionType::Unicode));
+    }
+
+    let mut unique = Vec::new();
+    for reaction in reactions {
+        if !unique.contains(&reaction) {
+            unique.push(reaction);
+        }
+    }
+    unique.truncate(MAX_REACTIONS);
+    unique
+}
+
+// `chosen`, or the fallback when the model never came up with anything
+pub fn or_fallback(chosen: Vec<ReactionType>) -> Vec<ReactionType> {
+    if chosen.is_empty() {
+        vec![ReactionType::Unicode(FALLBACK.to_string())]
+    } else {
+        chosen
+    }
+}
+
+fn custom_reaction(token: &str, custom: &[Emoji]) -> Option<ReactionType> {
+    // <:name:id> or <a:name:id>
+    let emoji = if let Some(inner) = token.strip_prefix('<').and_then(|t| t.strip_suffix('>')) {
+        let id = inner.rsplit(':').next()?.parse::<u64>().ok()?;
+        custom.iter().find(|emoji| emoji.id.0 == id)?
+    } else {
+        let name = token.strip_prefix(':')?.strip_suffix(':')?;
+        custom.iter().find(|emoji| emoji.name == name)?
+    };
+
+    if !emoji.available {
+        return None;
+    }
+
+    Some(ReactionType::Custom {
+        animated: emoji.animated,
+        id: emoji.id,
+        name: Some(emoji.name.clone()),
+    })
+}
+
+// Every emoji in `text`, fully qualified. Takes the longest match at each position so ZWJ
+// sequences, flags and skin tones stay whole.
+fn unicode_emoji(text: &str) -> Vec<String> {
+    let boundaries: Vec<usize> = text.char_indices().map(|(i, _)| i).chain(std::iter::once(text.len())).collect();
+    let mut found = Vec::new();
+    let mut start = 0;
+
+    while start + 1 < boundaries.len() {
+        let longest = (start + 1..boundaries.len())
+            .rev()
+            .find_map(|end| emojis::get(&text[boundaries[start]..boundaries[end]]).map(|emoji| (end, emoji)));
+
+        match longest {
+            Some((end, emoji)) => {
+                found.push(emoji.as_str().to_string());
+                start = end;
+            }
+            None => start += 1,
+        }
+    }
+
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn emoji(id: u64, name: &str, animated: bool, available: bool) -> Emoji {
+        serde_json::from_value(serde_json::json!({
+            "id": id.to_string(),
+            "name": name,
+            "animated": animated,
+            "available": available,
+            "managed": false,
+            "require_colons": true,
+            "roles": []
+        }))
+        .unwrap()
+    }
+
+    fn unicode(emoji: &str) -> ReactionType {
+        ReactionType::Unicode(emoji.to_string())
+    }
+
+    #[test]
+    fn parses_unicode_emoji() {
+        assert_eq!(parse("😂 🔥, 👍", &[]), vec![unicode("😂"), unicode("🔥"), unicode("👍")]);
+        // Run together, with ZWJ sequences and skin tones kept whole
+        assert_eq!(parse("👨‍👩‍👧👍🏽", &[]), vec![unicode("👨‍👩‍👧"), unicode("👍🏽")]);
+        assert_eq!(parse(":joy: lol", &[]), vec![unicode("😂")]);
+    }
+
+    #[test]
+    fn keeps_at_most_three_distinct() {
+        assert_eq!(parse("🔥 🔥 😂 👍 🎉", &[]), vec![unicode("🔥"), unicode("😂"), unicode("👍")]);
+    }
+
+    #[test]
+    fn parses_custom_emoji() {
+        let custom = [emoji(1, "pog", false, true), emoji(2, "dance", true, true)];
+
+        let expected = vec![
+            ReactionType::Custom { animated: false, id: 1.into(), name: Some("pog".to_string()) },
+            ReactionType::Custom { animated: true, id: 2.into(), name: Some("dance".to_string()) },
+        ];
+        assert_eq!(parse("<:pog:1> <a:dance:2>", &custom), expected);
+        assert_eq!(parse(":pog: :dance:", &custom), expected);
+    }
+
+    #[test]
+    fn rejects_emoji_from_elsewhere() {
+        let custom = [emoji(1, "pog", false, true), emoji(3, "gone", false, false)];
+        assert!(parse("<:other:99> <a:pog:98> :nothere:", &custom).is_empty());
+        // Known but unavailable (e.g. the server lost its boost)
+        assert!(parse("<:gone:3> :gone:", &custom).is_empty());
+    }
+
+    #[test]
+    fn falls_back_to_thinking() {
+        assert!(parse("I would react with a laughing face", &[]).is_empty());
+        assert_eq!(or_fallback(Vec::new()), vec![unicode("🤔")]);
+        assert_eq!(or_fallback(vec![unicode("🔥")]), vec![unicode("🔥")]);
+    }
+}
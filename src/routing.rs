@@ -0,0 +1,196 @@
+use crate::config::RoutingConfig;
+use crate::permissions::Level;
+
+// Prompt flag that forces a model for a single request, e.g. `@egghead --model llama3 hi`
+const MODEL_FLAG: &str = "--model";
+// Who may use it: a forced model can be far bigger than the one the server was set up with
+pub const FORCE_MODEL_LEVEL: Level = Level::Admin;
+
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub model: String,
+    // Human-readable explanation, logged and shown by `e.model`
+    pub reason: String,
+}
+
+pub struct Router {
+    vision_model: String,
+    text_model: String,
+    rules: Vec<(String, String)>,
+}
+
+impl Router {
+    // `fallback_model` is the backend's configured model, used for anything routing leaves unset
+    pub fn new(config: &RoutingConfig, fallback_model: &str) -> Router {
+        Router {
+            vision_model: config.vision_model.clone().unwrap_or_else(|| fallback_model.to_string()),
+            text_model: config.text_model.clone().unwrap_or_else(|| fallback_model.to_string()),
+            rules: config
+                .rules
+                .iter()
+                .map(|rule| (rule.keyword.to_lowercase(), rule.model.clone()))
+                .collect(),
+        }
+    }
+
+    pub fn vision_model(&self) -> &str {
+        &self.vision_model
+    }
+
+    pub fn text_model(&self) -> &str {
+        &self.text_model
+    }
+
+    pub fn rules(&self) -> &[(String, String)] {
+        &self.rules
+    }
+
+    // Picks a model, most specific reason first:
+    //   1. `--model <name>` in the prompt
+    //   2. the vision model when images are attached (text-only models can't see them)
+    //   3. the first keyword rule found in the prompt
+    //   4. the channel/guild persona's model
+    //   5. the text model
+    pub fn route(&self, prompt: &str, has_images: bool, forced_model: Option<String>, persona_model: Option<String>) -> Route {
+        if let Some(model) = forced_model {
+            return Route { model, reason: format!("forced with {}", MODEL_FLAG) };
+        }
+
+        if has_images {
+            return Route { model: self.vision_model.clone(), reason: "image attached".to_string() };
+        }
+
+        let lowered = prompt.to_lowercase();
+        if let Some((keyword, model)) = self.rules.iter().find(|(keyword, _)| contains_word(&lowered, keyword)) {
+            return Route { model: model.clone(), reason: format!("matched keyword '{}'", keyword) };
+        }
+
+        if let Some(model) = persona_model {
+            return Route { model, reason: "persona setting".to_string() };
+        }
+
+        Route { model: self.text_model.clone(), reason: "text-only prompt".to_string() }
+    }
+}
+
+// Pulls `--model <name>` out of a prompt, returning the model and the prompt without the flag.
+// Given more than once, the first one wins and the rest are dropped all the same.
+pub fn take_model_flag(prompt: &str) -> (Option<String>, String) {
+    let mut model = None;
+    let mut words = Vec::new();
+    let mut tokens = prompt.split(' ');
+
+    while let Some(token) = tokens.next() {
+        if token == MODEL_FLAG {
+            let value = tokens.next().map(|m| m.trim().to_string()).filter(|m| !m.is_empty());
+            model = model.or(value);
+        } else {
+            words.push(token);
+        }
+    }
+
+    (model, words.join(" ").trim().to_string())
+}
+
+// Whole-word match, so a `code` rule doesn't fire on "barcode". Phrases fall back to a
+// plain substring search.
+fn contains_word(haystack: &str, word: &str) -> bool {
+    if word.contains(' ') {
+        return haystack.contains(word);
+    }
+
+    haystack
+        .split(|c: char| !c.is_alphanumeric() && c != '+' && c != '#')
+        .any(|w| w == word)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RoutingRule;
+
+    fn router() -> Router {
+        let config = RoutingConfig {
+            vision_model: Some("llava".to_string()),
+            text_model: Some("llama3".to_string()),
+            rules: vec![
+                RoutingRule { keyword: "Code".to_string(), model: "codellama".to_string() },
+                RoutingRule { keyword: "step by step".to_string(), model: "deepseek-r1".to_string() },
+                RoutingRule { keyword: "c++".to_string(), model: "codellama".to_string() },
+            ],
+        };
+        Router::new(&config, "fallback")
+    }
+
+    fn model(route: Route) -> String {
+        route.model
+    }
+
+    #[test]
+    fn routes_most_specific_reason_first() {
+        let router = router();
+        let forced = || Some("mistral".to_string());
+        let persona = || Some("persona-model".to_string());
+
+        // 1. The flag beats everything
+        assert_eq!(model(router.route("write code", true, forced(), persona())), "mistral");
+        // 2. Images beat keywords and the persona
+        assert_eq!(model(router.route("write code", true, None, persona())), "llava");
+        // 3. Keywords beat the persona
+        assert_eq!(model(router.route("write code", false, None, persona())), "codellama");
+        // 4. The persona beats the text model
+        assert_eq!(model(router.route("hello", false, None, persona())), "persona-model");
+        // 5. The text model
+        assert_eq!(model(router.route("hello", false, None, None)), "llama3");
+    }
+
+    #[test]
+    fn unset_models_fall_back() {
+        let router = Router::new(&RoutingConfig::default(), "fallback");
+        assert_eq!(model(router.route("hello", false, None, None)), "fallback");
+        assert_eq!(model(router.route("hello", true, None, None)), "fallback");
+    }
+
+    #[test]
+    fn takes_the_model_flag() {
+        assert_eq!(take_model_flag("--model llama3 hi there"), (Some("llama3".to_string()), "hi there".to_string()));
+        assert_eq!(take_model_flag("hi --model llama3 there"), (Some("llama3".to_string()), "hi there".to_string()));
+        assert_eq!(take_model_flag("hi there"), (None, "hi there".to_string()));
+        // Line breaks in the prompt survive
+        assert_eq!(take_model_flag("--model x line one\nline two"), (Some("x".to_string()), "line one\nline two".to_string()));
+    }
+
+    #[test]
+    fn flag_without_a_value_is_dropped() {
+        assert_eq!(take_model_flag("hi there --model"), (None, "hi there".to_string()));
+        assert_eq!(take_model_flag("--model"), (None, String::new()));
+    }
+
+    #[test]
+    fn first_flag_wins() {
+        assert_eq!(take_model_flag("--model a hi --model b"), (Some("a".to_string()), "hi".to_string()));
+    }
+
+    #[test]
+    fn matches_whole_words() {
+        assert!(contains_word("fix my code please", "code"));
+        assert!(contains_word("code: why?", "code"));
+        assert!(!contains_word("scan this barcode", "code"));
+        assert!(!contains_word("codes", "code"));
+        assert!(contains_word("help with c++ templates", "c++"));
+        assert!(!contains_word("help with c templates", "c++"));
+    }
+
+    #[test]
+    fn matches_phrases_as_substrings() {
+        assert!(contains_word("explain it step by step", "step by step"));
+        assert!(!contains_word("step by stepping", "step by steps"));
+    }
+
+    #[test]
+    fn keywords_ignore_case() {
+        let router = router();
+        assert_eq!(model(router.route("Write CODE for me", false, None, None)), "codellama");
+        assert_eq!(model(router.route("Scan this barcode", false, None, None)), "llama3");
+    }
+}
@@ -0,0 +1,322 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::oneshot;
+
+use crate::config::QueueConfig;
+
+// Limits how many generations hit the model at once and hands out the free slots
+// round-robin between users, so one person queueing ten prompts can't starve everyone else.
+//
+// `enqueue` gives back a `Ticket`; awaiting `Ticket::wait` yields a `Permit` that holds a slot
+// until it's dropped. Dropping a ticket before it's served gives up its place in line.
+#[derive(Clone)]
+pub struct Scheduler {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    max_concurrent: usize,
+    max_queued_per_user: usize,
+    running: usize,
+    next_id: u64,
+    // Users with waiting jobs, in the order they'll next be served
+    rotation: VecDeque<u64>,
+    queues: HashMap<u64, VecDeque<Job>>,
+    // Jobs handed a slot whose ticket hasn't turned into a permit yet
+    granted: HashSet<u64>,
+}
+
+struct Job {
+    id: u64,
+    wake: oneshot::Sender<()>,
+}
+
+#[derive(Debug)]
+pub struct QueueFull {
+    pub queued: usize,
+}
+
+pub struct Ticket {
+    id: u64,
+    inner: Arc<Mutex<Inner>>,
+    // None when a slot was free straight away
+    wake: Option<oneshot::Receiver<()>>,
+    done: bool,
+}
+
+pub struct Permit {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct QueueStatus {
+    pub running: usize,
+    pub waiting: usize,
+    pub max_concurrent: usize,
+}
+
+impl Scheduler {
+    pub fn new(config: &QueueConfig) -> Scheduler {
+        Scheduler {
+            inner: Arc::new(Mutex::new(Inner {
+                max_concurrent: config.max_concurrent.max(1),
+                max_queued_per_user: config.max_queued_per_user.max(1),
+                running: 0,
+                next_id: 0,
+                rotation: VecDeque::new(),
+                queues: HashMap::new(),
+                granted: HashSet::new(),
+            })),
+        }
+    }
+
+    pub fn enqueue(&self, user_id: u64) -> Result<Ticket, QueueFull> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id += 1;
+        let id = inner.next_id;
+
+        if inner.running < inner.max_concurrent && inner.rotation.is_empty() {
+            inner.running += 1;
+            inner.granted.insert(id);
+            return Ok(Ticket { id, inner: self.inner.clone(), wake: None, done: false });
+        }
+
+        let queued = inner.queues.get(&user_id).map(|q| q.len()).unwrap_or(0);
+        if queued >= inner.max_queued_per_user {
+            return Err(QueueFull { queued });
+        }
+
+        let (wake, wait) = oneshot::channel();
+        inner.queues.entry(user_id).or_default().push_back(Job { id, wake });
+        if queued == 0 {
+            inner.rotation.push_back(user_id);
+        }
+
+        Ok(Ticket { id, inner: self.inner.clone(), wake: Some(wait), done: false })
+    }
+
+    pub fn status(&self) -> QueueStatus {
+        let inner = self.inner.lock().unwrap();
+        QueueStatus {
+            running: inner.running,
+            waiting: inner.queues.values().map(|q| q.len()).sum(),
+            max_concurrent: inner.max_concurrent,
+        }
+    }
+}
+
+impl Inner {
+    // 1-based place in line, or None once the job has been served
+    fn position(&self, id: u64) -> Option<usize> {
+        let mut position = 0;
+        let mut round = 0;
+
+        loop {
+            let mut any = false;
+            for user in &self.rotation {
+                if let Some(job) = self.queues.get(user).and_then(|q| q.get(round)) {
+                    any = true;
+                    position += 1;
+                    if job.id == id {
+                        return Some(position);
+                    }
+                }
+            }
+            if !any {
+                return None;
+            }
+            round += 1;
+        }
+    }
+
+    fn remove(&mut self, id: u64) -> bool {
+        let user = self
+            .queues
+            .iter()
+            .find(|(_, q)| q.iter().any(|job| job.id == id))
+            .map(|(user, _)| *user);
+
+        let user = match user {
+            Some(user) => user,
+            None => return false,
+        };
+
+        let queue = self.queues.get_mut(&user).unwrap();
+        queue.retain(|job| job.id != id);
+        if queue.is_empty() {
+            self.queues.remove(&user);
+            self.rotation.retain(|u| *u != user);
+        }
+        true
+    }
+
+    fn release(&mut self) {
+        self.running = self.running.saturating_sub(1);
+        self.dispatch();
+    }
+
+    // Serve the next user in the rotation; they go to the back if they still have jobs waiting
+    fn dispatch(&mut self) {
+        while self.running < self.max_concurrent {
+            let user = match self.rotation.pop_front() {
+                Some(user) => user,
+                None => return,
+            };
+
+            let queue = self.queues.get_mut(&user).unwrap();
+            let job = queue.pop_front().unwrap();
+            if queue.is_empty() {
+                self.queues.remove(&user);
+            } else {
+                self.rotation.push_back(user);
+            }
+
+            if job.wake.send(()).is_ok() {
+                self.running += 1;
+                self.granted.insert(job.id);
+            }
+        }
+    }
+}
+
+impl Ticket {
+    pub fn position(&self) -> Option<usize> {
+        self.inner.lock().unwrap().position(self.id)
+    }
+
+    // Cancel-safe: dropping this future (e.g. the losing side of a `select!`) keeps the ticket's
+    // place in line, so it can be awaited again.
+    pub async fn wait(&mut self) -> Permit {
+        if let Some(wake) = self.wake.as_mut() {
+            // The sender only goes away through `Ticket::drop`, so this can't fail while we
+            // still hold the ticket
+            wake.await.ok();
+            self.wake = None;
+        }
+
+        self.inner.lock().unwrap().granted.remove(&self.id);
+        self.done = true;
+        Permit { inner: self.inner.clone() }
+    }
+}
+
+impl Ticket {
+    // Waits up to `limit` for a slot, so callers can refresh a "you're #N in line" message
+    // between attempts
+    pub async fn wait_timeout(&mut self, limit: std::time::Duration) -> Option<Permit> {
+        tokio::time::timeout(limit, self.wait()).await.ok()
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+
+        // Still in line: just leave. Already handed a slot but never collected it: give the
+        // slot back.
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.remove(self.id) && inner.granted.remove(&self.id) {
+            inner.release();
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.inner.lock().unwrap().release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scheduler(max_concurrent: usize, max_queued_per_user: usize) -> Scheduler {
+        Scheduler::new(&QueueConfig { max_concurrent, max_queued_per_user })
+    }
+
+    #[tokio::test]
+    async fn serves_users_round_robin() {
+        let scheduler = scheduler(1, 3);
+        let mut first = scheduler.enqueue(1).unwrap();
+        let running = first.wait().await;
+
+        let mut a1 = scheduler.enqueue(1).unwrap();
+        let mut a2 = scheduler.enqueue(1).unwrap();
+        let mut b1 = scheduler.enqueue(2).unwrap();
+        assert_eq!(a1.position(), Some(1));
+        assert_eq!(b1.position(), Some(2));
+        assert_eq!(a2.position(), Some(3));
+
+        drop(running);
+        let running = a1.wait().await;
+        assert_eq!(b1.position(), Some(1));
+        assert_eq!(a2.position(), Some(2));
+
+        // User 2 goes before user 1's second job, though it was queued later
+        drop(running);
+        let running = b1.wait().await;
+        assert_eq!(a2.position(), Some(1));
+
+        drop(running);
+        a2.wait().await;
+        assert_eq!(scheduler.status().waiting, 0);
+    }
+
+    #[tokio::test]
+    async fn caps_jobs_per_user() {
+        let scheduler = scheduler(1, 2);
+        let _running = scheduler.enqueue(1).unwrap();
+
+        let _a1 = scheduler.enqueue(1).unwrap();
+        let _a2 = scheduler.enqueue(1).unwrap();
+        assert_eq!(scheduler.enqueue(1).err().map(|full| full.queued), Some(2));
+
+        // Others still get in line
+        assert!(scheduler.enqueue(2).is_ok());
+    }
+
+    #[tokio::test]
+    async fn dropped_ticket_leaves_the_line() {
+        let scheduler = scheduler(1, 3);
+        let mut first = scheduler.enqueue(1).unwrap();
+        let running = first.wait().await;
+
+        let a1 = scheduler.enqueue(1).unwrap();
+        let mut b1 = scheduler.enqueue(2).unwrap();
+        assert_eq!(scheduler.status().waiting, 2);
+
+        drop(a1);
+        assert_eq!(scheduler.status().waiting, 1);
+        assert_eq!(b1.position(), Some(1));
+
+        drop(running);
+        b1.wait().await;
+    }
+
+    #[tokio::test]
+    async fn uncollected_slot_is_released() {
+        let scheduler = scheduler(1, 3);
+
+        // Handed a slot straight away, never waited on
+        let ticket = scheduler.enqueue(1).unwrap();
+        assert_eq!(scheduler.status().running, 1);
+        drop(ticket);
+        assert_eq!(scheduler.status().running, 0);
+
+        // Woken from the queue, dropped before collecting the slot: it passes to the next job
+        let mut first = scheduler.enqueue(1).unwrap();
+        let running = first.wait().await;
+        let woken = scheduler.enqueue(2).unwrap();
+        let mut next = scheduler.enqueue(3).unwrap();
+        drop(running);
+        assert_eq!(woken.position(), None);
+        drop(woken);
+
+        assert_eq!(scheduler.status().running, 1);
+        assert!(next.wait_timeout(std::time::Duration::from_secs(1)).await.is_some());
+    }
+}
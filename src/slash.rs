@@ -0,0 +1,439 @@
+use std::borrow::Cow;
+
+use serenity::model::application::command::{Command, CommandOptionType};
+use serenity::model::application::interaction::application_command::{
+    ApplicationCommandInteraction, CommandDataOption, CommandDataOptionValue,
+};
+use serenity::model::channel::AttachmentType;
+use serenity::prelude::*;
+
+use crate::{blog, conversation, db, generator, permissions, ratelimit, routing, scheduler, split, streaming, tools};
+use crate::{BlogDatabasePath, BotConfig, ChatGenerator, ChatTools, DatabasePath, GenerationQueue};
+
+// Slash versions of the prefix commands. They're registered globally when the bot connects;
+// Discord can take a while to show changes to global commands in every server.
+pub async fn register(ctx: &Context) {
+    let result = Command::set_global_application_commands(&ctx.http, |commands| {
+        commands
+            .create_application_command(|command| {
+                command
+                    .name("ask")
+                    .description("Ask egghead something")
+                    .create_option(|option| {
+                        option
+                            .name("prompt")
+                            .description("What to ask")
+                            .kind(CommandOptionType::String)
+                            .required(true)
+                    })
+                    .create_option(|option| {
+                        option
+                            .name("temperature")
+                            .description("How adventurous the answer should be (0-2)")
+                            .kind(CommandOptionType::Number)
+                            .min_number_value(0.0)
+                            .max_number_value(2.0)
+                    })
+                    .create_option(|option| {
+                        option
+                            .name("image")
+                            .description("An image to look at")
+                            .kind(CommandOptionType::Attachment)
+                    })
+            })
+            .create_application_command(|command| {
+                command
+                    .name("dream")
+                    .description("Dream up an image")
+                    .create_option(|option| {
+                        option
+                            .name("prompt")
+                            .description("What to dream about")
+                            .kind(CommandOptionType::String)
+                            .required(true)
+                    })
+                    .create_option(|option| {
+                        option
+                            .name("steps")
+                            .description("Sampling steps; more is slower and sharper (1-50)")
+                            .kind(CommandOptionType::Integer)
+                            .min_int_value(1)
+                            .max_int_value(50)
+                    })
+                    .create_option(|option| {
+                        option
+                            .name("size")
+                            .description("Width and height in pixels")
+                            .kind(CommandOptionType::Integer)
+                            .add_int_choice("256", 256)
+                            .add_int_choice("512", 512)
+                            .add_int_choice("768", 768)
+                            .add_int_choice("1024", 1024)
+                    })
+                    .create_option(|option| {
+                        option
+                            .name("seed")
+                            .description("Reuse a seed to get the same image again")
+                            .kind(CommandOptionType::Integer)
+                            .min_int_value(0)
+                    })
+            })
+            .create_application_command(|command| {
+                command
+                    .name("blog")
+                    .description("Read egghead's blog")
+                    .create_option(|option| {
+                        option
+                            .name("id")
+                            .description("A post by number")
+                            .kind(CommandOptionType::SubCommand)
+                            .create_sub_option(|sub| {
+                                sub.name("id")
+                                    .description("Post number")
+                                    .kind(CommandOptionType::Integer)
+                                    .min_int_value(1)
+                                    .required(true)
+                            })
+                    })
+                    .create_option(|option| {
+                        option
+                            .name("latest")
+                            .description("The newest post")
+                            .kind(CommandOptionType::SubCommand)
+                    })
+                    .create_option(|option| {
+                        option
+                            .name("random")
+                            .description("A random post")
+                            .kind(CommandOptionType::SubCommand)
+                    })
+            })
+            .create_application_command(|command| {
+                command.name("help").description("What egghead can do")
+            })
+    })
+    .await;
+
+    match result {
+        Ok(commands) => println!("Registered {} slash commands", commands.len()),
+        Err(why) => eprintln!("Failed to register slash commands: {:?}", why),
+    }
+}
+
+pub async fn handle(ctx: &Context, command: &ApplicationCommandInteraction) {
+    println!("Running slash command '/{}' invoked by '{}'", command.data.name, command.user.tag());
+
+    // Generations easily outlast Discord's 3 second window for a response, so acknowledge
+    // first ("egghead is thinking...") and fill the response in when it's ready
+    if let Err(why) = command.defer(&ctx.http).await {
+        println!("Error deferring '/{}': {:?}", command.data.name, why);
+        return;
+    }
+
+    let kind = match command.data.name.as_str() {
+        "ask" => Some(ratelimit::Kind::Chat),
+        "dream" => Some(ratelimit::Kind::Dream),
+        _ => None,
+    };
+    if let Some(kind) = kind {
+        let roles = command.member.as_ref().map(|member| member.roles.clone()).unwrap_or_default();
+        if let Err(wait) = ratelimit::check(ctx, kind, command.user.id, command.channel_id, command.guild_id, &roles).await {
+            respond(ctx, command, &ratelimit::slow_down(wait)).await.ok();
+            return;
+        }
+    }
+
+    let result = match command.data.name.as_str() {
+        "ask" => ask(ctx, command).await,
+        "dream" => dream(ctx, command).await,
+        "blog" => blog_post(ctx, command).await,
+        "help" => respond(ctx, command, &crate::help_text()).await,
+        other => respond(ctx, command, &format!("I don't know `/{}` (anymore).", other)).await,
+    };
+
+    if let Err(why) = result {
+        println!("Error handling '/{}': {:?}", command.data.name, why);
+    }
+}
+
+fn option<'a>(options: &'a [CommandDataOption], name: &str) -> Option<&'a CommandDataOptionValue> {
+    options.iter().find(|o| o.name == name).and_then(|o| o.resolved.as_ref())
+}
+
+fn string_option(options: &[CommandDataOption], name: &str) -> Option<String> {
+    match option(options, name) {
+        Some(CommandDataOptionValue::String(value)) => Some(value.clone()),
+        _ => None,
+    }
+}
+
+fn integer_option(options: &[CommandDataOption], name: &str) -> Option<i64> {
+    match option(options, name) {
+        Some(CommandDataOptionValue::Integer(value)) => Some(*value),
+        _ => None,
+    }
+}
+
+// Fills in the deferred response, spilling into follow-up messages past Discord's limit
+async fn respond(ctx: &Context, command: &ApplicationCommandInteraction, text: &str) -> serenity::Result<()> {
+    let chunks = match split::split_message(text, split::MAX_LENGTH) {
+        chunks if chunks.is_empty() => vec!["...".to_string()],
+        chunks => chunks,
+    };
+
+    for (i, chunk) in chunks.iter().enumerate() {
+        if i == 0 {
+            command.edit_original_interaction_response(&ctx.http, |r| r.content(chunk)).await?;
+        } else {
+            command.create_followup_message(&ctx.http, |m| m.content(chunk)).await?;
+        }
+    }
+
+    Ok(())
+}
+
+// Waits in the shared generation queue, showing the place in line in the deferred response.
+// None when the user already has too much queued (they've been told).
+async fn wait_for_slot(ctx: &Context, command: &ApplicationCommandInteraction) -> serenity::Result<Option<scheduler::Permit>> {
+    let queue = {
+        let data_read = ctx.data.read().await;
+        data_read.get::<GenerationQueue>().expect("Expected GenerationQueue in TypeMap.").clone()
+    };
+
+    let mut ticket = match queue.enqueue(command.user.id.0) {
+        Ok(ticket) => ticket,
+        Err(full) => {
+            let response = format!("You already have {} requests waiting. Let those finish first!", full.queued);
+            respond(ctx, command, &response).await?;
+            return Ok(None);
+        }
+    };
+
+    let mut shown = None;
+    loop {
+        if let Some(position) = ticket.position() {
+            if shown != Some(position) {
+                command.edit_original_interaction_response(&ctx.http, |r| r.content(crate::queue_status(position))).await.ok();
+                shown = Some(position);
+            }
+        }
+        if let Some(permit) = ticket.wait_timeout(crate::QUEUE_POLL_INTERVAL).await {
+            return Ok(Some(permit));
+        }
+    }
+}
+
+async fn ask(ctx: &Context, command: &ApplicationCommandInteraction) -> serenity::Result<()> {
+    let options = &command.data.options;
+    let prompt = string_option(options, "prompt").unwrap_or_default();
+    let temperature = match option(options, "temperature") {
+        Some(CommandDataOptionValue::Number(value)) => Some(*value),
+        _ => None,
+    };
+
+    let mut images = Vec::new();
+    if let Some(CommandDataOptionValue::Attachment(attachment)) = option(options, "image") {
+        if !crate::is_image(attachment) {
+            return respond(ctx, command, "That attachment isn't an image I can look at.").await;
+        }
+        match crate::download_image(&attachment.url).await {
+            Some(image) => images.push(image),
+            None => return respond(ctx, command, "I couldn't download that image. Try again?").await,
+        }
+    }
+
+    let (generator, config, db_path, registry, blog_db_path) = {
+        let data_read = ctx.data.read().await;
+        (
+            data_read.get::<ChatGenerator>().expect("Expected ChatGenerator in TypeMap.").clone(),
+            data_read.get::<BotConfig>().expect("Expected BotConfig in TypeMap.").clone(),
+            data_read.get::<DatabasePath>().expect("Expected DatabasePath in TypeMap.").clone(),
+            data_read.get::<ChatTools>().expect("Expected ChatTools in TypeMap.").clone(),
+            data_read.get::<BlogDatabasePath>().expect("Expected BlogDatabasePath in TypeMap.").clone(),
+        )
+    };
+
+    let guild_id = command.guild_id.map(|g| g.0);
+    let channel_id = command.channel_id.0;
+    let persona = crate::resolve_persona(db_path.clone(), config.clone(), guild_id, channel_id).await;
+    let system_prompt = crate::with_memories(ctx, persona.system_prompt, &command.user, guild_id, &prompt).await;
+
+    let (forced_model, prompt) = routing::take_model_flag(&prompt);
+    if forced_model.is_some() {
+        let roles = command.member.as_ref().map(|member| member.roles.clone()).unwrap_or_default();
+        if permissions::level_of(ctx, command.user.id, command.guild_id, &roles).await < routing::FORCE_MODEL_LEVEL {
+            return respond(ctx, command, &permissions::refusal(routing::FORCE_MODEL_LEVEL, "pick the model with `--model`")).await;
+        }
+    }
+    let route = generator.router().route(&prompt, !images.is_empty(), forced_model, persona.model);
+    println!("Routing to model '{}' ({})", route.model, route.reason);
+    crate::remember_route(ctx, channel_id, route.clone()).await;
+
+    // A slash command is its own conversation: there's no reply chain to read
+    let chat = generator::ChatPrompt {
+        model: Some(route.model),
+        temperature: temperature.unwrap_or(persona.temperature),
+        system_prompt,
+        prompt,
+        images,
+        history: Vec::new(),
+        tools: Vec::new(),
+        tool_turns: Vec::new(),
+    };
+
+    let tool_context = tools::ToolContext {
+        ctx: ctx.clone(),
+        guild_id,
+        channel_id,
+        user_id: command.user.id.0,
+        db_path,
+        blog_db_path,
+    };
+    let max_tool_rounds = if config.tools.enabled { config.tools.max_iterations } else { 0 };
+
+    let _permit = match wait_for_slot(ctx, command).await? {
+        Some(permit) => permit,
+        None => return Ok(()),
+    };
+    command.edit_original_interaction_response(&ctx.http, |r| r.content(streaming::PLACEHOLDER)).await.ok();
+
+    let mut text = String::new();
+    let result = crate::generate_with_tools(&generator, &registry, &tool_context, chat, max_tool_rounds, &mut |token| {
+        text.push_str(token);
+    }).await;
+
+    let response = match result {
+        Ok(_) => text,
+        Err(e) => {
+            eprintln!("Generation failed: {}", e);
+            if text.trim().is_empty() {
+                crate::friendly_error(&e)
+            } else {
+                format!("{}\n\n*({})*", text, crate::friendly_error(&e))
+            }
+        }
+    };
+
+    respond(ctx, command, &response).await
+}
+
+async fn dream(ctx: &Context, command: &ApplicationCommandInteraction) -> serenity::Result<()> {
+    let options = &command.data.options;
+    let prompt = string_option(options, "prompt").unwrap_or_default();
+
+    let defaults = generator::DreamOptions::default();
+    let size = integer_option(options, "size").map(|s| s as u32).unwrap_or(defaults.width);
+    let dream_options = generator::DreamOptions {
+        steps: integer_option(options, "steps").map(|s| s as u32).unwrap_or(defaults.steps),
+        width: size,
+        height: size,
+        seed: integer_option(options, "seed"),
+    };
+
+    let generator = {
+        let data_read = ctx.data.read().await;
+        data_read.get::<ChatGenerator>().expect("Expected ChatGenerator in TypeMap.").clone()
+    };
+
+    let _permit = match wait_for_slot(ctx, command).await? {
+        Some(permit) => permit,
+        None => return Ok(()),
+    };
+    command.edit_original_interaction_response(&ctx.http, |r| r.content("*dreaming...*")).await.ok();
+
+    let (b64_string, image_bytes) = match generator.txt2img(&prompt, &dream_options).await {
+        Ok(Some(b64_string)) => {
+            use base64::{Engine as _, engine::general_purpose};
+            match general_purpose::STANDARD.decode(&b64_string) {
+                Ok(image_bytes) => (b64_string, image_bytes),
+                Err(e) => {
+                    eprintln!("Failed to decode base64: {:?}", e);
+                    return respond(ctx, command, "Failed to decode generated image.").await;
+                }
+            }
+        }
+        Ok(None) => return respond(ctx, command, "No image was generated.").await,
+        Err(e) => {
+            eprintln!("Dream failed: {}", e);
+            return respond(ctx, command, &crate::friendly_error(&e)).await;
+        }
+    };
+
+    let mut caption = format!("Dream: {}", prompt);
+    if let Some(seed) = dream_options.seed {
+        caption.push_str(&format!(" (seed {})", seed));
+    }
+
+    command.delete_original_interaction_response(&ctx.http).await.ok();
+    let sent = command
+        .create_followup_message(&ctx.http, |m| {
+            m.content(&caption).add_file(AttachmentType::Bytes {
+                data: Cow::Owned(image_bytes),
+                filename: format!("dream_{}.png", command.id.0),
+            })
+        })
+        .await?;
+
+    // No message to hang the prompt on, but replies to the image can still see it
+    crate::record_turns(ctx, vec![conversation::Turn {
+        message_id: sent.id.0,
+        parent_id: None,
+        channel_id: sent.channel_id.0,
+        user_id: command.user.id.0,
+        role: "assistant",
+        content: caption,
+        images: vec![b64_string],
+    }]).await;
+
+    Ok(())
+}
+
+async fn blog_post(ctx: &Context, command: &ApplicationCommandInteraction) -> serenity::Result<()> {
+    let subcommand = match command.data.options.first() {
+        Some(subcommand) => subcommand,
+        None => return respond(ctx, command, "Usage: `/blog id|latest|random`").await,
+    };
+    let requested_id = integer_option(&subcommand.options, "id");
+
+    let response = read_blog(ctx, &subcommand.name, requested_id).await;
+    respond(ctx, command, &response).await
+}
+
+// A blog post formatted for Discord: `kind` is "id" (with `requested_id`), "random" or
+// "latest". Shared with the `e.blog` prefix command.
+pub async fn read_blog(ctx: &Context, kind: &str, requested_id: Option<i64>) -> String {
+    let kind = kind.to_string();
+    let db_path = {
+        let data_read = ctx.data.read().await;
+        data_read.get::<BlogDatabasePath>().expect("Expected BlogDatabasePath in TypeMap.").clone()
+    };
+
+    let result = db::with_connection(db_path, move |conn| match (kind.as_str(), requested_id) {
+        ("id", Some(id)) => blog::get_blog_post_by_id(conn, id).map(Some),
+        ("random", _) => blog::get_random_blog_post(conn).map(Some),
+        _ => blog::get_latest_blog_posts(conn, 1).map(|posts| posts.into_iter().next()),
+    })
+    .await;
+
+    match result {
+        Ok(Some(post)) => format!(
+            "**Blog Post #{}**\n\n**When:** {}\n\n**What I'm passionate about:**\n{}\n\n**Where I am:** {}\n\n**What I'm doing:** {}\n\n**Photo:** {}",
+            post.id.unwrap_or(0),
+            post.timestamp.format("%Y-%m-%d %H:%M UTC"),
+            post.content,
+            post.location,
+            post.activity,
+            post.image_url
+        ),
+        Err(rusqlite::Error::QueryReturnedNoRows) if requested_id.is_some() => {
+            format!("Blog post #{} not found.", requested_id.unwrap_or(0))
+        }
+        Ok(None) | Err(rusqlite::Error::QueryReturnedNoRows) => {
+            "No blog posts yet! Wait for the next generation cycle.".to_string()
+        }
+        Err(e) => {
+            eprintln!("Failed to read blog posts: {:?}", e);
+            "The blog isn't available right now.".to_string()
+        }
+    }
+}
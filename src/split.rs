@@ -0,0 +1,205 @@
+// Discord caps message content at 2000 characters (not bytes)
+pub const MAX_LENGTH: usize = 2000;
+
+// Appended to a chunk that ends inside a code block
+const FENCE_CLOSE: &str = "\n```";
+// Space to keep free in a chunk that may need closing with `close_fence`
+pub const FENCE_RESERVE: usize = FENCE_CLOSE.len();
+
+// Splits `text` into chunks of at most `max_chars` characters for separate messages.
+// Breaks go at the last paragraph break that leaves a reasonably full chunk, else a line
+// break, a sentence end, a space, and only then mid-word. A chunk that ends inside a ```
+// code block is closed off and the next one reopens it with the same language tag, so every
+// chunk renders on its own.
+pub fn split_message(text: &str, max_chars: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut rest = text.trim();
+    let mut reopen: Option<String> = None;
+
+    while !rest.is_empty() {
+        let prefix = reopen.take().map(|opener| format!("{}\n", opener)).unwrap_or_default();
+        let room = max_chars.saturating_sub(prefix.chars().count());
+
+        if rest.chars().count() <= room {
+            chunks.push(format!("{}{}", prefix, rest));
+            break;
+        }
+
+        // Leave space to close a code block if we end up inside one
+        let (head_end, tail_start) = break_point(rest, room.saturating_sub(FENCE_RESERVE).max(1));
+        let mut chunk = format!("{}{}", prefix, rest[..head_end].trim_end());
+        reopen = close_fence(&mut chunk);
+
+        chunks.push(chunk);
+        rest = &rest[tail_start..];
+        if reopen.is_none() {
+            rest = rest.trim_start();
+        } else {
+            // Indentation matters inside code; only drop the line break we split on
+            rest = rest.trim_start_matches(['\n', '\r']);
+        }
+    }
+
+    chunks
+}
+
+// Where to cut `text` so the first part holds at most `max_chars` characters, as
+// (end of the first part, start of the rest) byte offsets. The gap between them is the
+// whitespace the break consumed.
+pub fn break_point(text: &str, max_chars: usize) -> (usize, usize) {
+    let limit = match text.char_indices().nth(max_chars) {
+        Some((i, _)) => i,
+        None => return (text.len(), text.len()),
+    };
+    let head = &text[..limit];
+
+    // Don't settle for a "nice" break that leaves a nearly empty chunk
+    let min = limit / 2;
+
+    if let Some(i) = head.rfind("\n\n").filter(|&i| i >= min) {
+        return (i, i + 2);
+    }
+    if let Some(i) = head.rfind('\n').filter(|&i| i >= min) {
+        return (i, i + 1);
+    }
+    let sentence_end = [". ", "! ", "? ", ".\t"]
+        .iter()
+        .filter_map(|end| head.rfind(end))
+        .max()
+        .filter(|&i| i >= min);
+    if let Some(i) = sentence_end {
+        return (i + 1, i + 2);
+    }
+    if let Some(i) = head.rfind(char::is_whitespace).filter(|&i| i > 0) {
+        let width = head[i..].chars().next().map(char::len_utf8).unwrap_or(1);
+        return (i, i + width);
+    }
+
+    (limit, limit)
+}
+
+// The opening line (e.g. "```rust") of the code block `text` ends inside, if any
+pub fn open_fence(text: &str) -> Option<&str> {
+    let mut open = None;
+    for line in text.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with("```") {
+            open = match open {
+                Some(_) => None,
+                None => Some(trimmed),
+            };
+        }
+    }
+    open
+}
+
+// Closes a code block left open at the end of `head`, returning the line that reopens it in
+// the next chunk. Also used by streaming.rs, which splits a piece at a time.
+pub fn close_fence(head: &mut String) -> Option<String> {
+    let opener = open_fence(head)?.to_string();
+    head.push_str(FENCE_CLOSE);
+    Some(opener)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_fits(chunks: &[String]) {
+        for chunk in chunks {
+            assert!(chunk.chars().count() <= MAX_LENGTH, "chunk of {} chars", chunk.chars().count());
+        }
+    }
+
+    #[test]
+    fn short_text_is_one_chunk() {
+        assert_eq!(split_message("  hello  ", MAX_LENGTH), vec!["hello".to_string()]);
+        assert!(split_message("   ", MAX_LENGTH).is_empty());
+    }
+
+    #[test]
+    fn multibyte_text_at_the_boundary() {
+        // Two-byte, four-byte and ZWJ-joined characters straddling the 2000th character
+        for filler in ["é", "😀", "👨‍👩‍👧"] {
+            let text = format!("{}{}", "a".repeat(MAX_LENGTH - 1), filler.repeat(10));
+            let chunks = split_message(&text, MAX_LENGTH);
+
+            assert_fits(&chunks);
+            assert_eq!(chunks.concat(), text);
+        }
+
+        let text = "é".repeat(MAX_LENGTH * 2 + 1);
+        let chunks = split_message(&text, MAX_LENGTH);
+        assert_eq!(chunks.len(), 3);
+        assert_fits(&chunks);
+        assert_eq!(chunks.concat(), text);
+    }
+
+    #[test]
+    fn reopens_code_blocks_with_their_language() {
+        let code: Vec<String> = (0..200).map(|i| format!("    let x{} = {};", i, i)).collect();
+        let text = format!("Here you go:\n```rust\n{}\n```\nDone.", code.join("\n"));
+        let chunks = split_message(&text, MAX_LENGTH);
+
+        assert!(chunks.len() > 1);
+        assert_fits(&chunks);
+        assert!(chunks[0].ends_with("\n```"));
+        for chunk in &chunks[1..] {
+            assert!(chunk.starts_with("```rust\n"));
+        }
+        // Every chunk has balanced fences and keeps its indentation
+        for chunk in &chunks {
+            assert!(open_fence(chunk).is_none(), "unclosed fence in {:?}", chunk);
+        }
+        assert!(chunks[1]["```rust\n".len()..].starts_with("    let"));
+    }
+
+    #[test]
+    fn prefers_paragraph_then_sentence_then_word() {
+        let paragraph = "First paragraph here.\n\nSecond one. It goes on and on";
+        let (end, start) = break_point(paragraph, 40);
+        assert_eq!(&paragraph[..end], "First paragraph here.");
+        assert_eq!(&paragraph[start..], "Second one. It goes on and on");
+
+        let lines = "A first line that is long\nthen more. And more words";
+        assert_eq!(&lines[..break_point(lines, 40).0], "A first line that is long");
+
+        let sentences = "The first sentence is here. The second sentence goes on";
+        let (end, start) = break_point(sentences, 40);
+        assert_eq!(&sentences[..end], "The first sentence is here.");
+        assert_eq!(&sentences[start..], "The second sentence goes on");
+
+        let words = "one two three four five six seven eight nine";
+        let (end, start) = break_point(words, 20);
+        assert_eq!(&words[..end], "one two three four");
+        assert_eq!(&words[start..], "five six seven eight nine");
+
+        let word = "a".repeat(50);
+        assert_eq!(break_point(&word, 20), (20, 20));
+    }
+
+    #[test]
+    fn nice_breaks_need_a_reasonably_full_chunk() {
+        // The paragraph break is too early; a later space wins
+        let text = "Hi.\n\nThis is a much longer paragraph of words";
+        assert_eq!(&text[..break_point(text, 30).0], "Hi.\n\nThis is a much longer");
+    }
+
+    #[test]
+    fn every_chunk_fits() {
+        let mut text = String::new();
+        for i in 0..40 {
+            text.push_str(&format!("Paragraph {} with some words and an emoji 🎉. ", i).repeat(5));
+            text.push_str("\n\n```python\n");
+            text.push_str(&"print('héllo wörld')  # 😀\n".repeat(20 + i));
+            text.push_str("```\n\n");
+        }
+
+        let chunks = split_message(&text, MAX_LENGTH);
+        assert!(chunks.len() > 5);
+        assert_fits(&chunks);
+        for chunk in &chunks {
+            assert!(open_fence(chunk).is_none());
+        }
+    }
+}
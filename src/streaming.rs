@@ -0,0 +1,207 @@
+use std::time::{Duration, Instant};
+
+use std::borrow::Cow;
+
+use serenity::http::Http;
+use serenity::model::channel::{AttachmentType, Message};
+use serenity::model::id::ChannelId;
+
+use crate::buttons::{self, Action};
+use crate::overflow::{self, OverflowPolicy};
+use crate::split::{self, MAX_LENGTH};
+
+// Discord allows roughly five edits per five seconds on a message before it starts rate
+// limiting us. Staying a little under that keeps serenity from queueing edits behind the
+// stream and making the reply lag.
+const EDIT_INTERVAL: Duration = Duration::from_millis(1200);
+
+pub const PLACEHOLDER: &str = "*thinking...*";
+
+const FILE_NOTE: &str = "\n\n*(the full answer will be attached)*";
+const THREAD_NOTE: &str = "\n\n*(continued in the thread)*";
+
+// A reply that fills in as tokens arrive. It starts out as a placeholder reply to the
+// triggering message, gets edited at most once per `EDIT_INTERVAL`, and handles outgrowing
+// Discord's limit according to the guild's overflow policy: roll over into a fresh message in
+// the channel, or cut the first message back to the same short preview `overflow::send`
+// posts and either carry on in a thread started on it or attach the whole answer at the end.
+pub struct StreamingReply {
+    current: Message,
+    // Text belonging to `current`
+    buffer: String,
+    // What Discord is currently showing for `current`
+    shown: String,
+    last_edit: Instant,
+    received_any: bool,
+    overflow: OverflowPolicy,
+    // Everything streamed so far, for the file policy
+    full_text: String,
+    // The thread the rest of the answer goes to once the thread policy has kicked in (the
+    // channel itself where no thread could be started)
+    thread: Option<ChannelId>,
+    // The file policy has frozen the preview
+    attaching: bool,
+    // The id of the first message, which the buttons refer to
+    key: u64,
+    // Buttons on `current`; they follow the text into each new message
+    actions: Vec<Action>,
+    // (channel id, message id) of every message the reply has used so far
+    sent: Vec<(u64, u64)>,
+}
+
+impl StreamingReply {
+    pub async fn start(http: &Http, msg: &Message, placeholder: &str, overflow: OverflowPolicy) -> serenity::Result<StreamingReply> {
+        let current = msg.reply(http, placeholder).await?;
+        let key = current.id.0;
+        let sent = vec![(current.channel_id.0, current.id.0)];
+
+        Ok(StreamingReply {
+            current,
+            buffer: String::new(),
+            shown: placeholder.to_string(),
+            last_edit: Instant::now(),
+            received_any: false,
+            overflow,
+            full_text: String::new(),
+            thread: None,
+            attaching: false,
+            key,
+            actions: Vec::new(),
+            sent,
+        })
+    }
+
+    pub fn key(&self) -> u64 {
+        self.key
+    }
+
+    pub fn sent(&self) -> &[(u64, u64)] {
+        &self.sent
+    }
+
+    // Swaps the buttons under the reply for `actions` (none removes them)
+    pub async fn set_buttons(&mut self, http: &Http, actions: &[Action]) -> serenity::Result<()> {
+        let key = self.key;
+        self.current.edit(http, |m| m.components(|c| buttons::add_buttons(c, key, actions))).await?;
+        self.actions = actions.to_vec();
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.received_any
+    }
+
+    // Replaces the placeholder (e.g. with a queue position) until the first token arrives
+    pub async fn set_status(&mut self, http: &Http, status: &str) -> serenity::Result<()> {
+        if self.received_any || self.shown == status {
+            return Ok(());
+        }
+
+        self.current.edit(http, |m| m.content(status)).await?;
+        self.shown = status.to_string();
+        Ok(())
+    }
+
+    pub async fn push(&mut self, http: &Http, token: &str) -> serenity::Result<()> {
+        self.received_any = true;
+        self.full_text.push_str(token);
+        if self.attaching {
+            return Ok(());
+        }
+        self.buffer.push_str(token);
+
+        if self.overflow != OverflowPolicy::Messages && self.thread.is_none() && self.buffer.chars().count() > MAX_LENGTH {
+            // Same as `overflow::send`: the channel only keeps a short preview
+            let (preview, rest) = overflow::preview(&self.buffer, overflow::PREVIEW_LENGTH);
+
+            if self.overflow == OverflowPolicy::File {
+                self.buffer = format!("{}{}", preview, FILE_NOTE);
+                self.attaching = true;
+                return self.flush(http).await;
+            }
+
+            self.buffer = format!("{}{}", preview, THREAD_NOTE);
+            self.flush(http).await?;
+            let thread = overflow::start_thread(http, &self.current, &self.full_text)
+                .await
+                .unwrap_or(self.current.channel_id);
+            self.thread = Some(thread);
+            self.roll_over(http, thread, rest).await?;
+        }
+
+        while self.buffer.chars().count() > MAX_LENGTH {
+            let (head_end, tail_start) = split::break_point(&self.buffer, MAX_LENGTH - split::FENCE_RESERVE);
+            let rest = self.buffer.split_off(tail_start);
+            self.buffer.truncate(head_end);
+
+            // A code block carries on in the next message
+            let rest = match split::close_fence(&mut self.buffer) {
+                Some(opener) => format!("{}\n{}", opener, rest.trim_start_matches(['\n', '\r'])),
+                None => rest.trim_start().to_string(),
+            };
+
+            // Finish off the full message, then carry on in a new one
+            self.flush(http).await?;
+            let channel = self.current.channel_id;
+            self.roll_over(http, channel, rest).await?;
+        }
+
+        if self.last_edit.elapsed() >= EDIT_INTERVAL {
+            self.flush(http).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn finish(&mut self, http: &Http) -> serenity::Result<()> {
+        self.flush(http).await?;
+
+        if self.attaching {
+            let (filename, contents) = overflow::attachment(&self.full_text);
+            self.current
+                .channel_id
+                .send_message(http, |m| {
+                    m.reference_message(&self.current)
+                        .add_file(AttachmentType::Bytes { data: Cow::Owned(contents.into_bytes()), filename })
+                })
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    // Carries on in a new message in `channel`, starting with `rest`. The buttons move along
+    // with the text.
+    async fn roll_over(&mut self, http: &Http, channel: ChannelId, rest: String) -> serenity::Result<()> {
+        let actions = std::mem::take(&mut self.actions);
+        if !actions.is_empty() {
+            self.set_buttons(http, &[]).await?;
+        }
+
+        // Anything still too long is split up by the next `push`
+        let content = if rest.is_empty() || rest.chars().count() > MAX_LENGTH { PLACEHOLDER } else { rest.as_str() };
+        let key = self.key;
+        self.current = channel
+            .send_message(http, |m| m.content(content).components(|c| buttons::add_buttons(c, key, &actions)))
+            .await?;
+        self.actions = actions;
+        self.sent.push((self.current.channel_id.0, self.current.id.0));
+        self.shown = content.to_string();
+        self.buffer = rest;
+        self.last_edit = Instant::now();
+        Ok(())
+    }
+
+    async fn flush(&mut self, http: &Http) -> serenity::Result<()> {
+        // Discord rejects empty messages, so leave the placeholder until there is something to show
+        if self.buffer.trim().is_empty() || self.buffer == self.shown {
+            return Ok(());
+        }
+
+        let content = self.buffer.clone();
+        self.current.edit(http, |m| m.content(&content)).await?;
+        self.shown = content;
+        self.last_edit = Instant::now();
+        Ok(())
+    }
+}
@@ -0,0 +1,287 @@
+use std::time::Duration;
+
+use chrono::{NaiveTime, Utc};
+
+use crate::generator::{Generator, GeneratorError};
+use crate::tokens;
+
+// Longest history `e.tldr` will read, however it's asked
+pub const MAX_MESSAGES: u64 = 1000;
+const DEFAULT_MESSAGES: u64 = 50;
+
+const TEMPERATURE: f64 = 0.3;
+const TIMEOUT: Duration = Duration::from_secs(180);
+// Reply room for each chunk's summary, and for the final merged one
+const CHUNK_SUMMARY_TOKENS: u32 = 384;
+const FINAL_SUMMARY_TOKENS: u32 = 768;
+// The instructions wrapped around each chunk, plus headroom for the estimate
+const PROMPT_OVERHEAD: usize = 192;
+
+// Which messages `e.tldr` should read
+#[derive(Debug, Clone, Copy)]
+pub enum Range {
+    Last(u64),
+    // Everything after this unix timestamp
+    Since(i64),
+    // The whole of the current thread channel
+    Thread,
+}
+
+// Parses `[N|since <time>|thread]`. <time> is a duration back from now (`90m`, `2h`, `1d`)
+// or a UTC time of day (`14:30`, today's or yesterday's, whichever has passed).
+pub fn parse_range(args: &str) -> Result<Range, String> {
+    let args = args.trim().to_lowercase();
+    let mut words = args.split_whitespace();
+
+    match words.next() {
+        None => Ok(Range::Last(DEFAULT_MESSAGES)),
+        Some("thread") => Ok(Range::Thread),
+        Some("since") => {
+            let time = words.next().ok_or("`since` needs a time, like `2h` or `14:30`")?;
+            parse_since(time).map(Range::Since)
+        }
+        Some(count) => match count.parse::<u64>() {
+            Ok(count) if count > 0 => Ok(Range::Last(count.min(MAX_MESSAGES))),
+            _ => Err(format!("I don't understand `{}`", count)),
+        },
+    }
+}
+
+fn parse_since(time: &str) -> Result<i64, String> {
+    let now = Utc::now();
+
+    if let Ok(clock) = NaiveTime::parse_from_str(time, "%H:%M") {
+        let mut since = now.date_naive().and_time(clock).and_utc();
+        if since > now {
+            since -= chrono::Duration::days(1);
+        }
+        return Ok(since.timestamp());
+    }
+
+    let split = time.find(|c: char| !c.is_ascii_digit()).unwrap_or(time.len());
+    let (amount, unit) = time.split_at(split);
+    let not_understood = || format!("I don't understand the time `{}`", time);
+    let amount: i64 = amount.parse().map_err(|_| not_understood())?;
+    let unit_seconds = match unit {
+        "s" | "sec" | "secs" => 1,
+        "m" | "min" | "mins" => 60,
+        "h" | "hr" | "hrs" | "" => 60 * 60,
+        "d" | "day" | "days" => 60 * 60 * 24,
+        _ => return Err(format!("I don't understand the unit `{}`", unit)),
+    };
+
+    amount
+        .checked_mul(unit_seconds)
+        .and_then(|seconds| now.timestamp().checked_sub(seconds))
+        .ok_or_else(not_understood)
+}
+
+// Map-reduce summary of `lines` ("name: text", oldest first). The log is cut into chunks that
+// fit the model's context window, each chunk is summarized, then the chunk summaries are
+// merged (in rounds, if even they don't fit together) into one bullet list.
+pub async fn summarize(generator: &Generator, lines: &[String]) -> Result<String, GeneratorError> {
+    let chunk_tokens = chunk_budget(generator, CHUNK_SUMMARY_TOKENS);
+    let merge_tokens = chunk_budget(generator, FINAL_SUMMARY_TOKENS);
+
+    let chunks = chunk(lines, merge_tokens);
+    if chunks.len() == 1 {
+        return generator.complete(&log_prompt(&chunks[0], None), TEMPERATURE, FINAL_SUMMARY_TOKENS, TIMEOUT).await;
+    }
+
+    // Map. Chunks can be a bit bigger than a lone log, since each one's summary is shorter
+    let chunks = chunk(lines, chunk_tokens);
+    println!("Summarizing {} lines in {} chunks", lines.len(), chunks.len());
+
+    let mut summaries = Vec::new();
+    for (i, part) in chunks.iter().enumerate() {
+        let summary = generator
+            .complete(&log_prompt(part, Some((i + 1, chunks.len()))), TEMPERATURE, CHUNK_SUMMARY_TOKENS, TIMEOUT)
+            .await?;
+        summaries.push(summary.trim().to_string());
+    }
+
+    // Reduce
+    loop {
+        let parts = chunk(&summaries, merge_tokens);
+        if parts.len() == 1 {
+            return generator.complete(&merge_prompt(&parts[0]), TEMPERATURE, FINAL_SUMMARY_TOKENS, TIMEOUT).await;
+        }
+
+        // A context window too small to merge even two summaries would never finish
+        if parts.len() >= summaries.len() {
+            return Ok(summaries.join("\n"));
+        }
+
+        let mut merged = Vec::new();
+        for part in &parts {
+            let summary = generator.complete(&merge_prompt(part), TEMPERATURE, CHUNK_SUMMARY_TOKENS, TIMEOUT).await?;
+            merged.push(summary.trim().to_string());
+        }
+        summaries = merged;
+    }
+}
+
+// Prompt tokens one request may spend on log text when `reply_tokens` are kept for the answer
+fn chunk_budget(generator: &Generator, reply_tokens: u32) -> usize {
+    let context_size = generator.budget().context_size(generator.default_model());
+    context_size
+        .saturating_sub(reply_tokens as usize + PROMPT_OVERHEAD)
+        .max(128)
+}
+
+// Groups consecutive lines into chunks of at most `max_tokens`. A single line that's too long
+// on its own is cut short rather than left to blow the budget.
+fn chunk(lines: &[String], max_tokens: usize) -> Vec<String> {
+    // Leaves room for the line break
+    let max_chars = max_tokens.saturating_sub(1).max(1) * 4;
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    let mut current_tokens = 0;
+
+    for line in lines {
+        let line = match line.char_indices().nth(max_chars) {
+            Some((i, _)) => &line[..i],
+            None => line.as_str(),
+        };
+        let cost = tokens::estimate_text(line) + 1;
+
+        if current_tokens + cost > max_tokens && !current.is_empty() {
+            chunks.push(std::mem::take(&mut current));
+            current_tokens = 0;
+        }
+
+        current.push_str(line);
+        current.push('\n');
+        current_tokens += cost;
+    }
+
+    if !current.is_empty() || chunks.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+fn log_prompt(log: &str, part: Option<(usize, usize)>) -> String {
+    let which = match part {
+        Some((i, n)) => format!("This is part {} of {} of a chat log.", i, n),
+        None => "This is a chat log.".to_string(),
+    };
+
+    format!(
+        "{} Summarize it as a short list of bullet points. Attribute each point to the people involved by name, e.g. \"- **alice** suggested ...\". Only include what was actually said.\n\n{}",
+        which, log
+    )
+}
+
+fn merge_prompt(summaries: &str) -> String {
+    format!(
+        "These are summaries of consecutive parts of one chat log, in order. Merge them into a single short list of bullet points covering the whole conversation. Keep who said what (names in bold), and drop repetition.\n\n{}",
+        summaries
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_counts() {
+        assert!(matches!(parse_range(""), Ok(Range::Last(DEFAULT_MESSAGES))));
+        assert!(matches!(parse_range("  20 "), Ok(Range::Last(20))));
+        assert!(matches!(parse_range("999999"), Ok(Range::Last(MAX_MESSAGES))));
+        assert!(parse_range("0").is_err());
+        assert!(parse_range("lots").is_err());
+    }
+
+    #[test]
+    fn parses_thread_and_since() {
+        assert!(matches!(parse_range("THREAD"), Ok(Range::Thread)));
+        assert!(matches!(parse_range("since 2h"), Ok(Range::Since(_))));
+        assert!(parse_range("since").is_err());
+        assert!(parse_range("since soon").is_err());
+    }
+
+    #[test]
+    fn since_durations() {
+        let now = Utc::now().timestamp();
+        let ago = |time: &str| now - parse_since(time).unwrap();
+
+        // A second or so may pass between `now` and the parse
+        assert!((90 * 60..=90 * 60 + 1).contains(&ago("90m")));
+        assert!((2 * 3600..=2 * 3600 + 1).contains(&ago("2h")));
+        assert!((2 * 3600..=2 * 3600 + 1).contains(&ago("2")));
+        assert!((86400..=86401).contains(&ago("1d")));
+        assert!((30..=31).contains(&ago("30secs")));
+        assert!(parse_since("5w").is_err());
+    }
+
+    #[test]
+    fn since_clock_time_has_passed() {
+        let now = Utc::now().timestamp();
+        let since = parse_since("00:00").unwrap();
+        assert!(since <= now);
+        assert!(now - since < 86400);
+        assert!(parse_since("25:00").is_err());
+    }
+
+    #[test]
+    fn since_overflow_is_an_error() {
+        assert_eq!(parse_since("999999999999999d"), Err("I don't understand the time `999999999999999d`".to_string()));
+        assert!(parse_since("99999999999999999999s").is_err());
+    }
+
+    fn lines(texts: &[&str]) -> Vec<String> {
+        texts.iter().map(|text| text.to_string()).collect()
+    }
+
+    // What `chunk` counts a chunk as costing: each line plus its line break
+    fn cost(chunk: &str) -> usize {
+        chunk.lines().map(|line| tokens::estimate_text(line) + 1).sum()
+    }
+
+    #[test]
+    fn chunks_stay_within_the_budget() {
+        let log: Vec<String> = (0..200).map(|i| format!("**user{}**: message number {} in the log", i % 7, i)).collect();
+        let chunks = chunk(&log, 100);
+
+        assert!(chunks.len() > 1);
+        for part in &chunks {
+            assert!(cost(part) <= 100, "chunk costs {}", cost(part));
+        }
+    }
+
+    #[test]
+    fn keeps_lines_whole_and_in_order() {
+        let log: Vec<String> = (0..50).map(|i| format!("line {}", i)).collect();
+        let chunks = chunk(&log, 20);
+
+        let rejoined: Vec<String> = chunks.iter().flat_map(|part| part.lines()).map(str::to_string).collect();
+        assert_eq!(rejoined, log);
+    }
+
+    #[test]
+    fn cuts_a_line_too_long_on_its_own() {
+        let log = lines(&["short", &"x".repeat(1000), "after"]);
+        let chunks = chunk(&log, 50);
+
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0], "short\n");
+        assert!(cost(&chunks[1]) <= 50);
+        assert!(chunks[1].starts_with("xxxx"));
+        assert_eq!(chunks[2], "after\n");
+
+        // Multibyte text is cut on a character boundary
+        let chunks = chunk(&lines(&[&"é".repeat(1000)]), 50);
+        assert!(cost(&chunks[0]) <= 50);
+    }
+
+    #[test]
+    fn empty_input_is_one_empty_chunk() {
+        assert_eq!(chunk(&[], 100), vec![String::new()]);
+    }
+
+    #[test]
+    fn short_logs_are_one_chunk() {
+        assert_eq!(chunk(&lines(&["a: hi", "b: hello"]), 100), vec!["a: hi\nb: hello\n".to_string()]);
+    }
+}
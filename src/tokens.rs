@@ -0,0 +1,291 @@
+use serde_json::json;
+
+use crate::config::ContextConfig;
+
+// Rough, tokenizer-free estimates. English averages about four characters per token across
+// the models we run; each message also costs a few tokens of role/formatting overhead.
+const CHARS_PER_TOKEN: usize = 4;
+const MESSAGE_OVERHEAD: usize = 4;
+// Headroom for the estimate being optimistic
+const SAFETY_MARGIN: usize = 64;
+// Don't bother keeping a truncated turn shorter than this
+const MIN_TRUNCATED_TOKENS: usize = 32;
+
+pub fn estimate_text(text: &str) -> usize {
+    text.chars().count().div_ceil(CHARS_PER_TOKEN)
+}
+
+// Estimate for one OpenAI-style message, whether its content is a plain string or an array
+// of text/image_url parts.
+pub fn estimate_message(message: &serde_json::Value, image_tokens: usize) -> usize {
+    // Tool call arguments count too
+    let tool_calls = message["tool_calls"]
+        .as_array()
+        .map(|calls| calls.iter().map(|call| estimate_text(&call["function"].to_string())).sum())
+        .unwrap_or(0);
+
+    let content = match &message["content"] {
+        serde_json::Value::String(text) => estimate_text(text),
+        serde_json::Value::Array(parts) => parts
+            .iter()
+            .map(|part| match part["type"].as_str() {
+                Some("image_url") => image_tokens,
+                _ => part["text"].as_str().map(estimate_text).unwrap_or(0),
+            })
+            .sum(),
+        _ => 0,
+    };
+
+    content + tool_calls + MESSAGE_OVERHEAD
+}
+
+#[derive(Debug)]
+pub struct Overflow {
+    pub needed: usize,
+    pub available: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Trimmed {
+    pub dropped: usize,
+    pub truncated: bool,
+    pub estimated: usize,
+}
+
+pub struct ContextBudget {
+    config: ContextConfig,
+}
+
+impl ContextBudget {
+    pub fn new(config: &ContextConfig) -> ContextBudget {
+        ContextBudget { config: config.clone() }
+    }
+
+    pub fn context_size(&self, model: &str) -> usize {
+        self.config.sizes.get(model).copied().unwrap_or(self.config.default_size)
+    }
+
+    pub fn max_tokens(&self) -> u32 {
+        self.config.max_tokens
+    }
+
+    pub fn max_images(&self) -> usize {
+        self.config.max_images
+    }
+
+    // Trims `history` (oldest first) so that system prompt + history + the current turn
+    // (`current`: the new user message and any tool exchanges) + room for the reply fit in
+    // `model`'s context window. The oldest turns go first; the turn on the boundary is cut
+    // down from the front rather than dropped when there's meaningful room left for it.
+    // Fails if the system prompt and current turn alone don't fit.
+    pub fn fit(&self, model: &str, system: &serde_json::Value, history: &mut Vec<serde_json::Value>, current: &[serde_json::Value]) -> Result<Trimmed, Overflow> {
+        let image_tokens = self.config.image_tokens;
+        let reserved = self.config.max_tokens as usize + SAFETY_MARGIN;
+        let available = self.context_size(model).saturating_sub(reserved);
+
+        let fixed = estimate_message(system, image_tokens)
+            + current.iter().map(|m| estimate_message(m, image_tokens)).sum::<usize>();
+        if fixed > available {
+            return Err(Overflow { needed: fixed, available });
+        }
+
+        let mut remaining = available - fixed;
+        let mut kept = Vec::new();
+        let mut trimmed = Trimmed::default();
+
+        // Walk newest to oldest, keeping turns while they fit
+        while let Some(message) = history.pop() {
+            let cost = estimate_message(&message, image_tokens);
+            if cost <= remaining {
+                remaining -= cost;
+                kept.push(message);
+                continue;
+            }
+
+            if remaining >= MIN_TRUNCATED_TOKENS + MESSAGE_OVERHEAD {
+                if let Some(text) = message["content"].as_str() {
+                    // Leave room for the "..." marker
+                    let keep_chars = (remaining - MESSAGE_OVERHEAD) * CHARS_PER_TOKEN - 3;
+                    let tail = keep_tail(text, keep_chars);
+                    let shortened = json!({ "role": message["role"], "content": format!("...{}", tail) });
+                    remaining = remaining.saturating_sub(estimate_message(&shortened, image_tokens));
+                    kept.push(shortened);
+                    trimmed.truncated = true;
+                } else {
+                    trimmed.dropped += 1;
+                }
+            } else {
+                trimmed.dropped += 1;
+            }
+
+            // Everything older than the boundary turn is dropped
+            trimmed.dropped += history.len();
+            history.clear();
+        }
+
+        kept.reverse();
+        *history = kept;
+        trimmed.estimated = available - remaining;
+
+        Ok(trimmed)
+    }
+}
+
+pub fn count_images(messages: &[serde_json::Value]) -> usize {
+    messages
+        .iter()
+        .filter_map(|message| message["content"].as_array())
+        .flatten()
+        .filter(|part| part["type"] == "image_url")
+        .count()
+}
+
+// Strips image parts from `history` past the newest `allowed` of them, returning how many went.
+// A message left with nothing but its text keeps it.
+pub fn limit_images(history: &mut [serde_json::Value], allowed: usize) -> usize {
+    let mut kept = 0;
+    let mut dropped = 0;
+
+    for message in history.iter_mut().rev() {
+        let parts = match message["content"].as_array_mut() {
+            Some(parts) => parts,
+            None => continue,
+        };
+
+        // Newest last within a message too
+        for i in (0..parts.len()).rev() {
+            if parts[i]["type"] != "image_url" {
+                continue;
+            }
+            if kept < allowed {
+                kept += 1;
+            } else {
+                parts.remove(i);
+                dropped += 1;
+            }
+        }
+    }
+
+    dropped
+}
+
+// The last `max_chars` characters of `text`, starting on a char boundary
+fn keep_tail(text: &str, max_chars: usize) -> &str {
+    let total = text.chars().count();
+    if total <= max_chars {
+        return text;
+    }
+
+    let start = text
+        .char_indices()
+        .nth(total - max_chars)
+        .map(|(i, _)| i)
+        .unwrap_or(0);
+    &text[start..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(role: &str, content: &str) -> serde_json::Value {
+        json!({ "role": role, "content": content })
+    }
+
+    // A budget with `room` tokens left for history once a 5-token system prompt and a
+    // 5-token current turn are in, and `max_tokens` reserved for the reply
+    fn budget(max_tokens: u32, room: usize) -> ContextBudget {
+        ContextBudget::new(&ContextConfig {
+            default_size: max_tokens as usize + SAFETY_MARGIN + 10 + room,
+            max_tokens,
+            image_tokens: 768,
+            ..ContextConfig::default()
+        })
+    }
+
+    #[test]
+    fn estimates_text_and_images() {
+        assert_eq!(estimate_text("abcd"), 1);
+        assert_eq!(estimate_text("abcde"), 2);
+        assert_eq!(estimate_text("ééééé"), 2);
+
+        let message = json!({
+            "role": "user",
+            "content": [
+                { "type": "text", "text": "abcd" },
+                { "type": "image_url", "image_url": { "url": "data:image/png;base64,AAAA" } }
+            ]
+        });
+        assert_eq!(estimate_message(&message, 768), 1 + 768 + MESSAGE_OVERHEAD);
+    }
+
+    #[test]
+    fn keeps_newest_turns() {
+        // Each turn costs 25 + 4 tokens, so two of them fit in 60
+        let mut history: Vec<_> = (0..4).map(|i| text("user", &i.to_string().repeat(100))).collect();
+        let trimmed = budget(100, 60).fit("any", &text("system", "sys"), &mut history, &[text("user", "hi")]).unwrap();
+
+        assert_eq!(history, vec![text("user", &"2".repeat(100)), text("user", &"3".repeat(100))]);
+        assert_eq!(trimmed.dropped, 2);
+        assert!(!trimmed.truncated);
+        assert_eq!(trimmed.estimated, 10 + 58);
+    }
+
+    #[test]
+    fn truncates_boundary_turn() {
+        let mut history = vec![text("user", &"a".repeat(400)), text("assistant", &"b".repeat(100))];
+        let trimmed = budget(100, 100).fit("any", &text("system", "sys"), &mut history, &[text("user", "hi")]).unwrap();
+
+        assert_eq!(history.len(), 2);
+        let cut = history[0]["content"].as_str().unwrap();
+        assert!(cut.starts_with("...a"));
+        assert!(cut.len() < 400);
+        assert_eq!(history[1], text("assistant", &"b".repeat(100)));
+        assert!(trimmed.truncated);
+        assert_eq!(trimmed.dropped, 0);
+    }
+
+    #[test]
+    fn reserves_max_tokens() {
+        let config = ContextConfig {
+            default_size: 1000,
+            max_tokens: 900,
+            ..ContextConfig::default()
+        };
+        let mut history = vec![text("user", "earlier")];
+        let overflow = ContextBudget::new(&config)
+            .fit("any", &text("system", &"s".repeat(200)), &mut history, &[text("user", "hi")])
+            .unwrap_err();
+
+        assert_eq!(overflow.available, 1000 - 900 - SAFETY_MARGIN);
+        assert_eq!(overflow.needed, 50 + 4 + 5);
+    }
+
+    #[test]
+    fn costs_images() {
+        // Plenty of room for the text, not for a 768-token image
+        let image = json!({
+            "role": "user",
+            "content": [{ "type": "image_url", "image_url": { "url": "data:image/png;base64,AAAA" } }]
+        });
+        let mut history = vec![image, text("assistant", "newer")];
+        let trimmed = budget(100, 500).fit("any", &text("system", "sys"), &mut history, &[text("user", "hi")]).unwrap();
+
+        assert_eq!(history, vec![text("assistant", "newer")]);
+        assert_eq!(trimmed.dropped, 1);
+    }
+
+    #[test]
+    fn limits_images_newest_first() {
+        let image = |url: &str| json!({ "type": "image_url", "image_url": { "url": url } });
+        let mut history = vec![
+            json!({ "role": "user", "content": [{ "type": "text", "text": "old" }, image("1")] }),
+            json!({ "role": "user", "content": [image("2"), image("3")] }),
+        ];
+
+        assert_eq!(count_images(&history), 3);
+        assert_eq!(limit_images(&mut history, 2), 1);
+        assert_eq!(history[0]["content"], json!([{ "type": "text", "text": "old" }]));
+        assert_eq!(count_images(&history), 2);
+    }
+}
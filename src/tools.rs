@@ -0,0 +1,585 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{Duration as ChronoDuration, Utc};
+use serde_json::json;
+use serenity::model::id::GuildId;
+use serenity::prelude::Context;
+
+use crate::blog;
+use crate::db;
+use crate::memory;
+use crate::generator::ToolCall;
+
+// Tool output is fed straight back into the prompt, so keep it from eating the context window
+const MAX_RESULT_CHARS: usize = 4000;
+
+// The model writes the calculator's input, and the parser recurses on every '(' and '-', so
+// cap both the length and the nesting well short of overflowing the stack
+const MAX_EXPRESSION_CHARS: usize = 256;
+const MAX_NESTING: usize = 32;
+
+// Where a tool call came from, for tools that need to look things up in Discord or the DB
+pub struct ToolContext {
+    pub ctx: Context,
+    pub guild_id: Option<u64>,
+    pub channel_id: u64,
+    // Who the model is answering
+    pub user_id: u64,
+    pub db_path: Arc<String>,
+    pub blog_db_path: Arc<String>,
+}
+
+// Something the model can ask us to do mid-answer. `parameters` is a JSON schema for the
+// arguments object; `call` gets those arguments and returns text for the model to read.
+// Errors are returned to the model too, so it can correct itself or explain.
+#[async_trait]
+pub trait Tool: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn description(&self) -> &'static str;
+    fn parameters(&self) -> serde_json::Value;
+    async fn call(&self, context: &ToolContext, arguments: serde_json::Value) -> Result<String, String>;
+}
+
+pub struct ToolRegistry {
+    tools: Vec<Box<dyn Tool>>,
+}
+
+impl ToolRegistry {
+    pub fn with_defaults() -> ToolRegistry {
+        ToolRegistry {
+            tools: vec![
+                Box::new(Calculator),
+                Box::new(CurrentDateTime),
+                Box::new(DiscordLookup),
+                Box::new(BlogLookup),
+                Box::new(Remember),
+            ],
+        }
+    }
+
+    // Function definitions in the shape the OpenAI `tools` field expects (Ollama takes the same)
+    pub fn definitions(&self) -> Vec<serde_json::Value> {
+        self.tools
+            .iter()
+            .map(|tool| json!({
+                "type": "function",
+                "function": {
+                    "name": tool.name(),
+                    "description": tool.description(),
+                    "parameters": tool.parameters(),
+                },
+            }))
+            .collect()
+    }
+
+    // Runs one call and returns what to tell the model, failures included
+    pub async fn call(&self, context: &ToolContext, call: &ToolCall) -> String {
+        let tool = match self.tools.iter().find(|tool| tool.name() == call.name) {
+            Some(tool) => tool,
+            None => return format!("Error: there is no tool called '{}'", call.name),
+        };
+
+        let arguments = if call.arguments.trim().is_empty() {
+            json!({})
+        } else {
+            match serde_json::from_str(&call.arguments) {
+                Ok(arguments) => arguments,
+                Err(e) => return format!("Error: arguments are not valid JSON ({})", e),
+            }
+        };
+
+        println!("Calling tool '{}' with {}", call.name, arguments);
+        let result = match tool.call(context, arguments).await {
+            Ok(result) => result,
+            Err(e) => format!("Error: {}", e),
+        };
+
+        truncate(&result, MAX_RESULT_CHARS)
+    }
+}
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((i, _)) => format!("{}...", &text[..i]),
+        None => text.to_string(),
+    }
+}
+
+struct Calculator;
+
+#[async_trait]
+impl Tool for Calculator {
+    fn name(&self) -> &'static str {
+        "calculator"
+    }
+
+    fn description(&self) -> &'static str {
+        "Evaluates an arithmetic expression. Supports + - * / % ^, parentheses, the constants pi and e, and sqrt, abs, ln, log, sin, cos, tan (radians)."
+    }
+
+    fn parameters(&self) -> serde_json::Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "expression": { "type": "string", "description": "The expression, e.g. \"(3 + 4) * 2 ^ 10\"" },
+            },
+            "required": ["expression"],
+        })
+    }
+
+    async fn call(&self, _context: &ToolContext, arguments: serde_json::Value) -> Result<String, String> {
+        let expression = arguments["expression"].as_str().ok_or("missing 'expression'")?;
+        evaluate(expression).map(|value| value.to_string())
+    }
+}
+
+fn evaluate(expression: &str) -> Result<f64, String> {
+    if expression.chars().count() > MAX_EXPRESSION_CHARS {
+        return Err(format!("the expression is longer than {} characters", MAX_EXPRESSION_CHARS));
+    }
+
+    let value = Parser::new(expression).parse()?;
+    if !value.is_finite() {
+        return Err("the result is not a finite number".to_string());
+    }
+    Ok(value)
+}
+
+// Recursive-descent evaluator for the calculator:
+//   expr   = term (('+' | '-') term)*
+//   term   = unary (('*' | '/' | '%') unary)*
+//   unary  = '-' unary | power
+//   power  = atom ('^' unary)?
+//   atom   = number | name | name '(' expr ')' | '(' expr ')'
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    // How many `unary`s deep we are; every recursive rule goes back through it
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a str) -> Parser<'a> {
+        Parser { chars: input.chars().peekable(), depth: 0 }
+    }
+
+    fn parse(mut self) -> Result<f64, String> {
+        let value = self.expr()?;
+        match self.peek() {
+            None => Ok(value),
+            Some(c) => Err(format!("unexpected '{}'", c)),
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        while self.chars.peek().is_some_and(|c| c.is_whitespace()) {
+            self.chars.next();
+        }
+        self.chars.peek().copied()
+    }
+
+    fn eat(&mut self, expected: char) -> bool {
+        if self.peek() == Some(expected) {
+            self.chars.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expr(&mut self) -> Result<f64, String> {
+        let mut value = self.term()?;
+        loop {
+            if self.eat('+') {
+                value += self.term()?;
+            } else if self.eat('-') {
+                value -= self.term()?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<f64, String> {
+        let mut value = self.unary()?;
+        loop {
+            if self.eat('*') {
+                value *= self.unary()?;
+            } else if self.eat('/') {
+                value /= self.unary()?;
+            } else if self.eat('%') {
+                value %= self.unary()?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<f64, String> {
+        if self.depth >= MAX_NESTING {
+            return Err("expression nested too deeply".to_string());
+        }
+        self.depth += 1;
+        let value = if self.eat('-') { self.unary().map(|value| -value) } else { self.power() };
+        self.depth -= 1;
+        value
+    }
+
+    fn power(&mut self) -> Result<f64, String> {
+        let base = self.atom()?;
+        if self.eat('^') {
+            // Right-associative: 2^3^2 = 2^9
+            return Ok(base.powf(self.unary()?));
+        }
+        Ok(base)
+    }
+
+    fn atom(&mut self) -> Result<f64, String> {
+        match self.peek() {
+            Some('(') => {
+                self.chars.next();
+                let value = self.expr()?;
+                if !self.eat(')') {
+                    return Err("missing ')'".to_string());
+                }
+                Ok(value)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let mut number = String::new();
+                while let Some(&c) = self.chars.peek() {
+                    if !(c.is_ascii_digit() || c == '.') {
+                        break;
+                    }
+                    number.push(c);
+                    self.chars.next();
+                }
+                number.parse().map_err(|_| format!("bad number '{}'", number))
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                let mut name = String::new();
+                while let Some(&c) = self.chars.peek() {
+                    if !c.is_ascii_alphanumeric() {
+                        break;
+                    }
+                    name.push(c);
+                    self.chars.next();
+                }
+                self.name(&name.to_lowercase())
+            }
+            Some(c) => Err(format!("unexpected '{}'", c)),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+
+    fn name(&mut self, name: &str) -> Result<f64, String> {
+        match name {
+            "pi" => return Ok(std::f64::consts::PI),
+            "e" => return Ok(std::f64::consts::E),
+            _ => {}
+        }
+
+        let function: fn(f64) -> f64 = match name {
+            "sqrt" => f64::sqrt,
+            "abs" => f64::abs,
+            "ln" => f64::ln,
+            "log" => f64::log10,
+            "sin" => f64::sin,
+            "cos" => f64::cos,
+            "tan" => f64::tan,
+            _ => return Err(format!("unknown name '{}'", name)),
+        };
+
+        if !self.eat('(') {
+            return Err(format!("expected '(' after {}", name));
+        }
+        let argument = self.expr()?;
+        if !self.eat(')') {
+            return Err("missing ')'".to_string());
+        }
+        Ok(function(argument))
+    }
+}
+
+struct CurrentDateTime;
+
+#[async_trait]
+impl Tool for CurrentDateTime {
+    fn name(&self) -> &'static str {
+        "current_datetime"
+    }
+
+    fn description(&self) -> &'static str {
+        "Returns the current date, time and weekday. Defaults to UTC."
+    }
+
+    fn parameters(&self) -> serde_json::Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "utc_offset_hours": { "type": "number", "description": "Offset from UTC in hours, e.g. -5 or 5.5" },
+            },
+        })
+    }
+
+    async fn call(&self, _context: &ToolContext, arguments: serde_json::Value) -> Result<String, String> {
+        let offset_hours = arguments["utc_offset_hours"].as_f64().unwrap_or(0.0);
+        if !(-14.0..=14.0).contains(&offset_hours) {
+            return Err("utc_offset_hours must be between -14 and 14".to_string());
+        }
+
+        let now = Utc::now() + ChronoDuration::minutes((offset_hours * 60.0).round() as i64);
+        let sign = if offset_hours < 0.0 { '-' } else { '+' };
+        Ok(format!("{} (UTC{}{})", now.format("%A, %Y-%m-%d %H:%M:%S"), sign, offset_hours.abs()))
+    }
+}
+
+struct DiscordLookup;
+
+#[async_trait]
+impl Tool for DiscordLookup {
+    fn name(&self) -> &'static str {
+        "discord_lookup"
+    }
+
+    fn description(&self) -> &'static str {
+        "Looks up a user or channel in the current Discord server by name or ID."
+    }
+
+    fn parameters(&self) -> serde_json::Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "kind": { "type": "string", "enum": ["user", "channel"] },
+                "query": { "type": "string", "description": "A name (or part of one), or a numeric ID" },
+            },
+            "required": ["kind", "query"],
+        })
+    }
+
+    async fn call(&self, context: &ToolContext, arguments: serde_json::Value) -> Result<String, String> {
+        let query = arguments["query"].as_str().ok_or("missing 'query'")?.trim().trim_start_matches(['#', '@']);
+        match arguments["kind"].as_str() {
+            Some("user") => lookup_user(context, query).await,
+            Some("channel") => lookup_channel(context, query).await,
+            _ => Err("'kind' must be \"user\" or \"channel\"".to_string()),
+        }
+    }
+}
+
+async fn lookup_user(context: &ToolContext, query: &str) -> Result<String, String> {
+    let http = &context.ctx.http;
+
+    if let Ok(id) = query.parse::<u64>() {
+        let user = http.get_user(id).await.map_err(|e| format!("no user with ID {} ({})", id, e))?;
+        return Ok(format!("{} (ID {}, bot: {}, account created {})", user.tag(), user.id.0, user.bot, user.created_at()));
+    }
+
+    let guild_id = context.guild_id.ok_or("name lookups only work in servers")?;
+    let members = GuildId(guild_id)
+        .search_members(http, query, Some(5))
+        .await
+        .map_err(|e| format!("member search failed ({})", e))?;
+
+    if members.is_empty() {
+        return Ok(format!("No members matching '{}'", query));
+    }
+
+    Ok(members
+        .iter()
+        .map(|member| {
+            let joined = member.joined_at.map(|t| t.to_string()).unwrap_or_else(|| "unknown".to_string());
+            format!("{} (display name {}, ID {}, joined {})", member.user.tag(), member.display_name(), member.user.id.0, joined)
+        })
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+async fn lookup_channel(context: &ToolContext, query: &str) -> Result<String, String> {
+    let guild_id = context.guild_id.ok_or("channel lookups only work in servers")?;
+    let channels = GuildId(guild_id)
+        .channels(&context.ctx.http)
+        .await
+        .map_err(|e| format!("couldn't list channels ({})", e))?;
+
+    let query_id = query.parse::<u64>().ok();
+    let lowered = query.to_lowercase();
+    let matches: Vec<String> = channels
+        .values()
+        .filter(|channel| Some(channel.id.0) == query_id || channel.name.to_lowercase().contains(&lowered))
+        .take(5)
+        .map(|channel| {
+            let topic = channel.topic.as_deref().filter(|t| !t.is_empty()).unwrap_or("no topic");
+            let here = if channel.id.0 == context.channel_id { ", the current channel" } else { "" };
+            format!("#{} (ID {}, {:?}, {}{})", channel.name, channel.id.0, channel.kind, topic, here)
+        })
+        .collect();
+
+    if matches.is_empty() {
+        return Ok(format!("No channels matching '{}'", query));
+    }
+    Ok(matches.join("\n"))
+}
+
+struct BlogLookup;
+
+#[async_trait]
+impl Tool for BlogLookup {
+    fn name(&self) -> &'static str {
+        "blog_lookup"
+    }
+
+    fn description(&self) -> &'static str {
+        "Reads Egghead's own blog posts, either one by ID or the most recent few."
+    }
+
+    fn parameters(&self) -> serde_json::Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "id": { "type": "integer", "description": "A post ID" },
+                "latest": { "type": "integer", "description": "How many of the newest posts to return (1-5), when no ID is given" },
+            },
+        })
+    }
+
+    async fn call(&self, context: &ToolContext, arguments: serde_json::Value) -> Result<String, String> {
+        let id = arguments["id"].as_i64();
+        let latest = arguments["latest"].as_u64().unwrap_or(1).clamp(1, 5) as usize;
+
+        let result = db::with_connection(context.blog_db_path.clone(), move |conn| match id {
+            Some(id) => blog::get_blog_post_by_id(conn, id).map(|post| vec![post]),
+            None => blog::get_latest_blog_posts(conn, latest),
+        })
+        .await;
+
+        let posts = match result {
+            Ok(posts) => posts,
+            Err(rusqlite::Error::QueryReturnedNoRows) => return Err(format!("there is no post {}", id.unwrap_or_default())),
+            Err(e) => return Err(format!("the blog isn't available ({})", e)),
+        };
+
+        if posts.is_empty() {
+            return Ok("There are no blog posts yet.".to_string());
+        }
+
+        Ok(posts
+            .iter()
+            .map(|post| format!(
+                "Post {} ({}), {} at {}:\n{}",
+                post.id.unwrap_or_default(),
+                post.timestamp.format("%Y-%m-%d %H:%M UTC"),
+                post.activity,
+                post.location,
+                post.content
+            ))
+            .collect::<Vec<_>>()
+            .join("\n\n"))
+    }
+}
+
+struct Remember;
+
+#[async_trait]
+impl Tool for Remember {
+    fn name(&self) -> &'static str {
+        "remember"
+    }
+
+    fn description(&self) -> &'static str {
+        "Saves a lasting fact about the user you're talking to (their setup, preferences, ongoing projects) so you can recall it in later conversations. Only save things they'd want remembered; they can see and delete everything with `e.memory`."
+    }
+
+    fn parameters(&self) -> serde_json::Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "fact": { "type": "string", "description": "The fact, in a short sentence, e.g. \"Uses NixOS on their laptop\"" },
+            },
+            "required": ["fact"],
+        })
+    }
+
+    async fn call(&self, context: &ToolContext, arguments: serde_json::Value) -> Result<String, String> {
+        let fact = arguments["fact"].as_str().map(str::trim).filter(|f| !f.is_empty()).ok_or("missing 'fact'")?.to_string();
+        if fact.chars().count() > memory::MAX_FACT_LENGTH {
+            return Err(format!("keep facts under {} characters", memory::MAX_FACT_LENGTH));
+        }
+
+        let (user_id, guild_id) = (context.user_id, context.guild_id.unwrap_or(0));
+        db::with_connection(context.db_path.clone(), move |conn| {
+            memory::add(conn, user_id, guild_id, &fact, memory::Source::Model)
+        })
+        .await
+        .map_err(|e| format!("couldn't save it ({})", e))?;
+
+        Ok("Saved. Let them know you'll remember it.".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_precedence() {
+        assert_eq!(evaluate("1 + 2 * 3"), Ok(7.0));
+        assert_eq!(evaluate("(1 + 2) * 3"), Ok(9.0));
+        assert_eq!(evaluate("10 - 4 - 3"), Ok(3.0));
+        assert_eq!(evaluate("7 % 4 * 2"), Ok(6.0));
+        assert_eq!(evaluate("2 * 3 ^ 2"), Ok(18.0));
+    }
+
+    #[test]
+    fn powers_are_right_associative() {
+        assert_eq!(evaluate("2 ^ 3 ^ 2"), Ok(512.0));
+        assert_eq!(evaluate("2 ^ -1"), Ok(0.5));
+    }
+
+    #[test]
+    fn unary_minus() {
+        assert_eq!(evaluate("-3"), Ok(-3.0));
+        assert_eq!(evaluate("--3"), Ok(3.0));
+        assert_eq!(evaluate("4 - -3"), Ok(7.0));
+        // Binds looser than ^, as in maths
+        assert_eq!(evaluate("-2 ^ 2"), Ok(-4.0));
+    }
+
+    #[test]
+    fn functions_and_constants() {
+        assert_eq!(evaluate("sqrt(16) + abs(-2)"), Ok(6.0));
+        assert_eq!(evaluate("log(1000)"), Ok(3.0));
+        assert_eq!(evaluate("ln(e)"), Ok(1.0));
+        assert_eq!(evaluate("cos(0) * PI"), Ok(std::f64::consts::PI));
+        assert_eq!(evaluate("sqrt 4"), Err("expected '(' after sqrt".to_string()));
+        assert_eq!(evaluate("foo(1)"), Err("unknown name 'foo'".to_string()));
+    }
+
+    #[test]
+    fn rejects_non_finite_results() {
+        assert_eq!(evaluate("1 / 0"), Err("the result is not a finite number".to_string()));
+        assert_eq!(evaluate("sqrt(-1)"), Err("the result is not a finite number".to_string()));
+    }
+
+    #[test]
+    fn rejects_malformed_expressions() {
+        assert_eq!(evaluate("(1 + 2"), Err("missing ')'".to_string()));
+        assert_eq!(evaluate("1 +"), Err("unexpected end of expression".to_string()));
+        assert_eq!(evaluate("1 2"), Err("unexpected '2'".to_string()));
+        assert_eq!(evaluate("1..2"), Err("bad number '1..2'".to_string()));
+    }
+
+    #[test]
+    fn limits_nesting_and_length() {
+        assert_eq!(evaluate(&format!("{}1{}", "(".repeat(20), ")".repeat(20))), Ok(1.0));
+        assert_eq!(evaluate(&"-".repeat(MAX_NESTING - 1)), Err("unexpected end of expression".to_string()));
+
+        let nested = format!("{}1{}", "(".repeat(100), ")".repeat(100));
+        assert_eq!(evaluate(&nested), Err("expression nested too deeply".to_string()));
+        assert_eq!(evaluate(&format!("{}1", "-".repeat(100))), Err("expression nested too deeply".to_string()));
+        assert_eq!(evaluate(&format!("2{}", "^2".repeat(100))), Err("expression nested too deeply".to_string()));
+
+        // Far past anything that would overflow the stack, without getting that far
+        let long = "(".repeat(100_000);
+        assert_eq!(evaluate(&long), Err(format!("the expression is longer than {} characters", MAX_EXPRESSION_CHARS)));
+    }
+}